@@ -0,0 +1,66 @@
+//! Batched vs. per-point distance and gradient queries
+//!
+//! `cargo bench --bench queries`
+
+use std::time::{Duration, Instant};
+
+use esdf_vis::prelude::*;
+use rand::{Rng, SeedableRng};
+
+const POINTS: usize = 1_000_000;
+const RUNS: u32 = 5;
+
+fn main() {
+    // 128x128x32 observed voxels of 0.1
+    let mut layer = TsdfLayer::<8>::new(0.1);
+    for x in 0..16 {
+        for y in 0..16 {
+            for z in 0..4 {
+                let block = layer.allocate_block_by_index(&BlockIndex::new(x, y, z));
+                for voxel in block.write().as_mut_slice() {
+                    voxel.weight = 1.0;
+                }
+            }
+        }
+    }
+
+    // also beyond the allocated blocks
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let points: Vec<_> = (0..POINTS)
+        .map(|_| {
+            Point3::new(
+                rng.gen_range(-0.5..12.8),
+                rng.gen_range(-0.5..12.8),
+                rng.gen_range(-0.5..3.2),
+            )
+        })
+        .collect();
+
+    let per_point = bench(|| {
+        points
+            .iter()
+            .filter(|p| layer.distance_and_gradient_at(p).is_ok())
+            .count()
+    });
+    let batched = bench(|| {
+        let batch = layer.batch_distance_and_gradient_at(&points);
+        batch.valid.iter().filter(|valid| **valid).count()
+    });
+    assert_eq!(per_point.1, batched.1, "both query the same points");
+
+    println!("{POINTS} points, {} valid, best of {RUNS} runs", batched.1);
+    println!("per-point: {:?}", per_point.0);
+    println!("batched:   {:?}", batched.0);
+}
+
+/// Fastest of the runs and the number of valid queries
+fn bench(query: impl Fn() -> usize) -> (Duration, usize) {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            let valid = std::hint::black_box(query());
+            (start.elapsed(), valid)
+        })
+        .min()
+        .unwrap()
+}
@@ -7,32 +7,21 @@ use super::voxel::Voxel;
 
 #[derive(Debug)]
 pub struct Block<VoxelType: Voxel, const VPS: usize> {
-    voxel_count: usize,
     voxel_size: Real,
-    voxel_inv_size: Real,
-    block_size: Real,
-    block_size_inv: Real,
     origin: Point3<Real>,
     voxels: RwLock<VoxelStorage<VoxelType, VPS, VPS, VPS>>,
 }
 
 impl<VoxelType: Voxel, const VPS: usize> Block<VoxelType, VPS> {
     pub fn new(voxel_size: Real, origin: Point3<Real>) -> Self {
-        let vps = VPS as Real;
-        let voxel_count = VPS * VPS * VPS;
-        let block_size = vps * voxel_size;
-
         Self {
-            voxel_count,
             voxel_size,
-            voxel_inv_size: voxel_size.recip(),
-            block_size,
-            block_size_inv: block_size.recip(),
             origin,
             voxels: RwLock::new(VoxelStorage::default()),
         }
     }
 
+    /// Corner of the block in grid coordinates, i.e., without the origin of the layer
     pub fn origin(&self) -> &Point3<Real> {
         &self.origin
     }
@@ -56,13 +45,13 @@ impl<VoxelType: Voxel, const VPS: usize> Block<VoxelType, VPS> {
         )
     }
 
-    pub fn read(&self) -> BlockReadLock<VoxelType, VPS> {
+    pub fn read(&self) -> BlockReadLock<'_, VoxelType, VPS> {
         BlockReadLock {
             voxels: self.voxels.read(),
         }
     }
 
-    pub fn write(&self) -> BlockWriteLock<VoxelType, VPS> {
+    pub fn write(&self) -> BlockWriteLock<'_, VoxelType, VPS> {
         BlockWriteLock {
             voxels: self.voxels.write(),
         }
@@ -147,9 +136,7 @@ mod test {
     use super::*;
 
     #[derive(Debug, Default, Clone, Copy)]
-    struct TestVoxel {
-        distance: f32,
-    }
+    struct TestVoxel;
 
     impl Voxel for TestVoxel {}
 
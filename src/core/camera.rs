@@ -0,0 +1,105 @@
+use std::path::Path;
+
+use super::prelude::*;
+
+/// Pinhole camera intrinsics (in pixels)
+///
+/// The camera looks along +z, x points right and y down in the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraIntrinsics {
+    pub fx: Real,
+    pub fy: Real,
+    pub cx: Real,
+    pub cy: Real,
+}
+
+impl CameraIntrinsics {
+    pub fn new(fx: Real, fy: Real, cx: Real, cy: Real) -> Self {
+        Self { fx, fy, cx, cy }
+    }
+
+    /// Image coordinates of a point in the camera frame, `None` if it is behind the camera
+    pub fn project(&self, p: &Point3<Real>) -> Option<(Real, Real)> {
+        if p.z <= 0.0 {
+            return None;
+        }
+
+        Some((self.fx * p.x / p.z + self.cx, self.fy * p.y / p.z + self.cy))
+    }
+
+    /// Point in the camera frame at image coordinates `(u, v)` and the given depth
+    pub fn unproject(&self, u: Real, v: Real, depth: Real) -> Point3<Real> {
+        Point3::new(
+            (u - self.cx) / self.fx * depth,
+            (v - self.cy) / self.fy * depth,
+            depth,
+        )
+    }
+}
+
+/// Depth image in meters, 0 marks an invalid measurement
+#[derive(Debug, Clone)]
+pub struct DepthImage {
+    width: u32,
+    height: u32,
+    data: Vec<f32>,
+}
+
+impl DepthImage {
+    /// Row-major depth buffer
+    pub fn from_f32(width: u32, height: u32, data: Vec<f32>) -> Self {
+        assert_eq!(data.len(), (width * height) as usize);
+
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    /// 16-bit depth image, `depth_scale` converts the raw values to meters (e.g. 0.001 for mm)
+    pub fn from_luma16(
+        image: &image::ImageBuffer<image::Luma<u16>, Vec<u16>>,
+        depth_scale: f32,
+    ) -> Self {
+        Self {
+            width: image.width(),
+            height: image.height(),
+            data: image
+                .as_raw()
+                .iter()
+                .map(|d| *d as f32 * depth_scale)
+                .collect(),
+        }
+    }
+
+    /// Loads a 16-bit (PNG) depth image
+    pub fn load<P: AsRef<Path>>(path: P, depth_scale: f32) -> image::ImageResult<Self> {
+        let image = image::io::Reader::open(path)?.decode()?.into_luma16();
+        Ok(Self::from_luma16(&image, depth_scale))
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Depth of the pixel covering image coordinates `(u, v)`,
+    /// `None` if outside of the image or invalid
+    pub fn depth_at(&self, u: Real, v: Real) -> Option<Real> {
+        if u < 0.0 || v < 0.0 {
+            return None;
+        }
+
+        let (x, y) = (u as u32, v as u32);
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+
+        let depth = self.data[(y * self.width + x) as usize];
+        (depth > 0.0 && depth.is_finite()).then_some(depth)
+    }
+}
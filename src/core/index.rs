@@ -1,8 +1,11 @@
 use std::{
-    hash::{Hash, Hasher},
+    collections::HashMap,
+    hash::{BuildHasherDefault, Hash, Hasher},
     ops::{Add, Deref, Sub},
 };
 
+use nalgebra::Isometry3;
+
 use super::{prelude::*, utils::grid_index_from_point};
 
 pub trait GridIndex: From<Point3<i64>> {}
@@ -39,8 +42,14 @@ impl<const VPS: usize> Add for GlobalIndex<VPS> {
 }
 
 impl<const VPS: usize> GlobalIndex<VPS> {
-    pub fn from_point(p: &Point3<Real>, grid_size_inv: Real) -> Self {
-        Self::from(grid_index_from_point(p, grid_size_inv))
+    /// Index of the voxel containing the world point `p`
+    ///
+    /// `origin` is the pose of the layer in the world, see [`Layer::origin`](super::layer::Layer::origin).
+    pub fn from_point(p: &Point3<Real>, origin: &Isometry3<Real>, grid_size_inv: Real) -> Self {
+        Self::from(grid_index_from_point(
+            &origin.inverse_transform_point(p),
+            grid_size_inv,
+        ))
     }
 
     pub fn from_block_and_local_lin_index(
@@ -90,15 +99,17 @@ impl<const VPS: usize> GlobalIndex<VPS> {
         ))
     }
 
-    pub fn center(&self, voxel_size: Real) -> Point3<Real> {
-        Point3::new(
-            ((self.x as Real) + 0.5) * voxel_size,
-            ((self.y as Real) + 0.5) * voxel_size,
-            ((self.z as Real) + 0.5) * voxel_size,
-        )
+    /// Center of the voxel in the world, `origin` is the pose of the layer
+    pub fn center(&self, origin: &Isometry3<Real>, voxel_size: Real) -> Point3<Real> {
+        origin
+            * Point3::new(
+                ((self.x as Real) + 0.5) * voxel_size,
+                ((self.y as Real) + 0.5) * voxel_size,
+                ((self.z as Real) + 0.5) * voxel_size,
+            )
     }
 
-    pub fn neighbors(&self) -> IndexNeighborIter<GlobalIndex<VPS>> {
+    pub fn neighbors(&self) -> IndexNeighborIter<'_, GlobalIndex<VPS>> {
         IndexNeighborIter {
             pivot: self,
             n: 0,
@@ -106,13 +117,21 @@ impl<const VPS: usize> GlobalIndex<VPS> {
         }
     }
 
-    pub fn neighbors6(&self) -> IndexNeighborIter<GlobalIndex<VPS>> {
+    pub fn neighbors6(&self) -> IndexNeighborIter<'_, GlobalIndex<VPS>> {
         IndexNeighborIter {
             pivot: self,
             n: 0,
             count: 6,
         }
     }
+
+    pub fn neighbors6_include_self(&self) -> IndexNeighborIter<'_, GlobalIndex<VPS>> {
+        IndexNeighborIter {
+            pivot: self,
+            n: 0,
+            count: 7,
+        }
+    }
 }
 
 impl<const VPS: usize> From<Point3<i64>> for GlobalIndex<VPS> {
@@ -121,6 +140,12 @@ impl<const VPS: usize> From<Point3<i64>> for GlobalIndex<VPS> {
     }
 }
 
+impl<const VPS: usize> From<GlobalIndex<VPS>> for Point3<i64> {
+    fn from(val: GlobalIndex<VPS>) -> Self {
+        val.0
+    }
+}
+
 pub struct IndexNeighborIter<'a, T> {
     pivot: &'a T,
     n: usize,
@@ -213,7 +238,7 @@ impl<const VPS: usize> VoxelIndex<VPS> {
 pub struct BlockIndex<const VPS: usize>(pub Point3<i32>);
 
 impl<const VPS: usize> BlockIndex<VPS> {
-    pub fn neighbors(&self) -> IndexNeighborIter<BlockIndex<VPS>> {
+    pub fn neighbors(&self) -> IndexNeighborIter<'_, BlockIndex<VPS>> {
         IndexNeighborIter {
             pivot: self,
             n: 1,
@@ -221,7 +246,7 @@ impl<const VPS: usize> BlockIndex<VPS> {
         }
     }
 
-    pub fn neighbors6(&self) -> IndexNeighborIter<BlockIndex<VPS>> {
+    pub fn neighbors6(&self) -> IndexNeighborIter<'_, BlockIndex<VPS>> {
         IndexNeighborIter {
             pivot: self,
             n: 1,
@@ -229,7 +254,7 @@ impl<const VPS: usize> BlockIndex<VPS> {
         }
     }
 
-    pub fn neighbors6_include_self(&self) -> IndexNeighborIter<BlockIndex<VPS>> {
+    pub fn neighbors6_include_self(&self) -> IndexNeighborIter<'_, BlockIndex<VPS>> {
         IndexNeighborIter {
             pivot: self,
             n: 0,
@@ -287,9 +312,15 @@ impl<const VPS: usize> BlockIndex<VPS> {
     }
 }
 
+/// Orders by `deco_hash` to keep neighbouring blocks close,
+/// the coordinates break ties between colliding hashes
 impl<const VPS: usize> Ord for BlockIndex<VPS> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.deco_hash().cmp(&other.deco_hash())
+        self.deco_hash()
+            .cmp(&other.deco_hash())
+            .then_with(|| self.x.cmp(&other.x))
+            .then_with(|| self.y.cmp(&other.y))
+            .then_with(|| self.z.cmp(&other.z))
     }
 }
 
@@ -299,6 +330,43 @@ impl<const VPS: usize> PartialOrd for BlockIndex<VPS> {
     }
 }
 
+impl<const VPS: usize> Hash for BlockIndex<VPS> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.deco_hash());
+    }
+}
+
+/// Hasher for keys that already hash to a single `u64` such as [`BlockIndex::deco_hash`]
+///
+/// `deco_hash` is locality preserving and therefore has poorly distributed bits,
+/// they are mixed with the 64-bit finalizer of MurmurHash3.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DecoHasher(u64);
+
+impl Hasher for DecoHasher {
+    fn finish(&self) -> u64 {
+        let mut h = self.0;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51afd7ed558ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+        h ^ (h >> 33)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ *byte as u64;
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.0 = self.0.rotate_left(32) ^ i;
+    }
+}
+
+pub type BlockMap<const VPS: usize, T> =
+    HashMap<BlockIndex<VPS>, T, BuildHasherDefault<DecoHasher>>;
+
 impl<const VPS: usize> Sub for BlockIndex<VPS> {
     type Output = BlockIndex<VPS>;
 
@@ -323,8 +391,11 @@ mod test {
 
     #[test]
     fn test_global_index() {
-        let global_index: GlobalIndex<3> =
-            GlobalIndex::from_point(&Point3::new(1.0, 2.0, 3.0), 1.0 / 0.5);
+        let global_index: GlobalIndex<3> = GlobalIndex::from_point(
+            &Point3::new(1.0, 2.0, 3.0),
+            &Isometry3::identity(),
+            1.0 / 0.5,
+        );
         assert_eq!(global_index, GlobalIndex(Point3::new(2, 4, 6)));
 
         let global_index: GlobalIndex<3> = GlobalIndex(Point3::new(5, 5, 5));
@@ -355,6 +426,35 @@ mod test {
         assert_eq!(voxel_index, VoxelIndex(Point3::new(0, 0, 0)));
     }
 
+    #[test]
+    fn negative_points() {
+        let identity = Isometry3::identity();
+
+        // points on a boundary belong to the upper voxel
+        for k in -2000i64..2000 {
+            let p = Point3::new(k as Real * 0.1, k as Real * -0.05, k as Real);
+            let index = GlobalIndex::<8>::from_point(&p, &identity, (0.1 as Real).recip());
+            assert_eq!(index.0, Point3::new(k, (-k).div_euclid(2), 10 * k), "{k}");
+        }
+
+        // map style origin
+        let origin = Isometry3::new(
+            Vector3::new(-50.3, -12.0, 0.0),
+            Vector3::z() * std::f32::consts::FRAC_PI_6,
+        );
+        for x in -300..300 {
+            for y in [-120, -1, 0, 7] {
+                let index = GlobalIndex::<8>(Point3::new(x, y, -3));
+                let center = index.center(&origin, 0.05);
+                assert_eq!(GlobalIndex::from_point(&center, &origin, 20.0), index);
+            }
+        }
+        assert_eq!(
+            GlobalIndex::<8>::from_point(&Point3::new(-50.3, -12.0, 0.0), &origin, 20.0),
+            GlobalIndex(Point3::new(0, 0, 0))
+        );
+    }
+
     #[test]
     fn test_block_index_from_global_index() {
         let global_index: GlobalIndex<3> = GlobalIndex(Point3::new(-1, -2, -3));
@@ -370,6 +470,7 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::identity_op)]
     fn test_block_from_lin_index() {
         let block_index = BlockIndex(Point3::new(0, 0, 0));
         let global_index: GlobalIndex<3> =
@@ -383,6 +484,55 @@ mod test {
         assert_eq!(global_index, GlobalIndex(Point3::new(2, 1, 0)));
     }
 
+    #[test]
+    fn block_index_collisions() {
+        use std::collections::{BTreeSet, HashSet};
+
+        // same deco hash, different blocks
+        let a = BlockIndex::<3>::new(17191, 0, 0);
+        let b = BlockIndex::<3>::new(0, 1, 0);
+        assert_eq!(a.deco_hash(), b.deco_hash());
+        assert_ne!(a.cmp(&b), std::cmp::Ordering::Equal);
+        assert_eq!(BTreeSet::from([a, b]).len(), 2);
+
+        let mut map = BlockMap::default();
+        map.insert(a, 'a');
+        map.insert(b, 'b');
+        assert_eq!(map.get(&a), Some(&'a'));
+        assert_eq!(map.get(&b), Some(&'b'));
+
+        // large and negative coordinates
+        let extents = [i32::MIN, -17191, -1024, -1, 0, 1, 17190, 17191, i32::MAX];
+        let mut indices = vec![];
+        for x in extents {
+            for y in extents {
+                for z in extents {
+                    indices.push(BlockIndex::<3>::new(x, y, z));
+                }
+            }
+        }
+        for x in -20..20 {
+            for y in -20..20 {
+                for z in -20..20 {
+                    indices.push(BlockIndex::<3>::new(x * 997, y * 17191, z));
+                }
+            }
+        }
+
+        let unique: HashSet<_> = indices.iter().map(|index| index.0).collect();
+        let ordered: BTreeSet<_> = indices.iter().copied().collect();
+        let hashed: BlockMap<3, ()> = indices.iter().map(|index| (*index, ())).collect();
+        assert_eq!(ordered.len(), unique.len());
+        assert_eq!(hashed.len(), unique.len());
+
+        // the ordering is total and consistent with Eq
+        let ordered: Vec<_> = ordered.into_iter().collect();
+        for pair in ordered.windows(2) {
+            assert_eq!(pair[0].cmp(&pair[1]), std::cmp::Ordering::Less);
+            assert_ne!(pair[0], pair[1]);
+        }
+    }
+
     #[test]
     fn neighbors() {
         let block_index = BlockIndex::<3>::new(0, 0, 0);
@@ -399,5 +549,14 @@ mod test {
             .collect();
         assert_eq!(neighbors.len(), 7);
         assert_eq!(neighbors[0].0, point![0, 0, 0]);
+
+        let global_index = GlobalIndex::<3>(point![0, 0, 0]);
+
+        let neighbors: Vec<_> = global_index
+            .neighbors6_include_self()
+            .map(|p| p.index)
+            .collect();
+        assert_eq!(neighbors.len(), 7);
+        assert_eq!(neighbors[0], global_index);
     }
 }
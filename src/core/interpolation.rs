@@ -0,0 +1,377 @@
+use std::fmt::Display;
+
+use rayon::prelude::*;
+
+use super::{
+    index::{BlockIndex, GlobalIndex},
+    layer::Layer,
+    prelude::*,
+    voxel::{DistanceVoxel, Voxel},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryError<const VPS: usize> {
+    /// A voxel required for the interpolation lies in a block that is not allocated
+    Unallocated(BlockIndex<VPS>),
+    /// A voxel required for the interpolation holds no valid distance
+    Unobserved(GlobalIndex<VPS>),
+}
+
+impl<const VPS: usize> Display for QueryError<VPS> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryError::Unallocated(index) => {
+                write!(
+                    f,
+                    "block ({}, {}, {}) is not allocated",
+                    index.x, index.y, index.z
+                )
+            }
+            QueryError::Unobserved(index) => {
+                write!(
+                    f,
+                    "voxel ({}, {}, {}) is unobserved",
+                    index.x, index.y, index.z
+                )
+            }
+        }
+    }
+}
+
+impl<const VPS: usize> std::error::Error for QueryError<VPS> {}
+
+impl<VoxelType: Voxel + DistanceVoxel, const VPS: usize> Layer<VoxelType, VPS> {
+    /// Distance of the voxel at `global_index` without interpolation
+    pub fn distance_at_index(
+        &self,
+        global_index: &GlobalIndex<VPS>,
+    ) -> Result<Real, QueryError<VPS>> {
+        let (block_index, voxel_index) = global_index.block_voxel_index();
+        let block = self
+            .block_by_index(&block_index)
+            .ok_or(QueryError::Unallocated(block_index))?;
+
+        let voxel = *block.read().voxel_from_index(&voxel_index);
+        voxel
+            .distance()
+            .ok_or(QueryError::Unobserved(*global_index))
+    }
+
+    /// Trilinearly interpolated distance at `p`
+    pub fn distance_at(&self, p: &Point3<Real>) -> Result<Real, QueryError<VPS>> {
+        self.distance_and_gradient_at(p)
+            .map(|(distance, _)| distance)
+    }
+
+    /// Gradient of the trilinearly interpolated distance at `p`
+    pub fn gradient_at(&self, p: &Point3<Real>) -> Result<Vector3<Real>, QueryError<VPS>> {
+        self.distance_and_gradient_at(p)
+            .map(|(_, gradient)| gradient)
+    }
+
+    /// Trilinearly interpolated distance and its gradient at `p`
+    ///
+    /// Interpolates between the 8 voxel centers surrounding `p`, which may lie in different blocks.
+    /// `p` and the gradient are in world coordinates.
+    pub fn distance_and_gradient_at(
+        &self,
+        p: &Point3<Real>,
+    ) -> Result<(Real, Vector3<Real>), QueryError<VPS>> {
+        let (base, t) = interpolation_base(
+            &self.origin().inverse_transform_point(p),
+            self.voxel_size_inv(),
+        );
+
+        // corner distances indexed by [x][y][z]
+        let mut c = [[[0.0; 2]; 2]; 2];
+        for (x, cx) in c.iter_mut().enumerate() {
+            for (y, cxy) in cx.iter_mut().enumerate() {
+                for (z, cxyz) in cxy.iter_mut().enumerate() {
+                    *cxyz = self.distance_at_index(&(base + corner_offset(x, y, z)))?;
+                }
+            }
+        }
+
+        let (distance, gradient) = trilinear(&c, &t, self.voxel_size_inv());
+        Ok((distance, self.origin().rotation * gradient))
+    }
+}
+
+/// Distances and gradients of a batch of points, see [`Layer::batch_distance_and_gradient_at`]
+#[derive(Debug, Default, Clone)]
+pub struct BatchQuery {
+    pub distances: Vec<Real>,
+    pub gradients: Vec<Vector3<Real>>,
+    /// `false` if a voxel required for the interpolation is unallocated or unobserved,
+    /// distance and gradient are zero in that case
+    pub valid: Vec<bool>,
+}
+
+impl<VoxelType: Voxel + DistanceVoxel + Send + Sync, const VPS: usize> Layer<VoxelType, VPS> {
+    /// Same as [`Layer::distance_and_gradient_at`] for many points at once
+    ///
+    /// Points are grouped by block so every group locks its blocks only once.
+    /// The groups are processed in parallel.
+    pub fn batch_distance_and_gradient_at(&self, points: &[Point3<Real>]) -> BatchQuery {
+        firestorm::profile_method!("batch_distance_and_gradient_at");
+
+        let voxel_size_inv = self.voxel_size_inv();
+        let origin = self.origin();
+        let bases: Vec<_> = points
+            .iter()
+            .map(|p| interpolation_base::<VPS>(&origin.inverse_transform_point(p), voxel_size_inv))
+            .collect();
+
+        // point indices sorted by the block of their lower corner
+        let mut order: Vec<_> = bases
+            .iter()
+            .enumerate()
+            .map(|(i, (base, _))| (base.block_index(), i))
+            .collect();
+        order.sort_unstable_by_key(|(block_index, _)| block_index.0.coords.data.0);
+        let groups: Vec<_> = order.chunk_by(|a, b| a.0 == b.0).collect();
+
+        let results: Vec<Vec<_>> = groups
+            .par_iter()
+            .map(|group| {
+                let block_index = group[0].0;
+
+                // the corners of the points in this group lie in the block itself
+                // or in one of its upper neighbours
+                let locks: [_; 8] = std::array::from_fn(|i| {
+                    let neighbor_index = block_index
+                        + BlockIndex::new(i as i32 & 1, (i as i32 >> 1) & 1, i as i32 >> 2);
+                    self.block_by_index(&neighbor_index)
+                        .map(|block| block.read())
+                });
+
+                let query = |(base, t): &(GlobalIndex<VPS>, Vector3<Real>)| {
+                    let mut c = [[[0.0; 2]; 2]; 2];
+                    for (x, cx) in c.iter_mut().enumerate() {
+                        for (y, cxy) in cx.iter_mut().enumerate() {
+                            for (z, cxyz) in cxy.iter_mut().enumerate() {
+                                let (corner_block_index, voxel_index) =
+                                    (*base + corner_offset(x, y, z)).block_voxel_index();
+                                let d = corner_block_index.0 - block_index.0;
+                                let lock = locks[(d.x + 2 * d.y + 4 * d.z) as usize].as_ref()?;
+                                *cxyz = lock.voxel_from_index(&voxel_index).distance()?;
+                            }
+                        }
+                    }
+
+                    let (distance, gradient) = trilinear(&c, t, voxel_size_inv);
+                    Some((distance, origin.rotation * gradient))
+                };
+
+                group.iter().map(|&(_, i)| (i, query(&bases[i]))).collect()
+            })
+            .collect();
+
+        let mut batch = BatchQuery {
+            distances: vec![0.0; points.len()],
+            gradients: vec![Vector3::zeros(); points.len()],
+            valid: vec![false; points.len()],
+        };
+        for (i, result) in results.into_iter().flatten() {
+            if let Some((distance, gradient)) = result {
+                batch.distances[i] = distance;
+                batch.gradients[i] = gradient;
+                batch.valid[i] = true;
+            }
+        }
+
+        batch
+    }
+}
+
+/// Index of the lower corner voxel and the position relative to it (in `[0, 1)`)
+///
+/// `p` is in grid coordinates.
+fn interpolation_base<const VPS: usize>(
+    p: &Point3<Real>,
+    voxel_size_inv: Real,
+) -> (GlobalIndex<VPS>, Vector3<Real>) {
+    // position relative to the voxel centers
+    let q = p.coords * voxel_size_inv - Vector3::repeat(0.5);
+    let base = q.map(|v| v.floor());
+
+    (GlobalIndex(Point3::from(base.map(|v| v as i64))), q - base)
+}
+
+fn corner_offset<const VPS: usize>(x: usize, y: usize, z: usize) -> GlobalIndex<VPS> {
+    GlobalIndex(Point3::new(x as i64, y as i64, z as i64))
+}
+
+/// Interpolated value and gradient from the corner values `c` indexed by [x][y][z]
+fn trilinear(
+    c: &[[[Real; 2]; 2]; 2],
+    t: &Vector3<Real>,
+    voxel_size_inv: Real,
+) -> (Real, Vector3<Real>) {
+    let lerp = |a: Real, b: Real, t: Real| a + (b - a) * t;
+
+    // interpolate along x
+    let cx = |y: usize, z: usize| lerp(c[0][y][z], c[1][y][z], t.x);
+    let dx = |y: usize, z: usize| c[1][y][z] - c[0][y][z];
+
+    // interpolate along y
+    let cxy = |z: usize| lerp(cx(0, z), cx(1, z), t.y);
+    let dxy = |z: usize| lerp(dx(0, z), dx(1, z), t.y);
+    let dyx = |z: usize| cx(1, z) - cx(0, z);
+
+    let distance = lerp(cxy(0), cxy(1), t.z);
+    let gradient = Vector3::new(
+        lerp(dxy(0), dxy(1), t.z),
+        lerp(dyx(0), dyx(1), t.z),
+        cxy(1) - cxy(0),
+    ) * voxel_size_inv;
+
+    (distance, gradient)
+}
+
+#[cfg(test)]
+mod test {
+    use nalgebra::Isometry3;
+
+    use super::*;
+    use crate::core::voxel::Tsdf;
+
+    fn linear_field(p: &Point3<Real>) -> Real {
+        p.x + 2.0 * p.y - 0.5 * p.z
+    }
+
+    /// 2x2x2 blocks holding the linear field `f(p) = p.x + 2 p.y - 0.5 p.z`
+    fn linear_layer() -> Layer<Tsdf, 4> {
+        linear_layer_at(Isometry3::identity())
+    }
+
+    /// Same as [`linear_layer`], the field is defined in world coordinates
+    fn linear_layer_at(origin: Isometry3<Real>) -> Layer<Tsdf, 4> {
+        let mut layer = Layer::<Tsdf, 4>::new(0.5);
+        layer.set_origin(origin);
+        for x in 0..2 {
+            for y in 0..2 {
+                for z in 0..2 {
+                    let block_index = BlockIndex::new(x, y, z);
+                    let block = layer.allocate_block_by_index(&block_index);
+                    for (i, voxel) in block.write().as_mut_slice().iter_mut().enumerate() {
+                        let p = GlobalIndex::from_block_and_local_lin_index(&block_index, i)
+                            .center(&origin, 0.5);
+                        voxel.distance = linear_field(&p);
+                        voxel.weight = 1.0;
+                    }
+                }
+            }
+        }
+        layer
+    }
+
+    #[test]
+    fn interpolation() {
+        let layer = linear_layer();
+
+        // in between blocks
+        for p in [
+            Point3::new(2.0, 1.3, 0.7),
+            Point3::new(1.1, 2.05, 1.95),
+            Point3::new(0.25, 0.25, 0.25),
+        ] {
+            let (distance, gradient) = layer.distance_and_gradient_at(&p).unwrap();
+            assert!((distance - linear_field(&p)).abs() < 1e-4);
+            assert!((gradient - Vector3::new(1.0, 2.0, -0.5)).norm() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn transformed_origin() {
+        let origin = Isometry3::new(
+            Vector3::new(-50.3, -12.0, 1.0),
+            Vector3::z() * std::f32::consts::FRAC_PI_4,
+        );
+        let layer = linear_layer_at(origin);
+
+        for p in [
+            Point3::new(2.0, 1.3, 0.7),
+            Point3::new(1.1, 2.05, 1.95),
+            Point3::new(0.25, 0.25, 0.25),
+        ] {
+            let p = origin * p;
+            let (distance, gradient) = layer.distance_and_gradient_at(&p).unwrap();
+            assert!((distance - linear_field(&p)).abs() < 1e-3);
+            assert!((gradient - Vector3::new(1.0, 2.0, -0.5)).norm() < 1e-3);
+
+            let batch = layer.batch_distance_and_gradient_at(&[p]);
+            assert!(batch.valid[0]);
+            assert_eq!(
+                (batch.distances[0], batch.gradients[0]),
+                (distance, gradient)
+            );
+        }
+    }
+
+    #[test]
+    fn missing_neighbours() {
+        let layer = linear_layer();
+
+        // the lower neighbours are outside of the allocated blocks
+        assert_eq!(
+            layer.distance_at(&Point3::new(0.1, 1.0, 1.0)),
+            Err(QueryError::Unallocated(BlockIndex::new(-1, 0, 0)))
+        );
+
+        layer
+            .block_by_index(&BlockIndex::new(1, 1, 1))
+            .unwrap()
+            .write()
+            .voxel_from_lin_index_mut(0)
+            .weight = 0.0;
+        assert_eq!(
+            layer.gradient_at(&Point3::new(2.1, 2.1, 2.1)),
+            Err(QueryError::Unobserved(GlobalIndex(Point3::new(4, 4, 4))))
+        );
+    }
+
+    fn random_points(count: usize, max: Real) -> Vec<Point3<Real>> {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        (0..count)
+            .map(|_| {
+                Point3::new(
+                    rng.gen_range(-0.5..max),
+                    rng.gen_range(-0.5..max),
+                    rng.gen_range(-0.5..max),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batch_query() {
+        let layer = linear_layer();
+        layer
+            .block_by_index(&BlockIndex::new(1, 0, 1))
+            .unwrap()
+            .write()
+            .voxel_from_lin_index_mut(7)
+            .weight = 0.0;
+
+        // includes points outside of the allocated blocks
+        let points = random_points(1000, 4.5);
+        let batch = layer.batch_distance_and_gradient_at(&points);
+
+        assert!(batch.valid.iter().any(|valid| *valid));
+        assert!(batch.valid.iter().any(|valid| !*valid));
+
+        for (i, p) in points.iter().enumerate() {
+            match layer.distance_and_gradient_at(p) {
+                Ok((distance, gradient)) => {
+                    assert!(batch.valid[i]);
+                    assert_eq!(batch.distances[i], distance);
+                    assert_eq!(batch.gradients[i], gradient);
+                }
+                Err(_) => assert!(!batch.valid[i]),
+            }
+        }
+    }
+}
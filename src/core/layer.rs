@@ -1,15 +1,20 @@
-use std::collections::BTreeMap;
+use nalgebra::Isometry3;
 
 use super::prelude::*;
 
-use super::{block::Block, index::BlockIndex, voxel::Voxel};
+use super::{
+    block::Block,
+    index::{BlockIndex, BlockMap, GlobalIndex},
+    voxel::Voxel,
+};
 
 pub struct Layer<VoxelType: Voxel, const VPS: usize> {
     block_size: Real,
     block_size_inv: Real,
     voxel_size: Real,
     voxel_size_inv: Real,
-    blocks: BTreeMap<BlockIndex<VPS>, Block<VoxelType, VPS>>,
+    origin: Isometry3<Real>,
+    blocks: BlockMap<VPS, Block<VoxelType, VPS>>,
 }
 
 impl<VoxelType: Voxel + Copy, const VPS: usize> Layer<VoxelType, VPS> {
@@ -23,10 +28,23 @@ impl<VoxelType: Voxel + Copy, const VPS: usize> Layer<VoxelType, VPS> {
             block_size_inv,
             voxel_size,
             voxel_size_inv,
-            blocks: BTreeMap::new(),
+            origin: Isometry3::identity(),
+            blocks: BlockMap::default(),
         }
     }
 
+    /// Pose of the layer in the world, i.e., the transform from the grid to world coordinates
+    ///
+    /// The corner of voxel (0, 0, 0) is at the origin.
+    #[inline]
+    pub fn origin(&self) -> &Isometry3<Real> {
+        &self.origin
+    }
+
+    pub fn set_origin(&mut self, origin: Isometry3<Real>) {
+        self.origin = origin;
+    }
+
     #[inline]
     pub fn voxel_size(&self) -> Real {
         self.voxel_size
@@ -74,29 +92,48 @@ impl<VoxelType: Voxel + Copy, const VPS: usize> Layer<VoxelType, VPS> {
         if !self.blocks.contains_key(index) {
             self.blocks.insert(
                 *index,
-                Block::new(self.voxel_size, self.origin_from_index(index)),
+                Block::new(self.voxel_size, self.local_origin_from_index(index)),
             );
         }
 
         self.blocks.get_mut(index).unwrap()
     }
 
+    /// Corner of the block in the world
     pub fn origin_from_index(&self, index: &BlockIndex<VPS>) -> Point3<Real> {
-        Point3::new(
-            (index.x as Real) * self.block_size,
-            (index.y as Real) * self.block_size,
-            (index.z as Real) * self.block_size,
-        )
+        self.origin * self.local_origin_from_index(index)
     }
 
+    /// Center of the block in the world
     pub fn center_point_from_index(&self, index: &BlockIndex<VPS>) -> Point3<Real> {
+        self.origin
+            * Point3::new(
+                ((index.x as Real) + 0.5) * self.block_size,
+                ((index.y as Real) + 0.5) * self.block_size,
+                ((index.z as Real) + 0.5) * self.block_size,
+            )
+    }
+
+    /// Index of the voxel containing the world point `p`
+    pub fn global_index_from_point(&self, p: &Point3<Real>) -> GlobalIndex<VPS> {
+        GlobalIndex::from_point(p, &self.origin, self.voxel_size_inv)
+    }
+
+    /// Center of the voxel in the world
+    pub fn voxel_center(&self, index: &GlobalIndex<VPS>) -> Point3<Real> {
+        index.center(&self.origin, self.voxel_size)
+    }
+
+    /// Corner of the block in grid coordinates
+    fn local_origin_from_index(&self, index: &BlockIndex<VPS>) -> Point3<Real> {
         Point3::new(
-            ((index.x as Real) + 0.5) * self.block_size,
-            ((index.y as Real) + 0.5) * self.block_size,
-            ((index.z as Real) + 0.5) * self.block_size,
+            (index.x as Real) * self.block_size,
+            (index.y as Real) * self.block_size,
+            (index.z as Real) * self.block_size,
         )
     }
 
+    /// Allocated blocks in arbitrary order
     pub fn allocated_blocks_iter(&self) -> impl Iterator<Item = &BlockIndex<VPS>> {
         self.blocks.keys()
     }
@@ -0,0 +1,108 @@
+use super::prelude::*;
+
+/// Meaning of a cell in a 2D map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelClass {
+    /// Dark pixels
+    Occupied,
+    /// Bright pixels
+    Free,
+    /// Everything in between, e.g., grey
+    Unknown,
+}
+
+impl PixelClass {
+    pub fn from_rgb(pixel: &image::Rgb<u8>) -> Self {
+        let [r, g, b] = pixel.0.map(|c| c as u32);
+        let luma = (299 * r + 587 * g + 114 * b) / 1000;
+
+        match luma {
+            0..=63 => PixelClass::Occupied,
+            192.. => PixelClass::Free,
+            _ => PixelClass::Unknown,
+        }
+    }
+}
+
+/// Classified 2D grid in the z=0 plane
+///
+/// Cell `(x, y)` covers `origin + [x, y] * resolution` to `origin + [x + 1, y + 1] * resolution`.
+#[derive(Debug, Clone)]
+pub struct Map2d {
+    width: u32,
+    height: u32,
+    cells: Vec<PixelClass>,
+    resolution: Real,
+    origin: Point3<Real>,
+}
+
+impl Map2d {
+    /// Row-major cells, the first row is at the origin
+    pub fn new(
+        width: u32,
+        height: u32,
+        cells: Vec<PixelClass>,
+        resolution: Real,
+        origin: Point3<Real>,
+    ) -> Self {
+        assert_eq!(cells.len(), (width * height) as usize);
+
+        Self {
+            width,
+            height,
+            cells,
+            resolution,
+            origin,
+        }
+    }
+
+    /// One cell per pixel, the first image row is at the origin (y pointing down in the image)
+    pub fn from_rgb(image: &image::RgbImage, resolution: Real) -> Self {
+        Self::new(
+            image.width(),
+            image.height(),
+            image.pixels().map(PixelClass::from_rgb).collect(),
+            resolution,
+            Point3::origin(),
+        )
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Edge length of a cell
+    pub fn resolution(&self) -> Real {
+        self.resolution
+    }
+
+    /// Corner of the first cell
+    pub fn origin(&self) -> &Point3<Real> {
+        &self.origin
+    }
+
+    pub fn class_at(&self, x: u32, y: u32) -> PixelClass {
+        self.cells[(y * self.width + x) as usize]
+    }
+
+    pub fn set_class_at(&mut self, x: u32, y: u32, class: PixelClass) {
+        self.cells[(y * self.width + x) as usize] = class;
+    }
+
+    /// Sets the cells from `min` (inclusive) to `max` (exclusive), clipped to the map
+    pub fn fill_rect(&mut self, min: [u32; 2], max: [u32; 2], class: PixelClass) {
+        for y in min[1]..max[1].min(self.height) {
+            for x in min[0]..max[0].min(self.width) {
+                self.set_class_at(x, y, class);
+            }
+        }
+    }
+
+    pub fn center(&self, x: u32, y: u32) -> Point3<Real> {
+        self.origin + Vector3::new(x as Real + 0.5, y as Real + 0.5, 0.0) * self.resolution
+    }
+}
@@ -1,7 +1,12 @@
 pub mod block;
+pub mod camera;
 pub mod color;
 pub mod index;
+pub mod interpolation;
 pub mod layer;
+pub mod map2d;
+pub mod raycast;
+pub mod serialization;
 pub mod storage;
 pub mod utils;
 pub mod voxel;
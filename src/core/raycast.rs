@@ -0,0 +1,123 @@
+use super::{index::GlobalIndex, prelude::*, utils::grid_index_from_point};
+
+/// Voxels traversed by the line segment from `start` to `end` (3D DDA)
+///
+/// Amanatides, John, and Andrew Woo. "A fast voxel traversal algorithm for ray tracing."
+/// Eurographics. Vol. 87. No. 3. 1987.
+///
+/// Yields the voxel containing `start` first and the voxel containing `end` last.
+pub struct VoxelRayIter<const VPS: usize> {
+    current: Point3<i64>,
+    step: Vector3<i64>,
+    t_max: Vector3<Real>,
+    t_delta: Vector3<Real>,
+    remaining: usize,
+}
+
+impl<const VPS: usize> VoxelRayIter<VPS> {
+    pub fn new(start: &Point3<Real>, end: &Point3<Real>, voxel_size_inv: Real) -> Self {
+        let current = grid_index_from_point(start, voxel_size_inv);
+        let last = grid_index_from_point(end, voxel_size_inv);
+
+        // in voxel units
+        let start = start * voxel_size_inv;
+        let dir = (end * voxel_size_inv) - start;
+
+        let step = dir.map(|d| d.signum() as i64);
+        let mut t_max = Vector3::repeat(Real::INFINITY);
+        let mut t_delta = Vector3::repeat(Real::INFINITY);
+
+        for i in 0..3 {
+            if dir[i] != 0.0 {
+                t_delta[i] = dir[i].recip().abs();
+                let boundary = if dir[i] > 0.0 {
+                    (current[i] + 1) as Real
+                } else {
+                    current[i] as Real
+                };
+                t_max[i] = (boundary - start[i]) / dir[i];
+            }
+        }
+
+        // a fixed number of steps makes the traversal robust against rounding
+        let remaining = (last - current).abs().sum() as usize;
+
+        Self {
+            current,
+            step,
+            t_max,
+            t_delta,
+            remaining: remaining + 1,
+        }
+    }
+}
+
+impl<const VPS: usize> Iterator for VoxelRayIter<VPS> {
+    type Item = GlobalIndex<VPS>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let index = GlobalIndex(self.current);
+
+        if self.remaining > 0 {
+            let axis = self.t_max.imin();
+            self.current[axis] += self.step[axis];
+            self.t_max[axis] += self.t_delta[axis];
+        }
+
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use nalgebra::point;
+
+    use super::*;
+
+    fn ray(start: Point3<Real>, end: Point3<Real>) -> Vec<Point3<i64>> {
+        VoxelRayIter::<8>::new(&start, &end, 1.0)
+            .map(|index| index.0)
+            .collect()
+    }
+
+    #[test]
+    fn axis_aligned() {
+        assert_eq!(
+            ray(point![0.5, 0.5, 0.5], point![3.5, 0.5, 0.5]),
+            vec![
+                point![0, 0, 0],
+                point![1, 0, 0],
+                point![2, 0, 0],
+                point![3, 0, 0]
+            ]
+        );
+
+        assert_eq!(
+            ray(point![0.5, 0.5, 0.5], point![0.5, -1.5, 0.5]),
+            vec![point![0, 0, 0], point![0, -1, 0], point![0, -2, 0]]
+        );
+
+        assert_eq!(
+            ray(point![0.5, 0.5, 0.5], point![0.7, 0.2, 0.1]),
+            vec![point![0, 0, 0]]
+        );
+    }
+
+    #[test]
+    fn diagonal() {
+        let voxels = ray(point![0.5, 0.2, -0.5], point![-3.3, 2.9, 4.1]);
+
+        assert_eq!(voxels.first(), Some(&point![0, 0, -1]));
+        assert_eq!(voxels.last(), Some(&point![-4, 2, 4]));
+
+        // face connected
+        for pair in voxels.windows(2) {
+            assert_eq!((pair[1] - pair[0]).abs().sum(), 1);
+        }
+    }
+}
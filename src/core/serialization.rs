@@ -0,0 +1,463 @@
+//! Binary (de)serialization of layers
+//!
+//! All values are stored little-endian. Layout:
+//!
+//! | field        | type      |
+//! |--------------|-----------|
+//! | magic        | `[u8; 8]` |
+//! | version      | `u32`     |
+//! | voxel type   | `[u8; 4]` |
+//! | VPS          | `u32`     |
+//! | voxel size   | `f32`     |
+//! | origin       | `[f32; 7]`|
+//! | block count  | `u64`     |
+//!
+//! followed by `block count` blocks, each consisting of its `BlockIndex` (3x `i32`)
+//! and `VPS³` voxels in linear index order.
+//!
+//! The origin is stored as translation (x, y, z) followed by the rotation quaternion
+//! (i, j, k, w). Version 1 files have no origin, it defaults to the identity.
+
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use nalgebra::{Isometry3, Quaternion, Translation3, UnitQuaternion};
+
+use super::{
+    index::BlockIndex,
+    layer::Layer,
+    prelude::*,
+    voxel::{Esdf, EsdfFlags, Occupancy, Tsdf, Voxel},
+};
+
+pub const MAGIC: [u8; 8] = *b"ESDFVIS\0";
+pub const VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum LayerIoError {
+    Io(std::io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    VoxelTypeMismatch {
+        expected: [u8; 4],
+        found: [u8; 4],
+    },
+    VpsMismatch {
+        expected: usize,
+        found: usize,
+    },
+    /// The voxel size is not a positive finite number
+    InvalidVoxelSize(Real),
+    /// A block index occurs more than once
+    DuplicateBlock([i32; 3]),
+}
+
+impl Display for LayerIoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayerIoError::Io(err) => write!(f, "io error: {err}"),
+            LayerIoError::InvalidMagic => write!(f, "not a layer file"),
+            LayerIoError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "unsupported layer file version {version} (expected {VERSION})"
+                )
+            }
+            LayerIoError::VoxelTypeMismatch { expected, found } => write!(
+                f,
+                "voxel type mismatch: expected '{}', found '{}'",
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(found)
+            ),
+            LayerIoError::VpsMismatch { expected, found } => write!(
+                f,
+                "voxels per side mismatch: expected {expected}, found {found}"
+            ),
+            LayerIoError::InvalidVoxelSize(voxel_size) => {
+                write!(f, "invalid voxel size {voxel_size}")
+            }
+            LayerIoError::DuplicateBlock(index) => write!(f, "duplicate block {index:?}"),
+        }
+    }
+}
+
+impl std::error::Error for LayerIoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LayerIoError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for LayerIoError {
+    fn from(err: std::io::Error) -> Self {
+        LayerIoError::Io(err)
+    }
+}
+
+/// A voxel that can be written to and read from a layer file
+pub trait SerializableVoxel: Voxel {
+    /// Tag identifying the voxel type in the file header
+    const TYPE_ID: [u8; 4];
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()>;
+    fn read_from<R: Read>(reader: &mut R) -> std::io::Result<Self>;
+}
+
+impl SerializableVoxel for Tsdf {
+    const TYPE_ID: [u8; 4] = *b"TSDF";
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        write_real(writer, self.distance)?;
+        write_real(writer, self.weight)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        Ok(Self {
+            distance: read_real(reader)?,
+            weight: read_real(reader)?,
+        })
+    }
+}
+
+impl SerializableVoxel for Esdf {
+    const TYPE_ID: [u8; 4] = *b"ESDF";
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        for i in self.site_index {
+            writer.write_all(&i.to_le_bytes())?;
+        }
+        write_real(writer, self.distance)?;
+        writer.write_all(&self.flags.bits().to_le_bytes())
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        Ok(Self {
+            site_index: [read_i32(reader)?, read_i32(reader)?, read_i32(reader)?],
+            distance: read_real(reader)?,
+            flags: EsdfFlags::from_bits_retain(read_u32(reader)?),
+            ..Default::default()
+        })
+    }
+}
+
+impl SerializableVoxel for Occupancy {
+    const TYPE_ID: [u8; 4] = *b"OCCU";
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        write_real(writer, self.log_odds)?;
+        writer.write_all(&[self.observed as u8])
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let log_odds = read_real(reader)?;
+        let mut observed = [0u8];
+        reader.read_exact(&mut observed)?;
+
+        Ok(Self {
+            log_odds,
+            observed: observed[0] != 0,
+        })
+    }
+}
+
+impl<VoxelType: SerializableVoxel, const VPS: usize> Layer<VoxelType, VPS> {
+    /// Saves the layer to `path`, see the module docs for the format
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), LayerIoError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Loads a layer from `path`
+    ///
+    /// Fails if the file was written for another voxel type or VPS.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LayerIoError> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), LayerIoError> {
+        firestorm::profile_method!("write_to");
+
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&VoxelType::TYPE_ID)?;
+        writer.write_all(&(VPS as u32).to_le_bytes())?;
+        write_real(writer, self.voxel_size())?;
+        let origin = self.origin();
+        for value in origin
+            .translation
+            .vector
+            .iter()
+            .chain(origin.rotation.coords.iter())
+        {
+            write_real(writer, *value)?;
+        }
+        writer.write_all(&(self.allocated_blocks_iter().count() as u64).to_le_bytes())?;
+
+        // sorted to get reproducible files
+        let mut block_indices: Vec<_> = self.allocated_blocks_iter().collect();
+        block_indices.sort();
+
+        for block_index in block_indices {
+            for i in [block_index.x, block_index.y, block_index.z] {
+                writer.write_all(&i.to_le_bytes())?;
+            }
+
+            let block = self.block_by_index(block_index).unwrap();
+            for voxel in block.read().as_slice() {
+                voxel.write_to(writer)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, LayerIoError> {
+        firestorm::profile_method!("read_from");
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(LayerIoError::InvalidMagic);
+        }
+
+        let version = read_u32(reader)?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(LayerIoError::UnsupportedVersion(version));
+        }
+
+        let mut type_id = [0u8; 4];
+        reader.read_exact(&mut type_id)?;
+        if type_id != VoxelType::TYPE_ID {
+            return Err(LayerIoError::VoxelTypeMismatch {
+                expected: VoxelType::TYPE_ID,
+                found: type_id,
+            });
+        }
+
+        let vps = read_u32(reader)? as usize;
+        if vps != VPS {
+            return Err(LayerIoError::VpsMismatch {
+                expected: VPS,
+                found: vps,
+            });
+        }
+
+        let voxel_size = read_real(reader)?;
+        if !(voxel_size.is_finite() && voxel_size > 0.0) {
+            return Err(LayerIoError::InvalidVoxelSize(voxel_size));
+        }
+
+        let origin = if version >= 2 {
+            let mut values = [0.0; 7];
+            for value in &mut values {
+                *value = read_real(reader)?;
+            }
+            let [x, y, z, i, j, k, w] = values;
+            Isometry3::from_parts(
+                Translation3::new(x, y, z),
+                UnitQuaternion::new_normalize(Quaternion::new(w, i, j, k)),
+            )
+        } else {
+            Isometry3::identity()
+        };
+        let block_count = read_u64(reader)?;
+
+        let mut layer = Self::new(voxel_size);
+        layer.set_origin(origin);
+        for _ in 0..block_count {
+            let block_index =
+                BlockIndex::new(read_i32(reader)?, read_i32(reader)?, read_i32(reader)?);
+            if layer.contains(&block_index) {
+                return Err(LayerIoError::DuplicateBlock(block_index.coords.into()));
+            }
+
+            let block = layer.allocate_block_by_index(&block_index);
+            for voxel in block.write().as_mut_slice() {
+                *voxel = VoxelType::read_from(reader)?;
+            }
+        }
+
+        Ok(layer)
+    }
+}
+
+fn write_real<W: Write>(writer: &mut W, value: Real) -> std::io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_real<R: Read>(reader: &mut R) -> std::io::Result<Real> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(Real::from_le_bytes(buf))
+}
+
+fn read_i32<R: Read>(reader: &mut R) -> std::io::Result<i32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    fn tsdf_layer() -> Layer<Tsdf, 4> {
+        let mut layer = Layer::<Tsdf, 4>::new(0.5);
+        for (i, block_index) in [BlockIndex::new(0, 0, 0), BlockIndex::new(-1, 2, 3)]
+            .iter()
+            .enumerate()
+        {
+            let block = layer.allocate_block_by_index(block_index);
+            for (j, voxel) in block.write().as_mut_slice().iter_mut().enumerate() {
+                voxel.distance = (i * 100 + j) as Real;
+                voxel.weight = 1.0;
+            }
+        }
+        layer
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut layer = tsdf_layer();
+        layer.set_origin(Isometry3::new(
+            Vector3::new(-50.3, -12.0, 0.5),
+            Vector3::new(0.1, 0.2, 0.3),
+        ));
+
+        let mut buf = Vec::new();
+        layer.write_to(&mut buf).unwrap();
+        let loaded = Layer::<Tsdf, 4>::read_from(&mut buf.as_slice()).unwrap();
+
+        assert_eq!(loaded.voxel_size(), 0.5);
+        assert_eq!(loaded.origin(), layer.origin());
+        assert_eq!(
+            BTreeSet::from_iter(loaded.allocated_blocks_iter()),
+            BTreeSet::from_iter(layer.allocated_blocks_iter())
+        );
+        for block_index in layer.allocated_blocks_iter() {
+            let a = layer.block_by_index(block_index).unwrap().read();
+            let b = loaded.block_by_index(block_index).unwrap().read();
+            for (a, b) in a.as_slice().iter().zip(b.as_slice()) {
+                assert_eq!(a.distance, b.distance);
+                assert_eq!(a.weight, b.weight);
+            }
+        }
+    }
+
+    #[test]
+    fn esdf_roundtrip() {
+        let mut layer = Layer::<Esdf, 4>::new(1.0);
+        *layer
+            .allocate_block_by_index(&BlockIndex::new(1, 0, 0))
+            .write()
+            .voxel_from_lin_index_mut(5) = Esdf {
+            site_index: [1, -2, 3],
+            distance: -2.5,
+            flags: EsdfFlags::Observed | EsdfFlags::Inside,
+            ..Default::default()
+        };
+
+        let mut buf = Vec::new();
+        layer.write_to(&mut buf).unwrap();
+        let loaded = Layer::<Esdf, 4>::read_from(&mut buf.as_slice()).unwrap();
+
+        let block = loaded.block_by_index(&BlockIndex::new(1, 0, 0)).unwrap();
+        let voxel = *block.read().voxel_from_lin_index(5);
+        assert_eq!(voxel.site_index, [1, -2, 3]);
+        assert_eq!(voxel.distance, -2.5);
+        assert_eq!(voxel.flags, EsdfFlags::Observed | EsdfFlags::Inside);
+    }
+
+    #[test]
+    fn mismatch() {
+        let mut buf = Vec::new();
+        tsdf_layer().write_to(&mut buf).unwrap();
+
+        assert!(matches!(
+            Layer::<Tsdf, 8>::read_from(&mut buf.as_slice()),
+            Err(LayerIoError::VpsMismatch {
+                expected: 8,
+                found: 4
+            })
+        ));
+        assert!(matches!(
+            Layer::<Esdf, 4>::read_from(&mut buf.as_slice()),
+            Err(LayerIoError::VoxelTypeMismatch { .. })
+        ));
+        assert!(matches!(
+            Layer::<Tsdf, 4>::read_from(&mut &buf[1..]),
+            Err(LayerIoError::InvalidMagic)
+        ));
+        assert!(matches!(
+            Layer::<Tsdf, 4>::read_from(&mut &buf[..buf.len() - 1]),
+            Err(LayerIoError::Io(_))
+        ));
+
+        let mut future = buf.clone();
+        future[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Layer::<Tsdf, 4>::read_from(&mut future.as_slice()),
+            Err(LayerIoError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_contents() {
+        let mut buf = Vec::new();
+        tsdf_layer().write_to(&mut buf).unwrap();
+
+        for voxel_size in [0.0, -0.5, Real::NAN] {
+            let mut invalid = buf.clone();
+            invalid[20..24].copy_from_slice(&voxel_size.to_le_bytes());
+            assert!(matches!(
+                Layer::<Tsdf, 4>::read_from(&mut invalid.as_slice()),
+                Err(LayerIoError::InvalidVoxelSize(_))
+            ));
+        }
+
+        // the second block gets the index of the first, after the header and 4³ voxels
+        let first = 60;
+        let second = first + 12 + 4 * 4 * 4 * 8;
+        let mut duplicate = buf.clone();
+        duplicate.copy_within(first..first + 12, second);
+        assert!(matches!(
+            Layer::<Tsdf, 4>::read_from(&mut duplicate.as_slice()),
+            Err(LayerIoError::DuplicateBlock([0, 0, 0]))
+        ));
+    }
+
+    #[test]
+    fn version_1() {
+        // the origin was added in version 2
+        let mut buf = Vec::new();
+        tsdf_layer().write_to(&mut buf).unwrap();
+        buf[8..12].copy_from_slice(&1u32.to_le_bytes());
+        buf.drain(24..52);
+
+        let loaded = Layer::<Tsdf, 4>::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(loaded.origin(), &Isometry3::identity());
+        assert_eq!(loaded.allocated_blocks_iter().count(), 2);
+    }
+}
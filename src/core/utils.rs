@@ -1,9 +1,9 @@
 use super::prelude::*;
 
+/// Index of the grid cell containing `p`
+///
+/// Points within rounding error of a cell boundary belong to the upper cell. The tolerance
+/// scales with the magnitude of the coordinate, as does the error of `p * grid_size_inv`.
 pub fn grid_index_from_point(p: &Point3<Real>, grid_size_inv: Real) -> Point3<i64> {
-    Point3::new(
-        (p.x * grid_size_inv + Real::EPSILON).floor() as i64,
-        (p.y * grid_size_inv + Real::EPSILON).floor() as i64,
-        (p.z * grid_size_inv + Real::EPSILON).floor() as i64,
-    )
+    (p * grid_size_inv).map(|v| (v + 4.0 * Real::EPSILON * v.abs().max(1.0)).floor() as i64)
 }
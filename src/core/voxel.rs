@@ -2,7 +2,11 @@ use std::fmt::Debug;
 
 use bitflags::bitflags;
 
-use super::{color::rainbow_map, prelude::*};
+use super::{
+    color::rainbow_map,
+    index::{BlockIndex, GlobalIndex},
+    prelude::*,
+};
 
 pub trait Voxel: Default + Clone + Copy + Debug {}
 
@@ -11,8 +15,13 @@ pub trait DrawableVoxel {
     fn color(&self) -> Color;
 }
 
+pub trait DistanceVoxel {
+    /// The stored distance or `None` if the voxel holds no valid distance
+    fn distance(&self) -> Option<Real>;
+}
+
 /// Tsdf Voxel
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct Tsdf {
     pub distance: Real,
     pub weight: Real,
@@ -20,6 +29,30 @@ pub struct Tsdf {
 
 impl Voxel for Tsdf {}
 
+impl Tsdf {
+    /// Fuses a new measurement into the running weighted average,
+    /// the accumulated weight is capped at `max_weight`
+    ///
+    /// Returns true if the voxel changed.
+    pub fn fuse(&mut self, distance: Real, weight: Real, max_weight: Real) -> bool {
+        if weight <= 0.0 {
+            return false;
+        }
+
+        let previous = *self;
+        self.distance = (self.distance * self.weight + distance * weight) / (self.weight + weight);
+        self.weight = (self.weight + weight).min(max_weight);
+
+        self.distance != previous.distance || self.weight != previous.weight
+    }
+}
+
+impl DistanceVoxel for Tsdf {
+    fn distance(&self) -> Option<Real> {
+        (self.weight > 0.0).then_some(self.distance)
+    }
+}
+
 impl DrawableVoxel for Tsdf {
     fn color(&self) -> Color {
         if self.weight.abs() > 1e-6 && self.distance < 0.2 {
@@ -34,7 +67,8 @@ impl DrawableVoxel for Tsdf {
 #[derive(Debug, Default, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 #[repr(C, align(16))]
 pub struct Esdf {
-    pub site_block_index: [i32; 3],
+    /// Global index of the closest site (surface voxel)
+    pub site_index: [i32; 3],
     pub distance: Real,
     pub flags: EsdfFlags,
     pub _pad: [u32; 3],
@@ -54,15 +88,51 @@ bitflags! {
         const SpilledYMinus = 1<<7;
         const SpilledZPlus = 1<<8;
         const SpilledZMinus = 1<<9;
+        const Inside = 1<<10;
+        /// Observed free space
+        const Free = 1<<11;
     }
 }
 
 impl Voxel for Esdf {}
 
+impl Esdf {
+    pub fn site_global_index<const VPS: usize>(&self) -> GlobalIndex<VPS> {
+        GlobalIndex(Point3::from(self.site_index).cast())
+    }
+
+    /// True if the voxel lies in observed space (a site, inside an obstacle or free),
+    /// distances are also propagated into unobserved space
+    pub fn is_known(&self) -> bool {
+        self.flags
+            .intersects(EsdfFlags::Observed | EsdfFlags::Inside | EsdfFlags::Free)
+    }
+
+    pub fn site_block_index<const VPS: usize>(&self) -> BlockIndex<VPS> {
+        self.site_global_index().block_index()
+    }
+
+    /// Euclidean distance between the voxel at `global_index` and the site at `site_index`
+    pub fn site_distance<const VPS: usize>(
+        global_index: &GlobalIndex<VPS>,
+        site_index: &[i32; 3],
+        voxel_size: Real,
+    ) -> Real {
+        let site_index: Point3<i64> = Point3::from(*site_index).cast();
+        (global_index.0 - site_index).cast::<Real>().norm() * voxel_size
+    }
+}
+
+impl DistanceVoxel for Esdf {
+    fn distance(&self) -> Option<Real> {
+        (self.flags.contains(EsdfFlags::HasSiteIndex) && self.is_known()).then_some(self.distance)
+    }
+}
+
 impl DrawableVoxel for Esdf {
     fn color(&self) -> Color {
         if self.distance != 0.0 && !self.flags.contains(EsdfFlags::Fixed) {
-            rainbow_map(self.distance.abs() as f32 / 4.0)
+            rainbow_map(self.distance.abs() / 4.0)
         } else {
             Color::default()
         }
@@ -70,14 +140,29 @@ impl DrawableVoxel for Esdf {
 }
 
 /// Occupancy Voxel
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct Occupancy {
-    pub probability: Real,
+    /// Occupancy probability in log-odds, 0 is unknown (p = 0.5)
+    pub log_odds: Real,
     pub observed: bool,
 }
 
 impl Voxel for Occupancy {}
 
+impl Occupancy {
+    pub fn probability(&self) -> Real {
+        probability_from_log_odds(self.log_odds)
+    }
+}
+
+pub fn log_odds_from_probability(probability: Real) -> Real {
+    (probability / (1.0 - probability)).ln()
+}
+
+pub fn probability_from_log_odds(log_odds: Real) -> Real {
+    1.0 - 1.0 / (1.0 + log_odds.exp())
+}
+
 #[derive(Debug, Default, Clone, Copy)]
 pub struct IntensityVoxel {
     pub intensity: Real,
@@ -1,61 +1,367 @@
-use nalgebra::point;
-
 use crate::core::{
-    index::{BlockIndex, VoxelIndex},
+    index::{BlockIndex, GlobalIndex},
     layer::Layer,
-    voxel::{Esdf, EsdfFlags, Tsdf},
+    prelude::*,
+    voxel::{Esdf, EsdfFlags, Occupancy, Tsdf, Voxel},
+};
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    time::{Duration, Instant},
 };
 
-use std::{collections::BTreeSet, time::Duration};
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EsdfIntegratorConfig {
+    /// Only sweep and propagate along x and y, e.g., for planar maps
+    pub planar: bool,
+    /// Assign negative distances to voxels inside of obstacles
+    pub signed: bool,
+    /// Distances are not propagated beyond this value
+    pub max_distance: Real,
+    /// Minimum accumulated weight of a TSDF voxel to be considered observed
+    pub min_weight: Real,
+    /// Maximum distance (in voxels) of an observed TSDF voxel to be considered a site
+    pub max_site_distance_vox: Real,
+    /// Occupancy voxels above this probability are sites
+    pub occupied_probability: Real,
+    /// Stops the update after this many passes, e.g., to bound its time
+    ///
+    /// Blocks that did not converge are only updated again once they are dirty.
+    pub max_passes: Option<usize>,
+}
+
+impl Default for EsdfIntegratorConfig {
+    fn default() -> Self {
+        Self {
+            planar: false,
+            signed: false,
+            max_distance: Real::MAX,
+            min_weight: 1e-4,
+            max_site_distance_vox: 0.5,
+            occupied_probability: 0.5,
+            max_passes: None,
+        }
+    }
+}
+
+impl EsdfIntegratorConfig {
+    /// Returns true if the TSDF voxel is an observed surface or lies behind one
+    pub fn is_occupied(&self, tsdf_voxel: &Tsdf, voxel_size: Real) -> bool {
+        tsdf_voxel.weight >= self.min_weight
+            && tsdf_voxel.distance <= self.max_site_distance_vox * voxel_size
+    }
+}
+
+/// Classification of a map voxel for the ESDF
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SiteClass {
+    /// A site or inside of an obstacle
+    Occupied,
+    Free,
+    Unknown,
+}
+
+/// Voxels the ESDF can be computed from
+pub trait SiteVoxel: Voxel {
+    fn classify(&self, config: &EsdfIntegratorConfig, voxel_size: Real) -> SiteClass;
+}
+
+impl SiteVoxel for Tsdf {
+    fn classify(&self, config: &EsdfIntegratorConfig, voxel_size: Real) -> SiteClass {
+        if config.is_occupied(self, voxel_size) {
+            SiteClass::Occupied
+        } else if self.weight >= config.min_weight {
+            SiteClass::Free
+        } else {
+            SiteClass::Unknown
+        }
+    }
+}
+
+impl SiteVoxel for Occupancy {
+    fn classify(&self, config: &EsdfIntegratorConfig, _voxel_size: Real) -> SiteClass {
+        if !self.observed {
+            SiteClass::Unknown
+        } else if self.probability() > config.occupied_probability {
+            SiteClass::Occupied
+        } else {
+            SiteClass::Free
+        }
+    }
+}
+
+/// Axis of a sweep or propagation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+/// Direction along an [`Axis`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Plus,
+    Minus,
+}
+
+/// Blocks, work and time of an [`EsdfEvent`]
+#[derive(Debug, Clone, Copy)]
+pub struct EsdfEventInfo<'a, const VPS: usize> {
+    pub blocks: &'a [BlockIndex<VPS>],
+    pub updated_voxels: usize,
+    pub wall_time: Duration,
+    /// Only measured by the GPU backend on devices supporting timestamps
+    pub gpu_time: Option<Duration>,
+}
+
+impl<'a, const VPS: usize> EsdfEventInfo<'a, VPS> {
+    pub fn new(blocks: &'a [BlockIndex<VPS>], updated_voxels: usize, wall_time: Duration) -> Self {
+        Self {
+            blocks,
+            updated_voxels,
+            wall_time,
+            gpu_time: None,
+        }
+    }
+}
+
+/// Phase of the ESDF update
+#[derive(Debug, Clone, Copy)]
+pub enum EsdfEvent<'a, const VPS: usize> {
+    /// Blocks of the map layer (TSDF or occupancy) that changed since the last update
+    MapUpdated(EsdfEventInfo<'a, VPS>),
+    /// Blocks reset as they hold distances to sites that changed
+    SiteClear(EsdfEventInfo<'a, VPS>),
+    /// Sites transferred from the map to the cleared blocks, counts the sites
+    Transfer(EsdfEventInfo<'a, VPS>),
+    /// Sweep within a block
+    Sweep {
+        axis: Axis,
+        dir: Direction,
+        info: EsdfEventInfo<'a, VPS>,
+    },
+    /// Propagation across a side into the neighboring block, which is the one reported
+    Propagate {
+        axis: Axis,
+        dir: Direction,
+        info: EsdfEventInfo<'a, VPS>,
+    },
+    /// Sweep and propagation of all dirty blocks, reports the blocks to process next
+    ///
+    /// A pass of the GPU backend covers several iterations.
+    PassComplete {
+        pass: usize,
+        info: EsdfEventInfo<'a, VPS>,
+    },
+    /// No block changes anymore or [`EsdfIntegratorConfig::max_passes`] was reached,
+    /// reports the totals
+    Converged {
+        passes: usize,
+        info: EsdfEventInfo<'a, VPS>,
+    },
+}
+
+impl<'a, const VPS: usize> EsdfEvent<'a, VPS> {
+    pub fn info(&self) -> &EsdfEventInfo<'a, VPS> {
+        match self {
+            EsdfEvent::MapUpdated(info)
+            | EsdfEvent::SiteClear(info)
+            | EsdfEvent::Transfer(info)
+            | EsdfEvent::Sweep { info, .. }
+            | EsdfEvent::Propagate { info, .. }
+            | EsdfEvent::PassComplete { info, .. }
+            | EsdfEvent::Converged { info, .. } => info,
+        }
+    }
+}
+
+impl Display for Axis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Axis::X => write!(f, "x"),
+            Axis::Y => write!(f, "y"),
+            Axis::Z => write!(f, "z"),
+        }
+    }
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Plus => write!(f, "+"),
+            Direction::Minus => write!(f, "-"),
+        }
+    }
+}
+
+impl<const VPS: usize> Display for EsdfEvent<'_, VPS> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EsdfEvent::MapUpdated(_) => write!(f, "map updated"),
+            EsdfEvent::SiteClear(_) => write!(f, "clear site"),
+            EsdfEvent::Transfer(_) => write!(f, "transfer"),
+            EsdfEvent::Sweep { axis, dir, .. } => write!(f, "sweep: {axis}{dir}"),
+            EsdfEvent::Propagate { axis, dir, .. } => write!(f, "prop.: {axis}{dir}"),
+            EsdfEvent::PassComplete { pass, .. } => write!(f, "pass {pass}"),
+            EsdfEvent::Converged { passes, .. } => write!(f, "converged after {passes} passes"),
+        }
+    }
+}
+
+/// Work of a sweep and propagate pass, see [`EsdfEvent::PassComplete`]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EsdfPassStats {
+    /// Blocks updated by the pass, processed by the next one
+    pub updated_blocks: usize,
+    pub updated_voxels: usize,
+    #[serde(with = "duration_secs")]
+    pub cpu_time: Duration,
+    #[serde(with = "option_duration_secs")]
+    pub gpu_time: Option<Duration>,
+}
+
+/// Summary of [`EsdfIntegrator::update_blocks`], durations are serialized in seconds
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EsdfUpdateStats {
+    pub blocks_cleared: usize,
+    /// Sites of the cleared blocks that are no longer sites after the transfer
+    pub sites_invalidated: usize,
+    /// Sites transferred from the map
+    pub sites: usize,
+    /// Sweeps over the dirty blocks, on the GPU there are several per pass
+    pub sweep_passes: usize,
+    /// Propagations from the dirty blocks, on the GPU there are several per pass
+    pub propagate_passes: usize,
+    /// On the GPU a pass is a submission of several sweeps and propagations
+    pub passes: Vec<EsdfPassStats>,
+    /// Wall time of the whole update
+    #[serde(with = "duration_secs")]
+    pub cpu_time: Duration,
+    /// Only measured by the GPU backend on devices supporting timestamps
+    #[serde(with = "option_duration_secs")]
+    pub gpu_time: Option<Duration>,
+    /// False if [`EsdfIntegratorConfig::max_passes`] stopped the update early
+    pub converged: bool,
+    /// Why the GPU backend updated on the CPU instead, if it did
+    pub cpu_fallback: Option<String>,
+}
+
+impl EsdfUpdateStats {
+    pub fn updated_voxels(&self) -> usize {
+        self.passes.iter().map(|pass| pass.updated_voxels).sum()
+    }
+}
+
+mod duration_secs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
 
-#[derive(Default)]
-pub struct EsdfIntegratorConfig {}
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
 
-enum OpDir {
-    XPlus,
-    XMinus,
-    YPlus,
-    YMinus,
-    ZPlus,
-    ZMinus,
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_secs_f64(f64::deserialize(deserializer)?))
+    }
 }
 
-pub struct EsdfIntegrator {
+mod option_duration_secs {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => serializer.serialize_some(&duration.as_secs_f64()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<f64>::deserialize(deserializer)?.map(Duration::from_secs_f64))
+    }
+}
+
+/// Callback invoked by the backends after each operation
+pub type EsdfCallback<'a, const VPS: usize> = dyn FnMut(&EsdfEvent<VPS>, &Layer<Esdf, VPS>) + 'a;
+
+/// The sweep and propagate phases of the ESDF update
+pub trait EsdfBackend<const VPS: usize> {
+    /// Sweeps and propagates from `dirty_blocks` until no block changes anymore
+    ///
+    /// `changed_blocks` were modified on the host since the last update.
+    /// Backends keeping their own copy of the blocks may leave the layer outdated until [`EsdfBackend::sync`].
+    /// Returns the stats of the passes, the integrator fills in the rest.
+    fn update(
+        &mut self,
+        esdf_layer: &mut Layer<Esdf, VPS>,
+        changed_blocks: &BTreeSet<BlockIndex<VPS>>,
+        dirty_blocks: BTreeSet<BlockIndex<VPS>>,
+        config: &EsdfIntegratorConfig,
+        callback: &mut EsdfCallback<VPS>,
+    ) -> EsdfUpdateStats;
+
+    /// Writes the distances held by the backend back to the layer
+    fn sync(&mut self, _esdf_layer: &mut Layer<Esdf, VPS>) {}
+}
+
+pub struct EsdfIntegrator<const VPS: usize> {
     config: EsdfIntegratorConfig,
+    backend: Box<dyn EsdfBackend<VPS>>,
 }
 
-impl EsdfIntegrator {
-    pub fn new(config: EsdfIntegratorConfig) -> Self {
-        Self { config }
+impl<const VPS: usize> EsdfIntegrator<VPS> {
+    pub fn new(config: EsdfIntegratorConfig, backend: Box<dyn EsdfBackend<VPS>>) -> Self {
+        Self { config, backend }
     }
 
+    /// Brings `esdf_layer` up to date with the backend, see [`EsdfBackend::sync`]
+    pub fn sync(&mut self, esdf_layer: &mut Layer<Esdf, VPS>) {
+        self.backend.sync(esdf_layer);
+    }
+
+    /// Updates the ESDF from the blocks of `map_layer` (e.g., TSDF or occupancy) that changed
+    ///
+    /// The ESDF layer shares the grid of the map layer and takes over its origin.
+    /// Depending on the backend, the layer holds the result only after [`EsdfIntegrator::sync`].
     pub fn update_blocks<
-        const VPS: usize,
-        F: FnMut(&str, &Layer<Tsdf, VPS>, &Layer<Esdf, VPS>, &[BlockIndex<VPS>], Duration),
+        V: SiteVoxel,
+        F: FnMut(&EsdfEvent<VPS>, &Layer<V, VPS>, &Layer<Esdf, VPS>),
     >(
         &mut self,
-        tsdf_layer: &Layer<Tsdf, VPS>,
+        map_layer: &Layer<V, VPS>,
         esdf_layer: &mut Layer<Esdf, VPS>,
         updated_blocks: &BTreeSet<BlockIndex<VPS>>,
         mut callback: F,
-    ) {
-        let start = std::time::Instant::now();
+    ) -> EsdfUpdateStats {
+        let start = Instant::now();
+        // sites of the cleared blocks, the ones that are not transferred again are invalidated
+        let mut previous_sites = BTreeSet::new();
 
         let mut dirty_blocks = BTreeSet::new();
-        let mut propagate_blocks = BTreeSet::new();
         let mut sites_indices_to_clear = BTreeSet::new();
         let mut blocks_to_clear = updated_blocks.clone();
 
+        esdf_layer.set_origin(*map_layer.origin());
+        self.backend.sync(esdf_layer);
+
+        let blocks: Vec<_> = updated_blocks.iter().copied().collect();
         callback(
-            "tsdf updated",
-            tsdf_layer,
+            &EsdfEvent::MapUpdated(EsdfEventInfo::new(&blocks, 0, Duration::ZERO)),
+            map_layer,
             esdf_layer,
-            &updated_blocks.iter().copied().collect::<Vec<_>>(),
-            Duration::from_millis(500),
         );
 
-        // allocate all blocks from the tsdf layer
-        for block_index in tsdf_layer.allocated_blocks_iter() {
+        // allocate all blocks from the map layer
+        for block_index in map_layer.allocated_blocks_iter() {
             esdf_layer.allocate_block_by_index(block_index);
         }
 
@@ -66,11 +372,7 @@ impl EsdfIntegrator {
 
             for voxel in esdf_lock.as_slice() {
                 if voxel.flags.contains(EsdfFlags::HasSiteIndex) {
-                    sites_indices_to_clear.insert(BlockIndex::new(
-                        voxel.site_block_index[0],
-                        voxel.site_block_index[1],
-                        voxel.site_block_index[2],
-                    ));
+                    sites_indices_to_clear.insert(voxel.site_block_index::<VPS>());
                 }
             }
         }
@@ -88,11 +390,7 @@ impl EsdfIntegrator {
 
                     for voxel in esdf_lock.as_slice() {
                         if voxel.flags.contains(EsdfFlags::HasSiteIndex)
-                            && sites_indices_to_clear.contains(&BlockIndex::<VPS>::new(
-                                voxel.site_block_index[0],
-                                voxel.site_block_index[1],
-                                voxel.site_block_index[2],
-                            ))
+                            && sites_indices_to_clear.contains(&voxel.site_block_index())
                         {
                             blocks_to_clear.insert(index);
                             flagged_clear = true;
@@ -120,287 +418,528 @@ impl EsdfIntegrator {
 
         // reset blocks
         for block_index in &blocks_to_clear {
+            let clear_start = Instant::now();
             {
                 let esdf_block = esdf_layer.allocate_block_by_index(block_index);
                 let mut esdf_lock = esdf_block.write();
+                previous_sites.extend(
+                    esdf_lock
+                        .as_slice()
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, voxel)| voxel.flags.contains(EsdfFlags::Observed))
+                        .map(|(i, _)| (*block_index, i)),
+                );
                 esdf_lock.reset_voxels();
             }
 
             callback(
-                "clear site",
-                tsdf_layer,
+                &EsdfEvent::SiteClear(EsdfEventInfo::new(
+                    &[*block_index],
+                    VPS * VPS * VPS,
+                    clear_start.elapsed(),
+                )),
+                map_layer,
                 esdf_layer,
-                &[*block_index],
-                Duration::from_millis(50),
             );
         }
 
-        // transfer tsdf to esdf
+        // transfer map to esdf
+        let transfer_start = Instant::now();
+        let mut sites = 0;
         for block_index in &blocks_to_clear {
-            let tsdf_block = tsdf_layer.block_by_index(block_index).unwrap();
+            // not in the map, stays unknown after the reset
+            let Some(map_block) = map_layer.block_by_index(block_index) else {
+                continue;
+            };
             let esdf_block = esdf_layer.allocate_block_by_index(block_index);
             let mut esdf_lock = esdf_block.write();
 
-            for (i, tsdf_voxel) in tsdf_block.read().as_slice().iter().enumerate() {
+            for (i, map_voxel) in map_block.read().as_slice().iter().enumerate() {
                 let esdf_voxel = esdf_lock.voxel_from_lin_index_mut(i);
 
-                if tsdf_voxel.weight > 0.0 {
-                    esdf_voxel.distance = tsdf_voxel.distance;
-                    esdf_voxel
-                        .flags
-                        .insert(EsdfFlags::Fixed | EsdfFlags::Observed | EsdfFlags::HasSiteIndex);
-                    esdf_voxel.site_block_index = block_index.coords.into();
-                    dirty_blocks.insert(*block_index);
+                let class = map_voxel.classify(&self.config, map_layer.voxel_size());
+
+                if class == SiteClass::Occupied {
+                    let global_index = GlobalIndex::from_block_and_local_lin_index(block_index, i);
+
+                    if self.config.signed && is_inside(map_layer, &global_index, &self.config) {
+                        // gets its (negative) distance from the sites around it
+                        esdf_voxel.distance = 0.0;
+                        esdf_voxel.flags = EsdfFlags::Inside;
+                    } else {
+                        // a site is its own closest site
+                        esdf_voxel.distance = 0.0;
+                        esdf_voxel.flags.insert(
+                            EsdfFlags::Fixed | EsdfFlags::Observed | EsdfFlags::HasSiteIndex,
+                        );
+                        esdf_voxel.site_index = global_index.coords.cast().into();
+                        dirty_blocks.insert(*block_index);
+                        previous_sites.remove(&(*block_index, i));
+                        sites += 1;
+                    }
                 } else {
                     esdf_voxel.distance = 0.0;
                     esdf_voxel.flags.remove(EsdfFlags::all());
+
+                    if class == SiteClass::Free {
+                        esdf_voxel.flags.insert(EsdfFlags::Free);
+                    }
                 }
             }
         }
 
-        while !dirty_blocks.is_empty() {
-            while let Some(block_index) = dirty_blocks.pop_first() {
-                Self::sweep_block(OpDir::XPlus, &block_index, esdf_layer);
-                callback(
-                    "sweep: x+",
-                    tsdf_layer,
-                    esdf_layer,
-                    &[block_index],
-                    Duration::from_millis(50),
-                );
-                Self::sweep_block(OpDir::XMinus, &block_index, esdf_layer);
-                callback(
-                    "sweep: x-",
-                    tsdf_layer,
-                    esdf_layer,
-                    &[block_index],
-                    Duration::from_millis(50),
-                );
-                Self::sweep_block(OpDir::YPlus, &block_index, esdf_layer);
-                callback(
-                    "sweep: y+",
-                    tsdf_layer,
-                    esdf_layer,
-                    &[block_index],
-                    Duration::from_millis(50),
-                );
-                Self::sweep_block(OpDir::YMinus, &block_index, esdf_layer);
-                callback(
-                    "sweep: y-",
-                    tsdf_layer,
-                    esdf_layer,
-                    &[block_index],
-                    Duration::from_millis(50),
-                );
+        let blocks: Vec<_> = blocks_to_clear.iter().copied().collect();
+        callback(
+            &EsdfEvent::Transfer(EsdfEventInfo::new(&blocks, sites, transfer_start.elapsed())),
+            map_layer,
+            esdf_layer,
+        );
 
-                propagate_blocks.insert(block_index);
-            }
+        let mut backend_callback = |event: &EsdfEvent<VPS>, esdf_layer: &Layer<Esdf, VPS>| {
+            callback(event, map_layer, esdf_layer)
+        };
 
-            while let Some(block_index) = propagate_blocks.pop_first() {
-                if let Some(dirty_block_index) =
-                    Self::propagate_to_neighbour(OpDir::XPlus, &block_index, esdf_layer)
-                {
-                    dirty_blocks.insert(dirty_block_index);
-                    callback(
-                        "prop.: x+",
-                        tsdf_layer,
-                        esdf_layer,
-                        &[dirty_block_index],
-                        Duration::from_millis(50),
-                    );
-                }
+        let stats = self.backend.update(
+            esdf_layer,
+            &blocks_to_clear,
+            dirty_blocks,
+            &self.config,
+            &mut backend_callback,
+        );
 
-                if let Some(dirty_block_index) =
-                    Self::propagate_to_neighbour(OpDir::XMinus, &block_index, esdf_layer)
-                {
-                    dirty_blocks.insert(dirty_block_index);
-                    callback(
-                        "prop.: x-",
-                        tsdf_layer,
-                        esdf_layer,
-                        &[dirty_block_index],
-                        Duration::from_millis(50),
-                    );
-                }
+        EsdfUpdateStats {
+            blocks_cleared: blocks_to_clear.len(),
+            sites_invalidated: previous_sites.len(),
+            sites,
+            cpu_time: start.elapsed(),
+            ..stats
+        }
+    }
+}
 
-                if let Some(dirty_block_index) =
-                    Self::propagate_to_neighbour(OpDir::YPlus, &block_index, esdf_layer)
-                {
-                    dirty_blocks.insert(dirty_block_index);
-                    callback(
-                        "prop.: y+",
-                        tsdf_layer,
-                        esdf_layer,
-                        &[dirty_block_index],
-                        Duration::from_millis(50),
-                    );
-                }
+/// Returns true if the map voxel at `global_index` is occupied
+fn is_occupied<V: SiteVoxel, const VPS: usize>(
+    map_layer: &Layer<V, VPS>,
+    global_index: &GlobalIndex<VPS>,
+    config: &EsdfIntegratorConfig,
+) -> bool {
+    let (block_index, voxel_index) = global_index.block_voxel_index();
+
+    map_layer.block_by_index(&block_index).is_some_and(|block| {
+        block
+            .read()
+            .voxel_from_index(&voxel_index)
+            .classify(config, map_layer.voxel_size())
+            == SiteClass::Occupied
+    })
+}
 
-                if let Some(dirty_block_index) =
-                    Self::propagate_to_neighbour(OpDir::YMinus, &block_index, esdf_layer)
-                {
-                    dirty_blocks.insert(dirty_block_index);
-                    callback(
-                        "prop.: y-",
-                        tsdf_layer,
-                        esdf_layer,
-                        &[dirty_block_index],
-                        Duration::from_millis(50),
-                    );
+/// An occupied voxel is inside of an obstacle if it has no free neighbours
+pub fn is_inside<V: SiteVoxel, const VPS: usize>(
+    map_layer: &Layer<V, VPS>,
+    global_index: &GlobalIndex<VPS>,
+    config: &EsdfIntegratorConfig,
+) -> bool {
+    global_index
+        .neighbors6_include_self()
+        .filter(|neighbour| neighbour.dir != Vector3::zeros())
+        .filter(|neighbour| !config.planar || neighbour.dir.z == 0)
+        .all(|neighbour| is_occupied(map_layer, &neighbour.index, config))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        core::{index::VoxelIndex, voxel::DistanceVoxel},
+        integrators::esdf_cpu::CpuBackend,
+    };
+
+    use super::*;
+
+    fn esdf_at(esdf_layer: &Layer<Esdf, 4>, global_index: GlobalIndex<4>) -> Esdf {
+        let (block_index, voxel_index) = global_index.block_voxel_index();
+        let block = esdf_layer.block_by_index(&block_index).unwrap();
+        let voxel = *block.read().voxel_from_index(&voxel_index);
+        voxel
+    }
+
+    fn update(
+        config: EsdfIntegratorConfig,
+        sites: impl IntoIterator<Item = GlobalIndex<4>>,
+    ) -> Layer<Esdf, 4> {
+        let surface = Tsdf {
+            distance: 0.0,
+            weight: 1.0,
+        };
+        update_tsdf(config, sites.into_iter().map(|site| (site, surface)))
+    }
+
+    fn update_tsdf(
+        config: EsdfIntegratorConfig,
+        tsdf_voxels: impl IntoIterator<Item = (GlobalIndex<4>, Tsdf)>,
+    ) -> Layer<Esdf, 4> {
+        let mut tsdf_layer = Layer::<Tsdf, 4>::new(1.0);
+        let mut esdf_layer = Layer::<Esdf, 4>::new(1.0);
+
+        // a stack of blocks
+        for x in 0..2 {
+            for y in 0..2 {
+                for z in 0..3 {
+                    tsdf_layer.allocate_block_by_index(&BlockIndex::new(x, y, z));
                 }
             }
         }
 
-        println!(
-            "=> ESDF update finished in {:?}",
-            std::time::Instant::now().duration_since(start)
+        for (global_index, tsdf_voxel) in tsdf_voxels {
+            let (block_index, voxel_index) = global_index.block_voxel_index();
+            let mut lock = tsdf_layer.block_by_index(&block_index).unwrap().write();
+            *lock.voxel_from_index_mut(&voxel_index) = tsdf_voxel;
+        }
+
+        let updated_blocks = BTreeSet::from_iter(tsdf_layer.allocated_blocks_iter().copied());
+        EsdfIntegrator::new(config, Box::new(CpuBackend::default())).update_blocks(
+            &tsdf_layer,
+            &mut esdf_layer,
+            &updated_blocks,
+            |_, _, _| {},
         );
+
+        esdf_layer
     }
 
-    fn sweep_block<const VPS: usize>(
-        dir: OpDir,
-        index: &BlockIndex<VPS>,
-        esdf_layer: &mut Layer<Esdf, VPS>,
-    ) {
-        let (step, order) = match dir {
-            OpDir::XPlus => (1i32, [2, 1, 0]),
-            OpDir::XMinus => (-1, [2, 1, 0]),
-            OpDir::YPlus => (1, [2, 0, 1]),
-            OpDir::YMinus => (-1, [2, 0, 1]),
-            OpDir::ZPlus => (1, [1, 0, 2]),
-            OpDir::ZMinus => (-1, [1, 0, 2]),
+    #[test]
+    fn unallocated_map_block() {
+        let mut tsdf_layer = Layer::<Tsdf, 4>::new(1.0);
+        let mut esdf_layer = Layer::<Esdf, 4>::new(1.0);
+        *tsdf_layer
+            .allocate_block_by_index(&BlockIndex::new(0, 0, 0))
+            .write()
+            .voxel_from_index_mut(&VoxelIndex(Point3::new(1, 1, 0))) = Tsdf {
+            distance: 0.0,
+            weight: 1.0,
         };
 
-        let voxel_size = esdf_layer.voxel_size();
-        let mut lock = esdf_layer.block_by_index(index).unwrap().write();
+        let missing = BlockIndex::new(1, 0, 0);
+        let updated_blocks = BTreeSet::from([BlockIndex::new(0, 0, 0), missing]);
+        let config = EsdfIntegratorConfig {
+            planar: true,
+            ..Default::default()
+        };
+        let stats = EsdfIntegrator::new(config, Box::new(CpuBackend::default())).update_blocks(
+            &tsdf_layer,
+            &mut esdf_layer,
+            &updated_blocks,
+            |_, _, _| {},
+        );
 
-        for u in 0..VPS {
-            for v in 0..VPS {
-                let w_range = if step > 0 {
-                    create_range_chain(1, VPS - 1)
-                } else {
-                    create_range_chain(VPS - 2, 0)
-                };
-
-                for w in w_range {
-                    let mut p = point![0, 0, 0];
-                    p[order[0]] = u;
-                    p[order[1]] = v;
-                    p[order[2]] = w;
-
-                    let voxel_index = VoxelIndex(p);
-
-                    p[order[2]] = (w as i32 - step) as usize;
-                    let parent_voxel_index = VoxelIndex(p);
-
-                    let parent_voxel = lock.voxel_from_index(&parent_voxel_index);
-                    let parent_fixed = parent_voxel.flags.contains(EsdfFlags::Fixed);
-                    let parent_dist = parent_voxel.distance;
-                    let parent_site_block_index = parent_voxel.site_block_index;
-
-                    let voxel = lock.voxel_from_index_mut(&voxel_index);
-
-                    if parent_fixed && !voxel.flags.contains(EsdfFlags::Observed) {
-                        if !voxel.flags.contains(EsdfFlags::Fixed) {
-                            voxel.distance = parent_dist + voxel_size;
-                            voxel
-                                .flags
-                                .insert(EsdfFlags::Fixed | EsdfFlags::HasSiteIndex);
-                            voxel.site_block_index = parent_site_block_index;
-                        } else if voxel.distance > parent_dist + voxel_size {
-                            voxel.distance = parent_dist + voxel_size;
-                            voxel.site_block_index = parent_site_block_index;
-                        }
-                    }
-                }
-            }
-        }
+        assert!(stats.converged);
+        assert_eq!(stats.sites, 1);
+        assert!(esdf_layer
+            .block_by_index(&missing)
+            .unwrap()
+            .read()
+            .as_slice()
+            .iter()
+            .all(|voxel| !voxel.is_known()));
+        assert_eq!(
+            esdf_at(&esdf_layer, GlobalIndex(Point3::new(3, 1, 0))).distance,
+            2.0
+        );
     }
 
-    fn propagate_to_neighbour<const VPS: usize>(
-        dir: OpDir,
-        pivot_index: &BlockIndex<VPS>,
-        esdf_layer: &mut Layer<Esdf, VPS>,
-    ) -> Option<BlockIndex<VPS>> {
-        let voxel_size = esdf_layer.voxel_size();
-
-        let nblock_index = match dir {
-            OpDir::XPlus => BlockIndex::new(pivot_index.x + 1, pivot_index.y, pivot_index.z),
-            OpDir::XMinus => BlockIndex::new(pivot_index.x - 1, pivot_index.y, pivot_index.z),
-            OpDir::YPlus => BlockIndex::new(pivot_index.x, pivot_index.y + 1, pivot_index.z),
-            OpDir::YMinus => BlockIndex::new(pivot_index.x, pivot_index.y - 1, pivot_index.z),
-            OpDir::ZPlus => BlockIndex::new(pivot_index.x, pivot_index.y, pivot_index.z + 1),
-            OpDir::ZMinus => BlockIndex::new(pivot_index.x, pivot_index.y, pivot_index.z - 1),
+    #[test]
+    fn volumetric_update() {
+        let esdf_layer = update(
+            EsdfIntegratorConfig::default(),
+            [GlobalIndex(Point3::new(1, 1, 1))],
+        );
+
+        // above the site, across two block boundaries
+        let voxel = esdf_at(&esdf_layer, GlobalIndex(Point3::new(1, 1, 10)));
+        assert!(voxel.flags.contains(EsdfFlags::Fixed));
+        assert_eq!(voxel.distance, 9.0);
+        assert_eq!(voxel.site_index, [1, 1, 1]);
+    }
+
+    #[test]
+    fn planar_update() {
+        let config = EsdfIntegratorConfig {
+            planar: true,
+            ..Default::default()
         };
+        let esdf_layer = update(config, [GlobalIndex(Point3::new(1, 1, 1))]);
+
+        // same plane
+        let voxel = esdf_at(&esdf_layer, GlobalIndex(Point3::new(3, 1, 1)));
+        assert!(voxel.flags.contains(EsdfFlags::Fixed));
+        assert_eq!(voxel.distance, 2.0);
+
+        // diagonal, across a block boundary
+        let voxel = esdf_at(&esdf_layer, GlobalIndex(Point3::new(4, 5, 1)));
+        assert_eq!(voxel.distance, 5.0);
+        assert_eq!(voxel.site_index, [1, 1, 1]);
+
+        // nothing is propagated along z
+        let voxel = esdf_at(&esdf_layer, GlobalIndex(Point3::new(1, 1, 10)));
+        assert!(!voxel.flags.contains(EsdfFlags::Fixed));
+    }
 
-        let (n_index, p_index, order) = match dir {
-            OpDir::XPlus => (0usize, VPS - 1, [2, 1, 0]),
-            OpDir::XMinus => (VPS - 1, 0, [2, 1, 0]),
-            OpDir::YPlus => (0, VPS - 1, [2, 0, 1]),
-            OpDir::YMinus => (VPS - 1, 0, [2, 0, 1]),
-            OpDir::ZPlus => (0, VPS - 1, [1, 0, 2]),
-            OpDir::ZMinus => (VPS - 1, 0, [1, 0, 2]),
+    #[test]
+    fn signed_update() {
+        let config = EsdfIntegratorConfig {
+            planar: true,
+            signed: true,
+            ..Default::default()
         };
 
-        let mut dirty = false;
+        // 5x5 obstacle
+        let obstacle = (1..6).flat_map(|x| (1..6).map(move |y| GlobalIndex(Point3::new(x, y, 1))));
+        let esdf_layer = update(config, obstacle);
 
-        if let Some(neighbour_block) = esdf_layer.block_by_index(&nblock_index) {
-            let pivot_block = esdf_layer.block_by_index(pivot_index).unwrap().read();
+        // boundary
+        let voxel = esdf_at(&esdf_layer, GlobalIndex(Point3::new(1, 3, 1)));
+        assert!(voxel.flags.contains(EsdfFlags::Observed));
+        assert_eq!(voxel.distance, 0.0);
 
-            let mut nlock = neighbour_block.write();
+        // center
+        let voxel = esdf_at(&esdf_layer, GlobalIndex(Point3::new(3, 3, 1)));
+        assert!(voxel.flags.contains(EsdfFlags::Inside | EsdfFlags::Fixed));
+        assert_eq!(voxel.distance, -2.0);
 
-            for u in 0..VPS {
-                for v in 0..VPS {
-                    let mut p = point![0, 0, 0];
-                    p[order[0]] = u;
-                    p[order[1]] = v;
-                    p[order[2]] = p_index;
+        // outside
+        let voxel = esdf_at(&esdf_layer, GlobalIndex(Point3::new(3, 7, 1)));
+        assert!(!voxel.flags.contains(EsdfFlags::Inside));
+        assert_eq!(voxel.distance, 2.0);
+    }
 
-                    let p_voxel_index = VoxelIndex(p);
+    #[test]
+    fn max_distance() {
+        let config = EsdfIntegratorConfig {
+            max_distance: 3.0,
+            ..Default::default()
+        };
+        let esdf_layer = update(config, [GlobalIndex(Point3::new(1, 1, 1))]);
 
-                    p[order[2]] = n_index;
-                    let n_voxel_index = VoxelIndex(p);
+        let voxel = esdf_at(&esdf_layer, GlobalIndex(Point3::new(1, 1, 4)));
+        assert!(voxel.flags.contains(EsdfFlags::Fixed));
+        assert_eq!(voxel.distance, 3.0);
 
-                    // propagate through the sides
-                    let pivot_voxel = pivot_block.voxel_from_index(&p_voxel_index);
-                    let pivot_fixed = pivot_voxel.flags.contains(EsdfFlags::Fixed);
-                    let pivot_dist = pivot_voxel.distance;
-                    let pivot_site_block_index = pivot_voxel.site_block_index;
+        let voxel = esdf_at(&esdf_layer, GlobalIndex(Point3::new(1, 1, 5)));
+        assert!(!voxel.flags.contains(EsdfFlags::Fixed));
+    }
 
-                    let neighbour_voxel = nlock.voxel_from_index_mut(&n_voxel_index);
-                    let neighbour_fixed = neighbour_voxel.flags.contains(EsdfFlags::Fixed);
-                    let neighbour_observed = neighbour_voxel.flags.contains(EsdfFlags::Observed);
+    #[test]
+    fn site_thresholds() {
+        let config = EsdfIntegratorConfig {
+            min_weight: 0.5,
+            max_site_distance_vox: 1.0,
+            ..Default::default()
+        };
+        let voxel_size = 0.1;
 
-                    if pivot_fixed && !neighbour_observed {
-                        if neighbour_fixed {
-                            // found a shorter distance?
-                            if neighbour_voxel.distance > pivot_dist + voxel_size {
-                                neighbour_voxel.distance = pivot_dist + voxel_size;
-                                neighbour_voxel.site_block_index = pivot_site_block_index;
-                                dirty = true;
-                            }
-                        } else {
-                            neighbour_voxel.distance = pivot_dist + voxel_size;
-                            neighbour_voxel.flags.insert(EsdfFlags::Fixed);
-                            neighbour_voxel.site_block_index = pivot_site_block_index;
-                            dirty = true;
-                        }
-                    }
+        let surface = Tsdf {
+            distance: 0.05,
+            weight: 1.0,
+        };
+        let behind_surface = Tsdf {
+            distance: -0.3,
+            weight: 1.0,
+        };
+        let free = Tsdf {
+            distance: 0.15,
+            weight: 1.0,
+        };
+        let uncertain = Tsdf {
+            distance: 0.0,
+            weight: 0.1,
+        };
+
+        assert!(config.is_occupied(&surface, voxel_size));
+        assert!(config.is_occupied(&behind_surface, voxel_size));
+        assert!(!config.is_occupied(&free, voxel_size));
+        assert!(!config.is_occupied(&uncertain, voxel_size));
+    }
+
+    #[test]
+    fn events() {
+        let mut tsdf_layer = Layer::<Tsdf, 4>::new(1.0);
+        let mut esdf_layer = Layer::<Esdf, 4>::new(1.0);
+        for x in 0..2 {
+            tsdf_layer.allocate_block_by_index(&BlockIndex::new(x, 0, 0));
+        }
+        *tsdf_layer
+            .block_by_index(&BlockIndex::new(0, 0, 0))
+            .unwrap()
+            .write()
+            .voxel_from_index_mut(&VoxelIndex(Point3::new(1, 1, 0))) = Tsdf {
+            distance: 0.0,
+            weight: 1.0,
+        };
+
+        let mut labels = vec![];
+        let mut pass_voxels = 0;
+        let mut total_voxels = None;
+        let updated_blocks = BTreeSet::from_iter(tsdf_layer.allocated_blocks_iter().copied());
+        let config = EsdfIntegratorConfig {
+            planar: true,
+            ..Default::default()
+        };
+        EsdfIntegrator::new(config, Box::new(CpuBackend::default())).update_blocks(
+            &tsdf_layer,
+            &mut esdf_layer,
+            &updated_blocks,
+            |event, _, _| {
+                labels.push(event.to_string());
+                match event {
+                    EsdfEvent::Transfer(info) => assert_eq!(info.updated_voxels, 1),
+                    EsdfEvent::PassComplete { info, .. } => pass_voxels += info.updated_voxels,
+                    EsdfEvent::Converged { info, .. } => total_voxels = Some(info.updated_voxels),
+                    _ => {}
                 }
-            }
+            },
+        );
+
+        assert_eq!(labels[0], "map updated");
+        assert_eq!(labels[1..3], ["clear site", "clear site"]);
+        assert_eq!(labels[3], "transfer");
+        assert_eq!(
+            labels[4..8],
+            ["sweep: x+", "sweep: x-", "sweep: y+", "sweep: y-"]
+        );
+        assert!(labels.contains(&"prop.: x+".to_string()));
+        assert!(labels.contains(&"pass 1".to_string()));
+        assert!(labels.last().unwrap().starts_with("converged after"));
+
+        // every voxel of the plane but the site was reached
+        assert_eq!(total_voxels, Some(pass_voxels));
+        assert!(pass_voxels >= 2 * 4 * 4 - 1);
+    }
+
+    #[test]
+    fn update_stats() {
+        let mut tsdf_layer = Layer::<Tsdf, 4>::new(1.0);
+        for x in 0..4 {
+            tsdf_layer.allocate_block_by_index(&BlockIndex::new(x, 0, 0));
+        }
+        for x in [0, 15] {
+            *tsdf_layer
+                .block_by_index(&BlockIndex::new(x / 4, 0, 0))
+                .unwrap()
+                .write()
+                .voxel_from_index_mut(&VoxelIndex(Point3::new(x as usize % 4, 0, 0))) = Tsdf {
+                distance: 0.0,
+                weight: 1.0,
+            };
         }
+        let updated_blocks = BTreeSet::from_iter(tsdf_layer.allocated_blocks_iter().copied());
+
+        let update = |max_passes| {
+            let config = EsdfIntegratorConfig {
+                planar: true,
+                max_passes,
+                ..Default::default()
+            };
+            let mut esdf_layer = Layer::<Esdf, 4>::new(1.0);
+            let mut integrator = EsdfIntegrator::new(config, Box::new(CpuBackend::default()));
+
+            let stats = integrator.update_blocks(
+                &tsdf_layer,
+                &mut esdf_layer,
+                &updated_blocks,
+                |_, _, _| {},
+            );
+            // nothing changed, the sites are kept
+            let unchanged = integrator.update_blocks(
+                &tsdf_layer,
+                &mut esdf_layer,
+                &updated_blocks,
+                |_, _, _| {},
+            );
 
-        dirty.then_some(nblock_index)
+            (stats, unchanged)
+        };
+
+        let (stats, unchanged) = update(None);
+        assert!(stats.converged);
+        assert_eq!(stats.blocks_cleared, 4);
+        assert_eq!(stats.sites, 2);
+        assert_eq!(stats.sites_invalidated, 0);
+        assert_eq!(stats.sweep_passes, stats.passes.len());
+        assert_eq!(stats.passes.last().unwrap().updated_blocks, 0);
+        assert!(stats.updated_voxels() >= 4 * 4 * 4 - 2);
+        assert_eq!(unchanged.sites, 2);
+        assert_eq!(unchanged.sites_invalidated, 0);
+
+        let (stats, _) = update(Some(1));
+        assert!(!stats.converged);
+        assert_eq!(stats.passes.len(), 1);
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["converged"], false);
+        assert_eq!(json["gpu_time"], serde_json::Value::Null);
+        assert!(json["cpu_time"].is_f64());
+        let parsed: EsdfUpdateStats = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.updated_voxels(), stats.updated_voxels());
+        assert_eq!(parsed.sweep_passes, 1);
+
+        // removing the site at x = 15 invalidates only that one
+        let mut esdf_layer = Layer::<Esdf, 4>::new(1.0);
+        let mut integrator = EsdfIntegrator::new(
+            EsdfIntegratorConfig {
+                planar: true,
+                ..Default::default()
+            },
+            Box::new(CpuBackend::default()),
+        );
+        integrator.update_blocks(&tsdf_layer, &mut esdf_layer, &updated_blocks, |_, _, _| {});
+
+        tsdf_layer
+            .block_by_index(&BlockIndex::new(3, 0, 0))
+            .unwrap()
+            .write()
+            .voxel_from_index_mut(&VoxelIndex(Point3::new(3, 0, 0)))
+            .distance = 3.0;
+        let removed =
+            integrator.update_blocks(&tsdf_layer, &mut esdf_layer, &updated_blocks, |_, _, _| {});
+        assert_eq!(removed.sites, 1);
+        assert_eq!(removed.sites_invalidated, 1);
     }
-}
 
-fn create_range_chain(a: usize, b: usize) -> impl Iterator<Item = usize> {
-    #[allow(clippy::reversed_empty_ranges)]
-    let (part1, part2) = if a <= b {
-        (a..=b, 1..=0)
-    } else {
-        (1..=0, b..=a)
-    };
+    #[test]
+    fn free_space() {
+        let site = GlobalIndex(Point3::new(1, 1, 1));
+        let free = GlobalIndex(Point3::new(4, 1, 1));
+        let unobserved = GlobalIndex(Point3::new(1, 4, 1));
+
+        let esdf_layer = update_tsdf(
+            EsdfIntegratorConfig::default(),
+            [
+                (
+                    site,
+                    Tsdf {
+                        distance: 0.0,
+                        weight: 1.0,
+                    },
+                ),
+                (
+                    free,
+                    Tsdf {
+                        distance: 3.0,
+                        weight: 1.0,
+                    },
+                ),
+            ],
+        );
 
-    part1.chain(part2.rev())
+        let voxel = esdf_at(&esdf_layer, free);
+        assert!(voxel.flags.contains(EsdfFlags::Free));
+        assert_eq!(DistanceVoxel::distance(&voxel), Some(3.0));
+
+        // has a distance, but lies in unobserved space
+        let voxel = esdf_at(&esdf_layer, unobserved);
+        assert!(!voxel.is_known());
+        assert_eq!(voxel.distance, 3.0);
+        assert_eq!(DistanceVoxel::distance(&voxel), None);
+    }
 }
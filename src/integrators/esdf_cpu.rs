@@ -0,0 +1,384 @@
+use nalgebra::point;
+
+use crate::core::{
+    index::{BlockIndex, GlobalIndex, VoxelIndex},
+    layer::Layer,
+    prelude::*,
+    voxel::{Esdf, EsdfFlags},
+};
+
+use super::esdf::{
+    Axis, Direction, EsdfBackend, EsdfCallback, EsdfEvent, EsdfEventInfo, EsdfIntegratorConfig,
+    EsdfPassStats, EsdfUpdateStats,
+};
+
+use std::{collections::BTreeSet, time::Instant};
+
+#[derive(Debug, Clone, Copy)]
+enum OpDir {
+    XPlus,
+    XMinus,
+    YPlus,
+    YMinus,
+    ZPlus,
+    ZMinus,
+}
+
+impl OpDir {
+    const PLANAR: [OpDir; 4] = [OpDir::XPlus, OpDir::XMinus, OpDir::YPlus, OpDir::YMinus];
+    const VOLUMETRIC: [OpDir; 6] = [
+        OpDir::XPlus,
+        OpDir::XMinus,
+        OpDir::YPlus,
+        OpDir::YMinus,
+        OpDir::ZPlus,
+        OpDir::ZMinus,
+    ];
+
+    fn dirs(planar: bool) -> &'static [OpDir] {
+        if planar {
+            &Self::PLANAR
+        } else {
+            &Self::VOLUMETRIC
+        }
+    }
+
+    fn axis_dir(&self) -> (Axis, Direction) {
+        match self {
+            OpDir::XPlus => (Axis::X, Direction::Plus),
+            OpDir::XMinus => (Axis::X, Direction::Minus),
+            OpDir::YPlus => (Axis::Y, Direction::Plus),
+            OpDir::YMinus => (Axis::Y, Direction::Minus),
+            OpDir::ZPlus => (Axis::Z, Direction::Plus),
+            OpDir::ZMinus => (Axis::Z, Direction::Minus),
+        }
+    }
+}
+
+/// Sweeps and propagates block by block on the CPU
+#[derive(Debug, Default)]
+pub struct CpuBackend {}
+
+impl<const VPS: usize> EsdfBackend<VPS> for CpuBackend {
+    fn update(
+        &mut self,
+        esdf_layer: &mut Layer<Esdf, VPS>,
+        _changed_blocks: &BTreeSet<BlockIndex<VPS>>,
+        mut dirty_blocks: BTreeSet<BlockIndex<VPS>>,
+        config: &EsdfIntegratorConfig,
+        callback: &mut EsdfCallback<VPS>,
+    ) -> EsdfUpdateStats {
+        let start = Instant::now();
+        let mut passes = 0;
+        let mut total_updated_voxels = 0;
+        let mut stats = EsdfUpdateStats::default();
+
+        while !dirty_blocks.is_empty() {
+            if config
+                .max_passes
+                .is_some_and(|max_passes| passes >= max_passes)
+            {
+                break;
+            }
+
+            let pass_start = Instant::now();
+
+            let mut updated_voxels = self.sweep(esdf_layer, &dirty_blocks, config, callback);
+            let (next_dirty_blocks, propagated_voxels) =
+                self.propagate(esdf_layer, &dirty_blocks, config, callback);
+            dirty_blocks = next_dirty_blocks;
+            updated_voxels += propagated_voxels;
+
+            passes += 1;
+            total_updated_voxels += updated_voxels;
+
+            let pass_time = pass_start.elapsed();
+            stats.passes.push(EsdfPassStats {
+                updated_blocks: dirty_blocks.len(),
+                updated_voxels,
+                cpu_time: pass_time,
+                gpu_time: None,
+            });
+
+            let blocks: Vec<_> = dirty_blocks.iter().copied().collect();
+            callback(
+                &EsdfEvent::PassComplete {
+                    pass: passes,
+                    info: EsdfEventInfo::new(&blocks, updated_voxels, pass_time),
+                },
+                esdf_layer,
+            );
+        }
+
+        callback(
+            &EsdfEvent::Converged {
+                passes,
+                info: EsdfEventInfo::new(&[], total_updated_voxels, start.elapsed()),
+            },
+            esdf_layer,
+        );
+
+        EsdfUpdateStats {
+            sweep_passes: passes,
+            propagate_passes: passes,
+            cpu_time: start.elapsed(),
+            converged: dirty_blocks.is_empty(),
+            ..stats
+        }
+    }
+}
+
+impl CpuBackend {
+    /// Sweeps along all axes within each of the blocks, returns the number of updated voxels
+    pub fn sweep<const VPS: usize>(
+        &mut self,
+        esdf_layer: &mut Layer<Esdf, VPS>,
+        blocks: &BTreeSet<BlockIndex<VPS>>,
+        config: &EsdfIntegratorConfig,
+        callback: &mut EsdfCallback<VPS>,
+    ) -> usize {
+        let mut updated_voxels = 0;
+
+        for block_index in blocks {
+            for dir in OpDir::dirs(config.planar) {
+                let start = Instant::now();
+                let updated = Self::sweep_block(*dir, block_index, esdf_layer, config);
+                updated_voxels += updated;
+
+                let (axis, dir) = dir.axis_dir();
+                callback(
+                    &EsdfEvent::Sweep {
+                        axis,
+                        dir,
+                        info: EsdfEventInfo::new(&[*block_index], updated, start.elapsed()),
+                    },
+                    esdf_layer,
+                );
+            }
+        }
+
+        updated_voxels
+    }
+
+    /// Propagates across the sides of the blocks,
+    /// returns the blocks that changed and the number of updated voxels
+    pub fn propagate<const VPS: usize>(
+        &mut self,
+        esdf_layer: &mut Layer<Esdf, VPS>,
+        blocks: &BTreeSet<BlockIndex<VPS>>,
+        config: &EsdfIntegratorConfig,
+        callback: &mut EsdfCallback<VPS>,
+    ) -> (BTreeSet<BlockIndex<VPS>>, usize) {
+        let mut dirty_blocks = BTreeSet::new();
+        let mut updated_voxels = 0;
+
+        for block_index in blocks {
+            for dir in OpDir::dirs(config.planar) {
+                let start = Instant::now();
+                if let Some((dirty_block_index, updated)) =
+                    Self::propagate_to_neighbour(*dir, block_index, esdf_layer, config)
+                {
+                    dirty_blocks.insert(dirty_block_index);
+                    updated_voxels += updated;
+
+                    let (axis, dir) = dir.axis_dir();
+                    callback(
+                        &EsdfEvent::Propagate {
+                            axis,
+                            dir,
+                            info: EsdfEventInfo::new(
+                                &[dirty_block_index],
+                                updated,
+                                start.elapsed(),
+                            ),
+                        },
+                        esdf_layer,
+                    );
+                }
+            }
+        }
+
+        (dirty_blocks, updated_voxels)
+    }
+
+    /// Returns the number of updated voxels
+    fn sweep_block<const VPS: usize>(
+        dir: OpDir,
+        index: &BlockIndex<VPS>,
+        esdf_layer: &mut Layer<Esdf, VPS>,
+        config: &EsdfIntegratorConfig,
+    ) -> usize {
+        let (step, order) = match dir {
+            OpDir::XPlus => (1i32, [2, 1, 0]),
+            OpDir::XMinus => (-1, [2, 1, 0]),
+            OpDir::YPlus => (1, [2, 0, 1]),
+            OpDir::YMinus => (-1, [2, 0, 1]),
+            OpDir::ZPlus => (1, [1, 0, 2]),
+            OpDir::ZMinus => (-1, [1, 0, 2]),
+        };
+
+        let voxel_size = esdf_layer.voxel_size();
+        let mut lock = esdf_layer.block_by_index(index).unwrap().write();
+        let mut updated_voxels = 0;
+
+        for u in 0..VPS {
+            for v in 0..VPS {
+                let w_range = if step > 0 {
+                    create_range_chain(1, VPS - 1)
+                } else {
+                    create_range_chain(VPS - 2, 0)
+                };
+
+                for w in w_range {
+                    let mut p = point![0, 0, 0];
+                    p[order[0]] = u;
+                    p[order[1]] = v;
+                    p[order[2]] = w;
+
+                    let voxel_index = VoxelIndex(p);
+
+                    p[order[2]] = (w as i32 - step) as usize;
+                    let parent_voxel_index = VoxelIndex(p);
+
+                    let parent_voxel = *lock.voxel_from_index(&parent_voxel_index);
+                    let global_index = GlobalIndex::from_block_and_voxel_index(index, &voxel_index);
+                    let voxel = lock.voxel_from_index_mut(&voxel_index);
+
+                    if update_voxel(voxel, &parent_voxel, &global_index, voxel_size, config) {
+                        updated_voxels += 1;
+                    }
+                }
+            }
+        }
+
+        updated_voxels
+    }
+
+    /// Returns the neighbor and the number of its updated voxels if any changed
+    fn propagate_to_neighbour<const VPS: usize>(
+        dir: OpDir,
+        pivot_index: &BlockIndex<VPS>,
+        esdf_layer: &mut Layer<Esdf, VPS>,
+        config: &EsdfIntegratorConfig,
+    ) -> Option<(BlockIndex<VPS>, usize)> {
+        let voxel_size = esdf_layer.voxel_size();
+
+        let nblock_index = match dir {
+            OpDir::XPlus => BlockIndex::new(pivot_index.x + 1, pivot_index.y, pivot_index.z),
+            OpDir::XMinus => BlockIndex::new(pivot_index.x - 1, pivot_index.y, pivot_index.z),
+            OpDir::YPlus => BlockIndex::new(pivot_index.x, pivot_index.y + 1, pivot_index.z),
+            OpDir::YMinus => BlockIndex::new(pivot_index.x, pivot_index.y - 1, pivot_index.z),
+            OpDir::ZPlus => BlockIndex::new(pivot_index.x, pivot_index.y, pivot_index.z + 1),
+            OpDir::ZMinus => BlockIndex::new(pivot_index.x, pivot_index.y, pivot_index.z - 1),
+        };
+
+        let (n_index, p_index, order) = match dir {
+            OpDir::XPlus => (0usize, VPS - 1, [2, 1, 0]),
+            OpDir::XMinus => (VPS - 1, 0, [2, 1, 0]),
+            OpDir::YPlus => (0, VPS - 1, [2, 0, 1]),
+            OpDir::YMinus => (VPS - 1, 0, [2, 0, 1]),
+            OpDir::ZPlus => (0, VPS - 1, [1, 0, 2]),
+            OpDir::ZMinus => (VPS - 1, 0, [1, 0, 2]),
+        };
+
+        let mut updated_voxels = 0;
+
+        if let Some(neighbour_block) = esdf_layer.block_by_index(&nblock_index) {
+            let pivot_block = esdf_layer.block_by_index(pivot_index).unwrap().read();
+
+            let mut nlock = neighbour_block.write();
+
+            for u in 0..VPS {
+                for v in 0..VPS {
+                    let mut p = point![0, 0, 0];
+                    p[order[0]] = u;
+                    p[order[1]] = v;
+                    p[order[2]] = p_index;
+
+                    let p_voxel_index = VoxelIndex(p);
+
+                    p[order[2]] = n_index;
+                    let n_voxel_index = VoxelIndex(p);
+
+                    // propagate through the sides
+                    let pivot_voxel = pivot_block.voxel_from_index(&p_voxel_index);
+                    let global_index =
+                        GlobalIndex::from_block_and_voxel_index(&nblock_index, &n_voxel_index);
+                    let neighbour_voxel = nlock.voxel_from_index_mut(&n_voxel_index);
+
+                    if update_voxel(
+                        neighbour_voxel,
+                        pivot_voxel,
+                        &global_index,
+                        voxel_size,
+                        config,
+                    ) {
+                        updated_voxels += 1;
+                    }
+                }
+            }
+        }
+
+        (updated_voxels > 0).then_some((nblock_index, updated_voxels))
+    }
+}
+
+/// Updates `voxel` if `parent_voxel` provides a (new or closer) site,
+/// returns true if the voxel changed
+fn update_voxel<const VPS: usize>(
+    voxel: &mut Esdf,
+    parent_voxel: &Esdf,
+    global_index: &GlobalIndex<VPS>,
+    voxel_size: Real,
+    config: &EsdfIntegratorConfig,
+) -> bool {
+    let parent_fixed = parent_voxel.flags.contains(EsdfFlags::Fixed);
+    let voxel_observed = voxel.flags.contains(EsdfFlags::Observed);
+
+    // sites are seeds for both sides, the sides themselves are kept apart
+    let same_side = parent_voxel.flags.contains(EsdfFlags::Observed)
+        || parent_voxel.flags.contains(EsdfFlags::Inside)
+            == voxel.flags.contains(EsdfFlags::Inside);
+
+    if parent_fixed && !voxel_observed && same_side {
+        let mut distance = Esdf::site_distance(global_index, &parent_voxel.site_index, voxel_size);
+
+        // stop propagating
+        if distance > config.max_distance {
+            return false;
+        }
+
+        if voxel.flags.contains(EsdfFlags::Inside) {
+            distance = -distance;
+        }
+
+        if !voxel.flags.contains(EsdfFlags::Fixed) {
+            voxel.distance = distance;
+            voxel
+                .flags
+                .insert(EsdfFlags::Fixed | EsdfFlags::HasSiteIndex);
+            voxel.site_index = parent_voxel.site_index;
+
+            return true;
+        } else if voxel.distance.abs() > distance.abs() {
+            // found a shorter distance
+            voxel.distance = distance;
+            voxel.site_index = parent_voxel.site_index;
+
+            return true;
+        }
+    }
+
+    false
+}
+
+fn create_range_chain(a: usize, b: usize) -> impl Iterator<Item = usize> {
+    #[allow(clippy::reversed_empty_ranges)]
+    let (part1, part2) = if a <= b {
+        (a..=b, 1..=0)
+    } else {
+        (1..=0, b..=a)
+    };
+
+    part1.chain(part2.rev())
+}
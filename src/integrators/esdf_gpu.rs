@@ -1,285 +1,431 @@
 use crate::{
-    core::{
-        index::BlockIndex,
-        layer::Layer,
-        voxel::{Esdf, EsdfFlags, Tsdf},
-    },
-    wgpu_utils::{GpuPropagate, GpuSweep},
+    core::{index::BlockIndex, layer::Layer, prelude::*, voxel::Esdf},
+    wgpu_utils::{EsdfParamFlags, EsdfParams, GpuBlockPool, GpuError, GpuPassReport, GpuPipelines},
 };
 
-use std::{collections::BTreeSet, time::Duration};
+use super::{
+    esdf::{
+        EsdfBackend, EsdfCallback, EsdfEvent, EsdfEventInfo, EsdfIntegratorConfig, EsdfPassStats,
+        EsdfUpdateStats,
+    },
+    esdf_cpu::CpuBackend,
+};
 
-#[derive(Default)]
-pub struct EsdfIntegratorConfig {}
+use std::{
+    collections::BTreeSet,
+    ops::ControlFlow,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-pub struct EsdfIntegrator {
-    config: EsdfIntegratorConfig,
-    sweep_cache: GpuSweep,
-    propgate_cache: GpuPropagate,
+/// Keeps the ESDF blocks resident on the GPU and runs the update until convergence there
+///
+/// The shaders are specialised for `VPS` when the backend is created.
+/// The layer is only written back by [`EsdfBackend::sync`].
+/// Layers too large for the pool are updated in slices, and on the CPU only if not even
+/// a block and its neighbors fit.
+pub struct GpuBackend<const VPS: usize> {
+    device: Arc<wgpu::Device>,
+    queue: Arc<wgpu::Queue>,
+    pipelines: GpuPipelines,
+    pool: GpuBlockPool<VPS>,
+    fallback: CpuBackend,
 }
 
-impl EsdfIntegrator {
-    pub fn new(
-        device: &wgpu::Device,
-        queue: &mut wgpu::Queue,
-        config: EsdfIntegratorConfig,
-    ) -> Self {
-        Self {
-            config,
-            sweep_cache: GpuSweep::new(device, queue),
-            propgate_cache: GpuPropagate::new(device, queue),
-        }
-    }
+impl<const VPS: usize> GpuBackend<VPS> {
+    /// Blocks the pool is created for, it grows as needed
+    const INITIAL_CAPACITY: usize = 1024;
 
-    pub fn update_blocks<
-        const VPS: usize,
-        F: FnMut(&str, &Layer<Tsdf, VPS>, &Layer<Esdf, VPS>, &[BlockIndex<VPS>], Duration),
-    >(
-        &mut self,
-        tsdf_layer: &Layer<Tsdf, VPS>,
-        esdf_layer: &mut Layer<Esdf, VPS>,
-        updated_blocks: &BTreeSet<BlockIndex<VPS>>,
-        device: &wgpu::Device,
-        queue: &mut wgpu::Queue,
-        mut callback: F,
-    ) {
-        let start = std::time::Instant::now();
+    /// A dirty block and its six neighbors, the smallest slice of a layer
+    const MIN_SLICE_BLOCKS: usize = 7;
 
-        let mut dirty_blocks = BTreeSet::new();
-        let mut sites_indices_to_clear = BTreeSet::new();
-        let mut blocks_to_clear = updated_blocks.clone();
+    /// Fails if a block of `VPS` does not fit the limits of the device
+    pub fn new(device: Arc<wgpu::Device>, queue: Arc<wgpu::Queue>) -> Result<Self, GpuError> {
+        Self::with_max_resident_blocks(device, queue, usize::MAX)
+    }
 
-        callback(
-            "tsdf updated",
-            tsdf_layer,
-            esdf_layer,
-            &updated_blocks.iter().copied().collect::<Vec<_>>(),
-            Duration::from_millis(500),
-        );
+    /// Keeps at most `max_blocks` blocks on the GPU, larger layers are updated in slices
+    pub fn with_max_resident_blocks(
+        device: Arc<wgpu::Device>,
+        queue: Arc<wgpu::Queue>,
+        max_blocks: usize,
+    ) -> Result<Self, GpuError> {
+        let pipelines = GpuPipelines::new(&device, VPS)?;
+
+        Ok(Self {
+            pool: GpuBlockPool::new(&device, &pipelines, Self::INITIAL_CAPACITY, max_blocks),
+            pipelines,
+            device,
+            queue,
+            fallback: CpuBackend::default(),
+        })
+    }
 
-        // allocate all blocks from the tsdf layer
-        for block_index in tsdf_layer.allocated_blocks_iter() {
-            esdf_layer.allocate_block_by_index(block_index);
-        }
+    /// Updates a layer too large for the pool in slices, returns the number of passes
+    ///
+    /// Each pass sweeps and propagates the dirty blocks slice by slice. A slice holds as many
+    /// dirty blocks as fit the pool together with their neighbors, which receive the
+    /// propagated distances. Each slice is written back to the layer before the next one is
+    /// loaded, which exchanges the boundary blocks between the slices.
+    fn update_sliced(
+        &mut self,
+        esdf_layer: &Layer<Esdf, VPS>,
+        mut dirty_blocks: BTreeSet<BlockIndex<VPS>>,
+        params: &EsdfParams,
+        mut report: impl FnMut(&GpuPassReport<VPS>) -> ControlFlow<()>,
+    ) -> usize {
+        firestorm::profile_method!("update_sliced");
+
+        let mut passes = 0;
+        while !dirty_blocks.is_empty() {
+            let start = Instant::now();
+            let mut updated_blocks = BTreeSet::new();
+            let mut updated_voxels = 0;
+            let mut gpu_time: Option<Duration> = None;
+
+            let mut remaining = dirty_blocks.iter().peekable();
+            while remaining.peek().is_some() {
+                let mut slice = BTreeSet::new();
+                let mut resident = BTreeSet::new();
+
+                while let Some(index) = remaining.peek() {
+                    let blocks: Vec<_> = index
+                        .neighbors6_include_self()
+                        .map(|neighbor| neighbor.index)
+                        .filter(|index| esdf_layer.contains(index) && !resident.contains(index))
+                        .collect();
+                    if resident.len() + blocks.len() > self.pool.max_capacity() {
+                        break;
+                    }
 
-        // create a list of site *indices* to clear
-        for block_index in updated_blocks {
-            let esdf_block = esdf_layer.allocate_block_by_index(block_index);
-            let esdf_lock = esdf_block.read();
-
-            for voxel in esdf_lock.as_slice() {
-                if voxel.flags.contains(EsdfFlags::HasSiteIndex) {
-                    sites_indices_to_clear.insert(BlockIndex::new(
-                        voxel.site_block_index[0],
-                        voxel.site_block_index[1],
-                        voxel.site_block_index[2],
-                    ));
+                    resident.extend(blocks);
+                    slice.insert(**index);
+                    remaining.next();
                 }
-            }
-        }
 
-        // create a list of all blocks containing sites to clear
-        let mut open_list = updated_blocks.clone(); // blocks to visit
-        let mut closed_list = BTreeSet::new(); // blocks already visited
-        while let Some(index) = open_list.pop_first() {
-            closed_list.insert(index);
-
-            if let Some(esdf_block) = esdf_layer.block_by_index(&index) {
-                {
-                    let esdf_lock = esdf_block.read();
-                    let mut flagged_clear = false;
-
-                    for voxel in esdf_lock.as_slice() {
-                        if voxel.flags.contains(EsdfFlags::HasSiteIndex)
-                            && sites_indices_to_clear.contains(&BlockIndex::<VPS>::new(
-                                voxel.site_block_index[0],
-                                voxel.site_block_index[1],
-                                voxel.site_block_index[2],
-                            ))
-                        {
-                            blocks_to_clear.insert(index);
-                            flagged_clear = true;
-                            break;
-                        }
-                    }
+                self.pool.load(
+                    &self.device,
+                    &self.queue,
+                    &self.pipelines,
+                    esdf_layer,
+                    &resident,
+                );
+                let slice_report =
+                    self.pool
+                        .iterate(&self.device, &self.queue, &self.pipelines, &slice, params);
+                self.pool.sync(&self.device, &self.queue, esdf_layer);
+
+                updated_blocks.extend(slice_report.pending_blocks);
+                updated_voxels += slice_report.updated_voxels;
+                gpu_time = slice_report
+                    .gpu_time
+                    .map(|time| time + gpu_time.unwrap_or_default());
+            }
 
-                    if flagged_clear {
-                        // also explore its neighbors
-                        for neighbour in index.neighbors6() {
-                            if !closed_list.contains(&neighbour.index)
-                                && esdf_layer.contains(&neighbour.index)
-                            {
-                                open_list.insert(neighbour.index);
-                            }
-                        }
-                    } else {
-                        // propagate from these blocks as
-                        // they are still valid
-                        dirty_blocks.insert(index);
-                    }
-                }
+            passes += 1;
+            dirty_blocks = updated_blocks;
+
+            let flow = report(&GpuPassReport {
+                iterations: passes,
+                pending_blocks: dirty_blocks.iter().copied().collect(),
+                updated_voxels,
+                wall_time: start.elapsed(),
+                gpu_time,
+            });
+            if flow.is_break() {
+                break;
             }
         }
 
-        // reset blocks
-        for block_index in &blocks_to_clear {
-            {
-                let esdf_block = esdf_layer.allocate_block_by_index(block_index);
-                let mut esdf_lock = esdf_block.write();
-                esdf_lock.reset_voxels();
+        // the slots of the last slice do not match the layer
+        self.pool.clear();
+
+        passes
+    }
+}
+
+impl<const VPS: usize> EsdfBackend<VPS> for GpuBackend<VPS> {
+    fn update(
+        &mut self,
+        esdf_layer: &mut Layer<Esdf, VPS>,
+        changed_blocks: &BTreeSet<BlockIndex<VPS>>,
+        dirty_blocks: BTreeSet<BlockIndex<VPS>>,
+        config: &EsdfIntegratorConfig,
+        callback: &mut EsdfCallback<VPS>,
+    ) -> EsdfUpdateStats {
+        firestorm::profile_method!("update");
+
+        let sliced = match self.pool.upload(
+            &self.device,
+            &self.queue,
+            &self.pipelines,
+            esdf_layer,
+            changed_blocks,
+        ) {
+            Ok(()) => false,
+            Err(err) if self.pool.max_capacity() < Self::MIN_SLICE_BLOCKS => {
+                // the layer is synced at this point, the resident blocks are outdated after the update
+                self.pool.clear();
+                return EsdfUpdateStats {
+                    cpu_fallback: Some(err.to_string()),
+                    ..self.fallback.update(
+                        esdf_layer,
+                        changed_blocks,
+                        dirty_blocks,
+                        config,
+                        callback,
+                    )
+                };
             }
+            Err(_) => true,
+        };
+
+        let start = Instant::now();
+        let mut passes = 0;
+        let mut updated_voxels = 0;
+        let mut gpu_time = None;
+        let mut stats = EsdfUpdateStats {
+            converged: true,
+            ..Default::default()
+        };
+
+        let params = params(config, esdf_layer.voxel_size());
+        let mut report = |report: &GpuPassReport<VPS>| {
+            passes += 1;
+            updated_voxels += report.updated_voxels;
+            gpu_time = report
+                .gpu_time
+                .map(|time| time + gpu_time.unwrap_or_default());
+
+            stats.passes.push(EsdfPassStats {
+                updated_blocks: report.pending_blocks.len(),
+                updated_voxels: report.updated_voxels,
+                cpu_time: report.wall_time,
+                gpu_time: report.gpu_time,
+            });
+            stats.converged = report.pending_blocks.is_empty();
 
             callback(
-                "clear site",
-                tsdf_layer,
+                &EsdfEvent::PassComplete {
+                    pass: passes,
+                    info: EsdfEventInfo {
+                        gpu_time: report.gpu_time,
+                        ..EsdfEventInfo::new(
+                            &report.pending_blocks,
+                            report.updated_voxels,
+                            report.wall_time,
+                        )
+                    },
+                },
                 esdf_layer,
-                &[*block_index],
-                Duration::from_millis(50),
             );
-        }
 
-        // transfer tsdf to esdf
-        for block_index in &blocks_to_clear {
-            let tsdf_block = tsdf_layer.block_by_index(block_index).unwrap();
-            let esdf_block = esdf_layer.allocate_block_by_index(block_index);
-            let mut esdf_lock = esdf_block.write();
-
-            for (i, tsdf_voxel) in tsdf_block.read().as_slice().iter().enumerate() {
-                let esdf_voxel = esdf_lock.voxel_from_lin_index_mut(i);
-
-                if tsdf_voxel.weight > 0.0 {
-                    esdf_voxel.distance = tsdf_voxel.distance;
-                    esdf_voxel
-                        .flags
-                        .insert(EsdfFlags::Fixed | EsdfFlags::Observed | EsdfFlags::HasSiteIndex);
-                    esdf_voxel.site_block_index = block_index.coords.into();
-                    dirty_blocks.insert(*block_index);
-                } else {
-                    esdf_voxel.distance = 0.0;
-                    esdf_voxel.flags.remove(EsdfFlags::all());
-                }
+            if config
+                .max_passes
+                .is_some_and(|max_passes| passes >= max_passes)
+            {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
             }
+        };
+
+        // the layer is stale until synced, unless updated in slices
+        let iterations = if sliced {
+            self.update_sliced(esdf_layer, dirty_blocks, &params, &mut report)
+        } else {
+            self.pool.run(
+                &self.device,
+                &self.queue,
+                &self.pipelines,
+                &dirty_blocks,
+                &params,
+                &mut report,
+            )
+        };
+
+        callback(
+            &EsdfEvent::Converged {
+                passes,
+                info: EsdfEventInfo {
+                    gpu_time,
+                    ..EsdfEventInfo::new(&[], updated_voxels, start.elapsed())
+                },
+            },
+            esdf_layer,
+        );
+
+        EsdfUpdateStats {
+            sweep_passes: iterations,
+            propagate_passes: iterations,
+            cpu_time: start.elapsed(),
+            gpu_time,
+            ..stats
         }
+    }
 
-        while !dirty_blocks.is_empty() {
-            let start = std::time::Instant::now();
-            // sweep
-            self.sweep_gpu(esdf_layer, device, queue, &dirty_blocks);
-            let indices: Vec<_> = dirty_blocks.iter().copied().collect();
-            callback(
-                "sweep: xy (GPU)",
-                tsdf_layer,
-                esdf_layer,
-                &indices,
-                Duration::from_millis(1000),
-            );
+    fn sync(&mut self, esdf_layer: &mut Layer<Esdf, VPS>) {
+        self.pool.sync(&self.device, &self.queue, esdf_layer);
+    }
+}
 
-            // propagate
-            dirty_blocks = self.propagate_gpu(esdf_layer, device, queue, &dirty_blocks);
-            let indices: Vec<_> = dirty_blocks.iter().copied().collect();
-            callback(
-                "prop.: xy (GPU)",
-                tsdf_layer,
-                esdf_layer,
-                &indices,
-                Duration::from_millis(1000),
-            );
-            println!(
-                "ESDF pass finished in {:?}",
-                std::time::Instant::now().duration_since(start)
+fn params(config: &EsdfIntegratorConfig, voxel_size: Real) -> EsdfParams {
+    let mut flags = EsdfParamFlags::empty();
+    flags.set(EsdfParamFlags::ProcessZ, !config.planar);
+
+    EsdfParams {
+        flags,
+        max_distance: config.max_distance,
+        voxel_size,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        core::{
+            map2d::Map2d,
+            voxel::{EsdfFlags, Tsdf},
+        },
+        integrators::{
+            esdf::EsdfIntegrator,
+            tsdf::{TsdfIntegrator, TsdfIntegratorConfig},
+        },
+        wgpu_utils::request_device,
+    };
+
+    /// A 64x64 map of 8x8 blocks with walls spanning several blocks,
+    /// the horizontal wall is removed once the vertical ones move
+    fn map(shift: u32) -> Map2d {
+        let image = image::RgbImage::from_fn(64, 64, |x, y| {
+            let wall =
+                (x % 24 == shift && y > 6) || (shift == 3 && y == 40 && (20..60).contains(&x));
+            image::Rgb(if wall { [0; 3] } else { [255; 3] })
+        });
+
+        Map2d::from_rgb(&image, 1.0)
+    }
+
+    /// Updates the ESDF after each of the maps
+    fn update(
+        backend: Box<dyn EsdfBackend<8>>,
+        maps: &[Map2d],
+    ) -> (Layer<Esdf, 8>, Vec<EsdfUpdateStats>) {
+        let mut tsdf_layer = Layer::<Tsdf, 8>::new(1.0);
+        let mut esdf_layer = Layer::new(1.0);
+        let mut tsdf_integrator = TsdfIntegrator::new(TsdfIntegratorConfig {
+            max_weight: 1.0,
+            ..Default::default()
+        });
+        let mut esdf_integrator = EsdfIntegrator::new(
+            EsdfIntegratorConfig {
+                planar: true,
+                ..Default::default()
+            },
+            backend,
+        );
+
+        let mut all_stats = vec![];
+        for map in maps {
+            let mut updated_blocks = BTreeSet::new();
+            tsdf_integrator.integrate_map(&mut tsdf_layer, map, &mut updated_blocks);
+
+            let stats = esdf_integrator.update_blocks(
+                &tsdf_layer,
+                &mut esdf_layer,
+                &updated_blocks,
+                |_, _, _| {},
             );
+            assert!(stats.converged);
+            assert_eq!(stats.cpu_fallback, None);
+            all_stats.push(stats);
         }
+        esdf_integrator.sync(&mut esdf_layer);
 
-        println!(
-            "=> ESDF update finished in {:?}",
-            std::time::Instant::now().duration_since(start)
-        );
+        (esdf_layer, all_stats)
     }
 
-    fn sweep_gpu<const VPS: usize>(
-        &mut self,
-        esdf_layer: &mut Layer<Esdf, VPS>,
-        device: &wgpu::Device,
-        queue: &mut wgpu::Queue,
-        dirty_blocks: &BTreeSet<BlockIndex<VPS>>,
-    ) {
-        firestorm::profile_method!("sweep_gpu");
-
-        let blocks: Vec<_> = dirty_blocks
-            .iter()
-            .map(|index| esdf_layer.block_by_index(index).unwrap())
-            .collect();
-
-        self.sweep_cache.submit(device, queue, &blocks);
-        // wgpu_utils::sweep_blocks(device, queue, blocks.as_slice()).await;
+    fn device() -> Option<(Arc<wgpu::Device>, Arc<wgpu::Queue>)> {
+        let gpu = futures::executor::block_on(request_device(&Default::default())).ok()?;
+        Some((Arc::new(gpu.device), Arc::new(gpu.queue)))
     }
 
-    fn propagate_gpu<const VPS: usize>(
-        &mut self,
-        esdf_layer: &mut Layer<Esdf, VPS>,
-        device: &wgpu::Device,
-        queue: &mut wgpu::Queue,
-        dirty_blocks: &BTreeSet<BlockIndex<VPS>>,
-    ) -> BTreeSet<BlockIndex<VPS>> {
-        firestorm::profile_method!("propagate_gpu");
-
-        // padded list of blocks (blocks themselves + direct neighbors)
-        let block_indices_of_interest = BTreeSet::from_iter(
-            dirty_blocks
-                .iter()
-                .flat_map(|index| index.neighbors6_include_self().map(|p| p.index))
-                .filter(|p| esdf_layer.contains(p)),
-        );
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn iterations() {
+        let (device, queue) = device().expect("no GPU adapter");
+        let backend = GpuBackend::<8>::new(device, queue).unwrap();
 
-        // BlockIndex -> u32
-        let block_index_map = std::collections::BTreeMap::from_iter(
-            block_indices_of_interest
-                .iter()
-                .enumerate()
-                .map(|p| (p.1, p.0)),
-        );
+        let (_, all_stats) = update(Box::new(backend), &[map(3)]);
+        let stats = &all_stats[0];
 
-        // u32 -> BlockIndex
-        let block_index_map_inv = std::collections::BTreeMap::from_iter(
-            block_indices_of_interest
-                .iter()
-                .enumerate()
-                .map(|p| (p.0, p.1)),
-        );
+        // the last submission converged after some of its iterations
+        let submissions = stats.passes.len();
+        assert!(stats.sweep_passes > (submissions - 1) * 8);
+        assert!(stats.sweep_passes <= submissions * 8);
+        assert_eq!(stats.sweep_passes, stats.propagate_passes);
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn sliced_update() {
+        let (device, queue) = device().expect("no GPU adapter");
+        let backend = GpuBackend::<8>::with_max_resident_blocks(device, queue, 16).unwrap();
+
+        let maps = [map(3), map(5)];
+        let (gpu_layer, gpu_stats) = update(Box::new(backend), &maps);
+        let (cpu_layer, _) = update(Box::new(CpuBackend::default()), &maps);
+
+        // one iteration per pass over the slices
+        for stats in gpu_stats {
+            assert_eq!(stats.sweep_passes, stats.passes.len());
+        }
+
+        assert_eq!(gpu_layer.allocated_blocks_iter().count(), 64);
+        for index in cpu_layer.allocated_blocks_iter() {
+            let cpu_block = cpu_layer.block_by_index(index).unwrap().read();
+            let gpu_block = gpu_layer.block_by_index(index).unwrap().read();
+
+            // equidistant sites may be picked in either order
+            for (cpu, gpu) in cpu_block.as_slice().iter().zip(gpu_block.as_slice()) {
+                assert_eq!(cpu.distance, gpu.distance, "block {index:?}");
+                assert_eq!(cpu.flags, gpu.flags, "block {index:?}");
+            }
+        }
+    }
 
-        // a subset of all blocks to upload to the GPU
-        let blocks: Vec<_> = block_indices_of_interest
-            .iter()
-            .filter_map(|p| esdf_layer.block_by_index(p))
-            .collect();
-
-        // work indices for the shader
-        let workgroup_block_indices: Vec<u32> = dirty_blocks
-            .iter()
-            .flat_map(|p| {
-                p.neighbors6_include_self().map(|p| {
-                    block_index_map
-                        .get(&p.index)
-                        .copied()
-                        .map(|p| p as u32)
-                        .unwrap_or(u32::MAX) // MAX indicates an invalid index
-                })
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn updated_voxels() {
+        let (device, queue) = device().expect("no GPU adapter");
+        let backend = GpuBackend::<8>::new(device, queue).unwrap();
+
+        let (gpu_layer, gpu_stats) = update(Box::new(backend), &[map(3)]);
+        let (_, cpu_stats) = update(Box::new(CpuBackend::default()), &[map(3)]);
+
+        // every voxel that got a distance from a site was updated at least once
+        let propagated: usize = gpu_layer
+            .allocated_blocks_iter()
+            .map(|index| {
+                let block = gpu_layer.block_by_index(index).unwrap().read();
+                block
+                    .as_slice()
+                    .iter()
+                    .filter(|voxel| {
+                        voxel.flags.contains(EsdfFlags::Fixed)
+                            && !voxel.flags.contains(EsdfFlags::Observed)
+                    })
+                    .count()
             })
-            .collect();
-
-        let block_info =
-            self.propgate_cache
-                .submit(device, queue, &workgroup_block_indices, &blocks);
-        // wgpu_utils::propagate_blocks(device, queue, &workgroup_block_indices, &blocks).await;
-
-        // blocks updated by the shader are considered dirty and have to be swept again
-        BTreeSet::from_iter(
-            block_info
-                .iter()
-                .enumerate()
-                .filter(|p| p.1.flags.contains(EsdfFlags::Updated))
-                .map(|p| *block_index_map_inv.get(&p.0).unwrap())
-                .copied(),
-        )
+            .sum();
+        let gpu_voxels = gpu_stats[0].updated_voxels();
+        let cpu_voxels = cpu_stats[0].updated_voxels();
+        assert!(gpu_voxels >= propagated);
+        assert!(cpu_voxels >= propagated);
+
+        // the CPU propagates block by block and may revisit a few voxels
+        assert!(gpu_voxels.abs_diff(cpu_voxels) * 100 < cpu_voxels);
     }
 }
@@ -1,3 +1,6 @@
 pub mod esdf;
+pub mod esdf_cpu;
 pub mod esdf_gpu;
+pub mod occupancy;
+pub mod projective_tsdf;
 pub mod tsdf;
@@ -0,0 +1,247 @@
+use std::collections::BTreeSet;
+
+use crate::core::{
+    index::BlockIndex,
+    layer::Layer,
+    map2d::{Map2d, PixelClass},
+    prelude::*,
+    raycast::VoxelRayIter,
+    voxel::{log_odds_from_probability, Occupancy},
+};
+
+#[derive(Debug, Clone)]
+pub struct OccupancyIntegratorConfig {
+    /// Probability of the voxel containing the hit to be occupied
+    pub hit_probability: Real,
+    /// Probability of the voxels along the ray to be occupied
+    pub miss_probability: Real,
+    /// Lower clamp of the occupancy probability, keeps voxels responsive to changes
+    pub min_probability: Real,
+    /// Upper clamp of the occupancy probability
+    pub max_probability: Real,
+    /// Rays are cut at this length, only free space is carved along cut rays
+    pub max_ray_length: Real,
+}
+
+impl Default for OccupancyIntegratorConfig {
+    fn default() -> Self {
+        Self {
+            hit_probability: 0.7,
+            miss_probability: 0.4,
+            min_probability: 0.12,
+            max_probability: 0.97,
+            max_ray_length: 20.0,
+        }
+    }
+}
+
+/// Integrates point clouds into an occupancy layer with log-odds updates
+pub struct OccupancyIntegrator {
+    config: OccupancyIntegratorConfig,
+    hit_log_odds: Real,
+    miss_log_odds: Real,
+    min_log_odds: Real,
+    max_log_odds: Real,
+}
+
+impl OccupancyIntegrator {
+    pub fn new(config: OccupancyIntegratorConfig) -> Self {
+        Self {
+            hit_log_odds: log_odds_from_probability(config.hit_probability),
+            miss_log_odds: log_odds_from_probability(config.miss_probability),
+            min_log_odds: log_odds_from_probability(config.min_probability),
+            max_log_odds: log_odds_from_probability(config.max_probability),
+            config,
+        }
+    }
+
+    /// Casts a ray from `origin` to each point of the cloud
+    ///
+    /// The voxel containing the point is updated as hit, the voxels in between as misses.
+    pub fn integrate_point_cloud<const VPS: usize>(
+        &mut self,
+        layer: &mut Layer<Occupancy, VPS>,
+        origin: &Point3<Real>,
+        points: &[Point3<Real>],
+        updated_block_indices: &mut BTreeSet<BlockIndex<VPS>>,
+    ) {
+        firestorm::profile_method!("integrate_point_cloud");
+
+        // rays are traversed in grid coordinates
+        let world_to_layer = layer.origin().inverse();
+
+        for point in points {
+            let ray = point - origin;
+            let length = ray.norm();
+
+            // too far away to be trusted as a hit
+            let clear_only = length > self.config.max_ray_length;
+            let end = if clear_only {
+                origin + ray * (self.config.max_ray_length / length)
+            } else {
+                *point
+            };
+
+            let mut voxels = VoxelRayIter::new(
+                &(world_to_layer * origin),
+                &(world_to_layer * end),
+                layer.voxel_size_inv(),
+            )
+            .peekable();
+            while let Some(global_index) = voxels.next() {
+                let is_hit = !clear_only && voxels.peek().is_none();
+                let log_odds = if is_hit {
+                    self.hit_log_odds
+                } else {
+                    self.miss_log_odds
+                };
+
+                let (block_index, voxel_index) = global_index.block_voxel_index();
+                let mut lock = layer.allocate_block_by_index(&block_index).write();
+                if self.update_voxel(lock.voxel_from_index_mut(&voxel_index), log_odds) {
+                    updated_block_indices.insert(block_index);
+                }
+            }
+        }
+    }
+
+    /// Integrates a 2D map into the z=0 plane
+    ///
+    /// Occupied cells are updated as hits, free cells as misses and unknown cells are skipped.
+    pub fn integrate_map<const VPS: usize>(
+        &mut self,
+        layer: &mut Layer<Occupancy, VPS>,
+        map: &Map2d,
+        updated_block_indices: &mut BTreeSet<BlockIndex<VPS>>,
+    ) {
+        firestorm::profile_method!("integrate_map");
+
+        for y in 0..map.height() {
+            for x in 0..map.width() {
+                let log_odds = match map.class_at(x, y) {
+                    PixelClass::Occupied => self.hit_log_odds,
+                    PixelClass::Free => self.miss_log_odds,
+                    PixelClass::Unknown => continue,
+                };
+
+                let (block_index, voxel_index) = layer
+                    .global_index_from_point(&map.center(x, y))
+                    .block_voxel_index();
+                let mut lock = layer.allocate_block_by_index(&block_index).write();
+                if self.update_voxel(lock.voxel_from_index_mut(&voxel_index), log_odds) {
+                    updated_block_indices.insert(block_index);
+                }
+            }
+        }
+    }
+
+    /// Returns true if the voxel changed
+    fn update_voxel(&self, voxel: &mut Occupancy, log_odds: Real) -> bool {
+        let previous = *voxel;
+
+        voxel.log_odds = (voxel.log_odds + log_odds).clamp(self.min_log_odds, self.max_log_odds);
+        voxel.observed = true;
+
+        *voxel != previous
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use nalgebra::point;
+
+    use super::*;
+    use crate::{
+        core::{
+            index::GlobalIndex,
+            voxel::{Esdf, Voxel},
+        },
+        integrators::{
+            esdf::{EsdfIntegrator, EsdfIntegratorConfig},
+            esdf_cpu::CpuBackend,
+        },
+    };
+
+    fn voxel_at<V: Voxel>(layer: &Layer<V, 8>, x: i64, y: i64, z: i64) -> V {
+        let (block_index, voxel_index) = GlobalIndex::<8>(Point3::new(x, y, z)).block_voxel_index();
+        *layer
+            .block_by_index(&block_index)
+            .unwrap()
+            .read()
+            .voxel_from_index(&voxel_index)
+    }
+
+    #[test]
+    fn hits_and_misses() {
+        let mut layer = Layer::<Occupancy, 8>::new(1.0);
+        let mut updated_blocks = BTreeSet::new();
+        let mut integrator = OccupancyIntegrator::new(OccupancyIntegratorConfig::default());
+
+        for _ in 0..20 {
+            integrator.integrate_point_cloud(
+                &mut layer,
+                &point![0.5, 0.5, 0.5],
+                &[point![10.5, 0.5, 0.5]],
+                &mut updated_blocks,
+            );
+        }
+        assert_eq!(
+            updated_blocks,
+            BTreeSet::from([BlockIndex::new(0, 0, 0), BlockIndex::new(1, 0, 0)])
+        );
+
+        // clamped
+        let hit = voxel_at(&layer, 10, 0, 0);
+        assert!(hit.observed);
+        assert!((hit.probability() - 0.97).abs() < 1e-4);
+
+        let miss = voxel_at(&layer, 9, 0, 0);
+        assert!((miss.probability() - 0.12).abs() < 1e-4);
+
+        assert!(!voxel_at(&layer, 11, 0, 0).observed);
+
+        // saturated voxels do not dirty blocks
+        updated_blocks.clear();
+        integrator.integrate_point_cloud(
+            &mut layer,
+            &point![0.5, 0.5, 0.5],
+            &[point![10.5, 0.5, 0.5]],
+            &mut updated_blocks,
+        );
+        assert!(updated_blocks.is_empty());
+    }
+
+    #[test]
+    fn esdf_from_occupancy() {
+        let mut layer = Layer::<Occupancy, 8>::new(1.0);
+        let mut updated_blocks = BTreeSet::new();
+        let mut integrator = OccupancyIntegrator::new(OccupancyIntegratorConfig::default());
+
+        // a wall at x = 10
+        let points: Vec<_> = (0..8).map(|y| point![10.5, y as Real + 0.5, 0.5]).collect();
+        for _ in 0..3 {
+            integrator.integrate_point_cloud(
+                &mut layer,
+                &point![0.5, 3.5, 0.5],
+                &points,
+                &mut updated_blocks,
+            );
+        }
+
+        let mut esdf_layer = Layer::<Esdf, 8>::new(1.0);
+        EsdfIntegrator::new(
+            EsdfIntegratorConfig {
+                planar: true,
+                ..Default::default()
+            },
+            Box::new(CpuBackend::default()),
+        )
+        .update_blocks(&layer, &mut esdf_layer, &updated_blocks, |_, _, _| {});
+
+        assert_eq!(voxel_at(&esdf_layer, 10, 3, 0).distance, 0.0);
+        assert_eq!(voxel_at(&esdf_layer, 7, 3, 0).distance, 3.0);
+        assert!(voxel_at(&esdf_layer, 7, 3, 0).is_known());
+        // never observed
+        assert!(!voxel_at(&esdf_layer, 12, 3, 0).is_known());
+    }
+}
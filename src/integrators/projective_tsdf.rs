@@ -0,0 +1,255 @@
+use std::collections::BTreeSet;
+
+use nalgebra::Isometry3;
+
+use crate::core::{
+    camera::{CameraIntrinsics, DepthImage},
+    index::{BlockIndex, GlobalIndex},
+    layer::Layer,
+    prelude::*,
+    voxel::Tsdf,
+};
+
+use super::tsdf::TsdfWeighting;
+
+#[derive(Debug, Clone)]
+pub struct ProjectiveTsdfIntegratorConfig {
+    /// Signed distances are truncated to +/- this value (in voxels)
+    pub truncation_distance_vox: Real,
+    /// Measurements closer than this are ignored
+    pub min_depth: Real,
+    /// Measurements farther than this are ignored
+    pub max_depth: Real,
+    pub weighting: TsdfWeighting,
+    /// Cap of the accumulated weight, lower values adapt faster to changes
+    pub max_weight: Real,
+}
+
+impl Default for ProjectiveTsdfIntegratorConfig {
+    fn default() -> Self {
+        Self {
+            truncation_distance_vox: 4.0,
+            min_depth: 0.1,
+            max_depth: 5.0,
+            weighting: TsdfWeighting::Constant,
+            max_weight: 100.0,
+        }
+    }
+}
+
+/// Integrates depth images into a TSDF layer
+///
+/// Voxels are projected into the image and updated with the projective
+/// signed distance, i.e., the difference along the optical axis between the
+/// measured depth and the depth of the voxel.
+pub struct ProjectiveTsdfIntegrator {
+    config: ProjectiveTsdfIntegratorConfig,
+}
+
+impl ProjectiveTsdfIntegrator {
+    pub fn new(config: ProjectiveTsdfIntegratorConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &ProjectiveTsdfIntegratorConfig {
+        &self.config
+    }
+
+    /// `pose` transforms points from the camera frame to the world frame
+    pub fn integrate_depth<const VPS: usize>(
+        &mut self,
+        layer: &mut Layer<Tsdf, VPS>,
+        depth_image: &DepthImage,
+        intrinsics: &CameraIntrinsics,
+        pose: &Isometry3<Real>,
+        updated_block_indices: &mut BTreeSet<BlockIndex<VPS>>,
+    ) {
+        firestorm::profile_method!("integrate_depth");
+
+        self.allocate_blocks(layer, depth_image, intrinsics, pose);
+
+        let truncation_distance = self.config.truncation_distance_vox * layer.voxel_size();
+        let world_to_camera = pose.inverse();
+        let block_radius = 0.5 * layer.block_size() * (3.0 as Real).sqrt();
+
+        for block_index in layer.allocated_blocks_iter() {
+            // cull blocks outside of the view frustum
+            let center = world_to_camera * layer.center_point_from_index(block_index);
+            if center.z + block_radius < self.config.min_depth
+                || center.z - block_radius > self.config.max_depth + truncation_distance
+            {
+                continue;
+            }
+
+            let margin = (block_radius / center.z.max(self.config.min_depth))
+                * intrinsics.fx.max(intrinsics.fy);
+            if let Some((u, v)) = intrinsics.project(&center) {
+                if u < -margin
+                    || v < -margin
+                    || u > depth_image.width() as Real + margin
+                    || v > depth_image.height() as Real + margin
+                {
+                    continue;
+                }
+            }
+
+            if self.integrate_block(
+                block_index,
+                layer,
+                depth_image,
+                intrinsics,
+                &world_to_camera,
+            ) {
+                updated_block_indices.insert(*block_index);
+            }
+        }
+    }
+
+    /// Allocates the blocks within the truncation band around the measured surface
+    fn allocate_blocks<const VPS: usize>(
+        &self,
+        layer: &mut Layer<Tsdf, VPS>,
+        depth_image: &DepthImage,
+        intrinsics: &CameraIntrinsics,
+        pose: &Isometry3<Real>,
+    ) {
+        let mut block_indices = BTreeSet::new();
+        let truncation_distance = self.config.truncation_distance_vox * layer.voxel_size();
+
+        for y in 0..depth_image.height() {
+            for x in 0..depth_image.width() {
+                let (u, v) = (x as Real + 0.5, y as Real + 0.5);
+                let Some(depth) = self.valid_depth(depth_image, u, v) else {
+                    continue;
+                };
+
+                let near = (depth - truncation_distance).max(self.config.min_depth);
+                let far = depth + truncation_distance;
+                let steps = ((far - near) / layer.block_size()).ceil() as usize + 1;
+
+                for i in 0..=steps {
+                    let d = near + (far - near) * i as Real / steps as Real;
+                    let p = pose * intrinsics.unproject(u, v, d);
+                    block_indices.insert(layer.global_index_from_point(&p).block_index());
+                }
+            }
+        }
+
+        for block_index in &block_indices {
+            layer.allocate_block_by_index(block_index);
+        }
+    }
+
+    /// Returns true if any voxel of the block was updated
+    fn integrate_block<const VPS: usize>(
+        &self,
+        block_index: &BlockIndex<VPS>,
+        layer: &Layer<Tsdf, VPS>,
+        depth_image: &DepthImage,
+        intrinsics: &CameraIntrinsics,
+        world_to_camera: &Isometry3<Real>,
+    ) -> bool {
+        let truncation_distance = self.config.truncation_distance_vox * layer.voxel_size();
+        let mut updated = false;
+        let mut lock = layer.block_by_index(block_index).unwrap().write();
+
+        for (i, voxel) in lock.as_mut_slice().iter_mut().enumerate() {
+            let center =
+                layer.voxel_center(&GlobalIndex::from_block_and_local_lin_index(block_index, i));
+            let p = world_to_camera * center;
+
+            let Some((u, v)) = intrinsics.project(&p) else {
+                continue;
+            };
+            let Some(depth) = self.valid_depth(depth_image, u, v) else {
+                continue;
+            };
+
+            let sdf = depth - p.z;
+            if sdf < -truncation_distance {
+                // occluded
+                continue;
+            }
+
+            updated |= voxel.fuse(
+                sdf.min(truncation_distance),
+                self.config.weighting.weight(sdf, truncation_distance),
+                self.config.max_weight,
+            );
+        }
+
+        updated
+    }
+
+    fn valid_depth(&self, depth_image: &DepthImage, u: Real, v: Real) -> Option<Real> {
+        depth_image
+            .depth_at(u, v)
+            .filter(|depth| (self.config.min_depth..=self.config.max_depth).contains(depth))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// camera at the origin looking at a wall 2m away
+    fn integrate_wall() -> (Layer<Tsdf, 8>, BTreeSet<BlockIndex<8>>) {
+        let mut layer = Layer::new(0.1);
+        let mut updated_blocks = BTreeSet::new();
+
+        let mut depth = vec![2.0; 64 * 48];
+        // invalid measurement in the top left corner
+        depth[0] = 0.0;
+
+        ProjectiveTsdfIntegrator::new(ProjectiveTsdfIntegratorConfig {
+            truncation_distance_vox: 2.0,
+            ..Default::default()
+        })
+        .integrate_depth(
+            &mut layer,
+            &DepthImage::from_f32(64, 48, depth),
+            &CameraIntrinsics::new(50.0, 50.0, 32.0, 24.0),
+            &Isometry3::identity(),
+            &mut updated_blocks,
+        );
+
+        (layer, updated_blocks)
+    }
+
+    fn voxel_at(layer: &Layer<Tsdf, 8>, x: i64, y: i64, z: i64) -> Tsdf {
+        let (block_index, voxel_index) = GlobalIndex::<8>(Point3::new(x, y, z)).block_voxel_index();
+        *layer
+            .block_by_index(&block_index)
+            .unwrap()
+            .read()
+            .voxel_from_index(&voxel_index)
+    }
+
+    #[test]
+    fn wall() {
+        let (layer, updated_blocks) = integrate_wall();
+
+        assert!(updated_blocks.contains(&BlockIndex::new(0, 0, 2)));
+        assert!(updated_blocks.contains(&BlockIndex::new(-1, -1, 2)));
+        for block_index in &updated_blocks {
+            assert!(layer.contains(block_index));
+        }
+
+        // in front of the wall
+        let voxel = voxel_at(&layer, 0, 0, 19);
+        assert!((voxel.distance - 0.05).abs() < 1e-4);
+        assert_eq!(voxel.weight, 1.0);
+
+        // behind the wall
+        let voxel = voxel_at(&layer, 0, 0, 20);
+        assert!((voxel.distance + 0.05).abs() < 1e-4);
+
+        // truncated
+        let voxel = voxel_at(&layer, 0, 0, 16);
+        assert_eq!(voxel.distance, 0.2);
+
+        // occluded
+        let voxel = voxel_at(&layer, 0, 0, 23);
+        assert_eq!(voxel.weight, 0.0);
+    }
+}
@@ -1,21 +1,58 @@
 use std::collections::BTreeSet;
 
-use nalgebra::point;
+use serde::Deserialize;
 
-use crate::core::index::{BlockIndex, GlobalIndex};
+use crate::core::index::BlockIndex;
 use crate::core::layer::Layer;
+use crate::core::map2d::{Map2d, PixelClass};
 use crate::core::prelude::*;
+use crate::core::raycast::VoxelRayIter;
 use crate::core::voxel::Tsdf;
 
-#[derive(Debug)]
+/// Weight of a single observation
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TsdfWeighting {
+    /// Every observation has weight 1
+    Constant,
+    /// Weight 1 in front of the surface, dropping linearly to 0 at the truncation distance behind it
+    LinearDropoff,
+}
+
+impl TsdfWeighting {
+    pub fn weight(&self, sdf: Real, truncation_distance: Real) -> Real {
+        match self {
+            TsdfWeighting::Constant => 1.0,
+            TsdfWeighting::LinearDropoff => {
+                if sdf >= 0.0 {
+                    1.0
+                } else {
+                    (1.0 + sdf / truncation_distance).max(0.0)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct TsdfIntegratorConfig {
-    default_truncation_distance: Real,
+    /// Signed distances are truncated to +/- this value (in voxels)
+    pub truncation_distance_vox: Real,
+    /// Rays are cut at this length, only free space is carved along cut rays
+    pub max_ray_length: Real,
+    pub weighting: TsdfWeighting,
+    /// Cap of the accumulated weight, lower values adapt faster to changes
+    pub max_weight: Real,
 }
 
 impl Default for TsdfIntegratorConfig {
     fn default() -> Self {
         Self {
-            default_truncation_distance: 0.2,
+            truncation_distance_vox: 4.0,
+            max_ray_length: 20.0,
+            weighting: TsdfWeighting::Constant,
+            max_weight: 100.0,
         }
     }
 }
@@ -29,36 +66,311 @@ impl TsdfIntegrator {
         Self { config }
     }
 
+    /// Integrates an image into the z=0 plane, one voxel per pixel
+    ///
+    /// Dark pixels are obstacles, bright pixels free space and everything in
+    /// between is unknown and not integrated (see [`PixelClass`]).
     pub fn integrate_image<const VPS: usize>(
         &mut self,
         layer: &mut Layer<Tsdf, VPS>,
         image: &image::RgbImage,
         updated_block_indices: &mut BTreeSet<BlockIndex<VPS>>,
     ) {
-        for y in 0..image.width() {
-            for x in 0..image.height() {
-                let global_index = GlobalIndex::from_point(
-                    &point![x as Real, y as Real, 0.0],
-                    layer.voxel_size_inv(),
-                );
+        let map = Map2d::from_rgb(image, layer.voxel_size());
+        self.integrate_map(layer, &map, updated_block_indices);
+    }
+
+    /// Integrates a 2D map into the z=0 plane
+    ///
+    /// Obstacle cells bordering free space are the surface, the other cells get
+    /// their truncated distance to it, negative inside of obstacles.
+    /// Unknown cells are not integrated.
+    pub fn integrate_map<const VPS: usize>(
+        &mut self,
+        layer: &mut Layer<Tsdf, VPS>,
+        map: &Map2d,
+        updated_block_indices: &mut BTreeSet<BlockIndex<VPS>>,
+    ) {
+        firestorm::profile_method!("integrate_map");
+
+        let truncation_distance = self.config.truncation_distance_vox * layer.voxel_size();
+        let (width, height) = (map.width() as i64, map.height() as i64);
+        let class_at = |x: i64, y: i64| map.class_at(x as u32, y as u32);
+
+        // in cells
+        let radius = (truncation_distance / map.resolution()).ceil() as i64 + 1;
+
+        for y in 0..height {
+            for x in 0..width {
+                let class = class_at(x, y);
+                if class == PixelClass::Unknown {
+                    continue;
+                }
+
+                // distance to the closest cell of the other class
+                let mut min_distance = Real::MAX;
+                for ny in (y - radius).max(0)..(y + radius + 1).min(height) {
+                    for nx in (x - radius).max(0)..(x + radius + 1).min(width) {
+                        let neighbor_class = class_at(nx, ny);
+                        if neighbor_class != class && neighbor_class != PixelClass::Unknown {
+                            let d = (((nx - x).pow(2) + (ny - y).pow(2)) as Real).sqrt();
+                            min_distance = min_distance.min(d * map.resolution());
+                        }
+                    }
+                }
+
+                let sdf = match class {
+                    PixelClass::Free => min_distance.min(truncation_distance),
+                    // the obstacle cells next to free space are the surface
+                    _ => -(min_distance - map.resolution()).min(truncation_distance),
+                };
+
+                let global_index = layer.global_index_from_point(&map.center(x as u32, y as u32));
                 let (block_index, voxel_index) = global_index.block_voxel_index();
 
                 let mut lock = layer.allocate_block_by_index(&block_index).write();
-                let voxel = lock.voxel_from_index_mut(&voxel_index);
+                if lock.voxel_from_index_mut(&voxel_index).fuse(
+                    sdf,
+                    self.config.weighting.weight(sdf, truncation_distance),
+                    self.config.max_weight,
+                ) {
+                    updated_block_indices.insert(block_index);
+                }
+            }
+        }
+    }
 
-                if image.get_pixel(x, y).0 == [0, 0, 0] {
-                    if voxel.weight == 0.0 {
-                        voxel.distance = self.config.default_truncation_distance;
-                        voxel.weight = 1.0;
-                        updated_block_indices.insert(block_index);
-                    }
-                } else if voxel.weight == 1.0 {
-                    voxel.distance = 0.0;
-                    voxel.weight = 0.0;
+    /// Casts a ray from `origin` to each point of the cloud
+    ///
+    /// Voxels within the truncation distance around the hit are updated with the
+    /// signed distance along the ray, voxels in between are marked as free.
+    pub fn integrate_point_cloud<const VPS: usize>(
+        &mut self,
+        layer: &mut Layer<Tsdf, VPS>,
+        origin: &Point3<Real>,
+        points: &[Point3<Real>],
+        updated_block_indices: &mut BTreeSet<BlockIndex<VPS>>,
+    ) {
+        firestorm::profile_method!("integrate_point_cloud");
 
+        let truncation_distance = self.config.truncation_distance_vox * layer.voxel_size();
+        // rays are traversed in grid coordinates
+        let world_to_layer = layer.origin().inverse();
+
+        for point in points {
+            let ray = point - origin;
+            let length = ray.norm();
+            if length < Real::EPSILON {
+                continue;
+            }
+            let dir = ray / length;
+
+            // too far away to be trusted as a surface
+            let clear_only = length > self.config.max_ray_length;
+            let end = if clear_only {
+                origin + dir * self.config.max_ray_length
+            } else {
+                point + dir * truncation_distance
+            };
+
+            for global_index in VoxelRayIter::new(
+                &(world_to_layer * origin),
+                &(world_to_layer * end),
+                layer.voxel_size_inv(),
+            ) {
+                let sdf = if clear_only {
+                    truncation_distance
+                } else {
+                    (point - layer.voxel_center(&global_index)).dot(&dir)
+                };
+
+                if sdf < -truncation_distance {
+                    continue;
+                }
+
+                let (block_index, voxel_index) = global_index.block_voxel_index();
+                let mut lock = layer.allocate_block_by_index(&block_index).write();
+                if lock.voxel_from_index_mut(&voxel_index).fuse(
+                    sdf.min(truncation_distance),
+                    self.config.weighting.weight(sdf, truncation_distance),
+                    self.config.max_weight,
+                ) {
                     updated_block_indices.insert(block_index);
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use nalgebra::point;
+
+    use super::*;
+    use crate::core::index::GlobalIndex;
+
+    fn voxel_at(layer: &Layer<Tsdf, 8>, x: i64, y: i64, z: i64) -> Tsdf {
+        let (block_index, voxel_index) = GlobalIndex::<8>(Point3::new(x, y, z)).block_voxel_index();
+        *layer
+            .block_by_index(&block_index)
+            .unwrap()
+            .read()
+            .voxel_from_index(&voxel_index)
+    }
+
+    #[test]
+    fn point_cloud() {
+        let mut layer = Layer::<Tsdf, 8>::new(0.1);
+        let mut updated_blocks = BTreeSet::new();
+
+        let mut integrator = TsdfIntegrator::new(TsdfIntegratorConfig {
+            truncation_distance_vox: 2.0,
+            max_ray_length: 1.5,
+            ..Default::default()
+        });
+        integrator.integrate_point_cloud(
+            &mut layer,
+            &point![0.05, 0.05, 0.05],
+            &[point![1.05, 0.05, 0.05], point![-2.05, 0.05, 0.05]],
+            &mut updated_blocks,
+        );
+
+        // free space
+        let voxel = voxel_at(&layer, 3, 0, 0);
+        assert_eq!((voxel.distance, voxel.weight), (0.2, 1.0));
+
+        // hit
+        let voxel = voxel_at(&layer, 10, 0, 0);
+        assert!(voxel.distance.abs() < 1e-4);
+        let voxel = voxel_at(&layer, 9, 0, 0);
+        assert!((voxel.distance - 0.1).abs() < 1e-4);
+        let voxel = voxel_at(&layer, 11, 0, 0);
+        assert!((voxel.distance + 0.1).abs() < 1e-4);
+
+        // behind the hit
+        assert_eq!(voxel_at(&layer, 13, 0, 0).weight, 0.0);
+
+        // beyond the max. ray length: only cleared
+        let voxel = voxel_at(&layer, -15, 0, 0);
+        assert_eq!((voxel.distance, voxel.weight), (0.2, 1.0));
+        assert_eq!(voxel_at(&layer, -16, 0, 0).weight, 0.0);
+
+        assert_eq!(
+            updated_blocks,
+            BTreeSet::from([
+                BlockIndex::new(-2, 0, 0),
+                BlockIndex::new(-1, 0, 0),
+                BlockIndex::new(0, 0, 0),
+                BlockIndex::new(1, 0, 0)
+            ])
+        );
+    }
+
+    #[test]
+    fn weighted_average() {
+        let mut layer = Layer::<Tsdf, 8>::new(1.0);
+        let mut updated_blocks = BTreeSet::new();
+        let mut integrator = TsdfIntegrator::new(TsdfIntegratorConfig {
+            max_weight: 3.0,
+            ..Default::default()
+        });
+
+        let mut image = image::RgbImage::from_pixel(8, 8, image::Rgb([255, 255, 255]));
+        image.put_pixel(1, 1, image::Rgb([0, 0, 0]));
+
+        integrator.integrate_image(&mut layer, &image, &mut updated_blocks);
+        assert_eq!(
+            voxel_at(&layer, 1, 1, 0),
+            Tsdf {
+                distance: 0.0,
+                weight: 1.0
+            }
+        );
+        assert_eq!(
+            voxel_at(&layer, 6, 6, 0),
+            Tsdf {
+                distance: 4.0,
+                weight: 1.0
+            }
+        );
+
+        // a single contradicting observation only moves the average
+        image.put_pixel(1, 1, image::Rgb([255, 255, 255]));
+        image.put_pixel(6, 6, image::Rgb([0, 0, 0]));
+        integrator.integrate_image(&mut layer, &image, &mut updated_blocks);
+        assert_eq!(
+            voxel_at(&layer, 1, 1, 0),
+            Tsdf {
+                distance: 2.0,
+                weight: 2.0
+            }
+        );
+        assert_eq!(
+            voxel_at(&layer, 6, 6, 0),
+            Tsdf {
+                distance: 2.0,
+                weight: 2.0
+            }
+        );
+
+        // the weight is capped
+        for _ in 0..20 {
+            integrator.integrate_image(&mut layer, &image, &mut updated_blocks);
+        }
+        let voxel = voxel_at(&layer, 6, 6, 0);
+        assert_eq!(voxel.weight, 3.0);
+        assert!(voxel.distance < 0.1);
+
+        // repeated observations of a converged map do not dirty blocks
+        let image = image::RgbImage::from_pixel(8, 8, image::Rgb([255, 255, 255]));
+        let mut layer = Layer::<Tsdf, 8>::new(1.0);
+        for _ in 0..3 {
+            integrator.integrate_image(&mut layer, &image, &mut BTreeSet::new());
+        }
+        updated_blocks.clear();
+        integrator.integrate_image(&mut layer, &image, &mut updated_blocks);
+        assert!(updated_blocks.is_empty());
+    }
+
+    #[test]
+    fn image_signed_distances() {
+        let mut layer = Layer::<Tsdf, 8>::new(1.0);
+        let mut updated_blocks = BTreeSet::new();
+        let mut integrator = TsdfIntegrator::new(TsdfIntegratorConfig::default());
+
+        // free on the left, obstacle on the right, unknown top row
+        let image = image::RgbImage::from_fn(8, 8, |x, y| match (x, y) {
+            (_, 0) => image::Rgb([128, 128, 128]),
+            (0..=3, _) => image::Rgb([255, 255, 255]),
+            _ => image::Rgb([0, 0, 0]),
+        });
+        integrator.integrate_image(&mut layer, &image, &mut updated_blocks);
+        assert_eq!(updated_blocks, BTreeSet::from([BlockIndex::new(0, 0, 0)]));
+
+        let distances: Vec<_> = (0..8).map(|x| voxel_at(&layer, x, 5, 0).distance).collect();
+        assert_eq!(distances, [4.0, 3.0, 2.0, 1.0, 0.0, -1.0, -2.0, -3.0]);
+
+        // unknown pixels are not integrated
+        assert_eq!(voxel_at(&layer, 3, 0, 0).weight, 0.0);
+
+        // diagonal to the surface
+        let image = image::RgbImage::from_fn(8, 8, |x, y| {
+            if (x, y) == (4, 4) {
+                image::Rgb([0, 0, 0])
+            } else {
+                image::Rgb([255, 255, 255])
+            }
+        });
+        let mut layer = Layer::<Tsdf, 8>::new(1.0);
+        integrator.integrate_image(&mut layer, &image, &mut updated_blocks);
+        assert_eq!(voxel_at(&layer, 5, 5, 0).distance, Real::sqrt(2.0));
+    }
+
+    #[test]
+    fn weighting() {
+        assert_eq!(TsdfWeighting::Constant.weight(-0.3, 0.4), 1.0);
+        assert_eq!(TsdfWeighting::LinearDropoff.weight(0.3, 0.4), 1.0);
+        assert_eq!(TsdfWeighting::LinearDropoff.weight(-0.2, 0.4), 0.5);
+        assert_eq!(TsdfWeighting::LinearDropoff.weight(-0.5, 0.4), 0.0);
+    }
+}
@@ -0,0 +1,311 @@
+//! Loader for ROS `map_server` maps
+//!
+//! A map consists of a YAML file describing an image (usually a PGM), e.g.,
+//!
+//! ```yaml
+//! image: map.pgm
+//! resolution: 0.05
+//! origin: [-10.0, -10.0, 0.0]
+//! occupied_thresh: 0.65
+//! free_thresh: 0.196
+//! negate: 0
+//! ```
+//!
+//! The yaw of the origin is ignored, as it is by most of the ROS navigation stack.
+
+use std::{
+    fmt::Display,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::core::{
+    map2d::{Map2d, PixelClass},
+    prelude::*,
+};
+
+#[derive(Debug)]
+pub enum MapServerError {
+    Io(std::io::Error),
+    Yaml(serde_yaml::Error),
+    Image(image::ImageError),
+    Unsupported(String),
+}
+
+impl Display for MapServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MapServerError::Io(err) => write!(f, "io error: {err}"),
+            MapServerError::Yaml(err) => write!(f, "invalid map yaml: {err}"),
+            MapServerError::Image(err) => write!(f, "invalid map image: {err}"),
+            MapServerError::Unsupported(msg) => write!(f, "unsupported map: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for MapServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MapServerError::Io(err) => Some(err),
+            MapServerError::Yaml(err) => Some(err),
+            MapServerError::Image(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MapServerError {
+    fn from(err: std::io::Error) -> Self {
+        MapServerError::Io(err)
+    }
+}
+
+impl From<serde_yaml::Error> for MapServerError {
+    fn from(err: serde_yaml::Error) -> Self {
+        MapServerError::Yaml(err)
+    }
+}
+
+impl From<image::ImageError> for MapServerError {
+    fn from(err: image::ImageError) -> Self {
+        MapServerError::Image(err)
+    }
+}
+
+/// How pixel values are interpreted
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MapMode {
+    #[default]
+    Trinary,
+    /// Same thresholds as trinary, the scaled probabilities are not used
+    Scale,
+    /// Pixel values are occupancy values, not supported
+    Raw,
+}
+
+/// Contents of the map YAML file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MapMetadata {
+    /// Path to the image, relative to the YAML file
+    pub image: PathBuf,
+    /// Size of a pixel
+    pub resolution: Real,
+    /// Pose `[x, y, yaw]` of the lower-left pixel
+    pub origin: [Real; 3],
+    /// Pixels with a higher occupancy probability are obstacles
+    pub occupied_thresh: Real,
+    /// Pixels with a lower occupancy probability are free
+    pub free_thresh: Real,
+    /// Swaps the meaning of black and white
+    #[serde(deserialize_with = "bool_or_int")]
+    pub negate: bool,
+    #[serde(default)]
+    pub mode: MapMode,
+}
+
+impl MapMetadata {
+    pub fn classify(&self, value: u8) -> PixelClass {
+        let value = value as Real / 255.0;
+        let probability = if self.negate { value } else { 1.0 - value };
+
+        if probability > self.occupied_thresh {
+            PixelClass::Occupied
+        } else if probability < self.free_thresh {
+            PixelClass::Free
+        } else {
+            PixelClass::Unknown
+        }
+    }
+}
+
+/// `negate` is usually written as 0 or 1
+fn bool_or_int<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrInt {
+        Bool(bool),
+        Int(i64),
+    }
+
+    Ok(match BoolOrInt::deserialize(deserializer)? {
+        BoolOrInt::Bool(b) => b,
+        BoolOrInt::Int(i) => i != 0,
+    })
+}
+
+/// Loads the map described by the YAML file at `path`
+pub fn load_map<P: AsRef<Path>>(path: P) -> Result<Map2d, MapServerError> {
+    let path = path.as_ref();
+    let metadata = read_metadata(&mut File::open(path)?)?;
+
+    let image_path = path
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join(&metadata.image);
+    let image = image::io::Reader::open(image_path)?.decode()?.into_luma8();
+
+    map_from_image(&metadata, &image)
+}
+
+pub fn read_metadata<R: Read>(reader: &mut R) -> Result<MapMetadata, MapServerError> {
+    Ok(serde_yaml::from_reader(reader)?)
+}
+
+/// Classifies the pixels of the map image
+///
+/// The first image row is the top of the map, i.e., at the highest y coordinate.
+pub fn map_from_image(
+    metadata: &MapMetadata,
+    image: &image::GrayImage,
+) -> Result<Map2d, MapServerError> {
+    if metadata.mode == MapMode::Raw {
+        return Err(MapServerError::Unsupported("raw mode".to_string()));
+    }
+    if metadata.resolution <= 0.0 {
+        return Err(MapServerError::Unsupported(format!(
+            "resolution {}",
+            metadata.resolution
+        )));
+    }
+
+    let (width, height) = image.dimensions();
+    let cells = (0..height)
+        .rev()
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| metadata.classify(image.get_pixel(x, y).0[0]))
+        .collect();
+
+    let [x, y, _yaw] = metadata.origin;
+
+    Ok(Map2d::new(
+        width,
+        height,
+        cells,
+        metadata.resolution,
+        Point3::new(x, y, 0.0),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use nalgebra::point;
+
+    use super::*;
+    use crate::{
+        core::{layer::Layer, voxel::Tsdf},
+        integrators::tsdf::{TsdfIntegrator, TsdfIntegratorConfig},
+    };
+
+    const YAML: &str = "image: test.pgm
+resolution: 0.5
+origin: [-2.0, 1.0, 0.0]
+negate: 0
+occupied_thresh: 0.65
+free_thresh: 0.196
+";
+
+    /// 4x2 image, the top row is free, the bottom row is unknown except for an obstacle
+    fn image() -> image::GrayImage {
+        image::GrayImage::from_fn(4, 2, |x, y| match (x, y) {
+            (_, 0) => image::Luma([254]),
+            (0, 1) => image::Luma([0]),
+            _ => image::Luma([205]),
+        })
+    }
+
+    #[test]
+    fn metadata() {
+        let metadata = read_metadata(&mut YAML.as_bytes()).unwrap();
+        assert_eq!(
+            metadata,
+            MapMetadata {
+                image: "test.pgm".into(),
+                resolution: 0.5,
+                origin: [-2.0, 1.0, 0.0],
+                occupied_thresh: 0.65,
+                free_thresh: 0.196,
+                negate: false,
+                mode: MapMode::Trinary,
+            }
+        );
+
+        let negated =
+            read_metadata(&mut YAML.replace("negate: 0", "negate: true").as_bytes()).unwrap();
+        assert!(negated.negate);
+        assert_eq!(negated.classify(0), PixelClass::Free);
+        assert_eq!(negated.classify(255), PixelClass::Occupied);
+
+        assert!(matches!(
+            read_metadata(&mut "resolution: 0.5\n".as_bytes()),
+            Err(MapServerError::Yaml(_))
+        ));
+    }
+
+    #[test]
+    fn classification() {
+        let metadata = read_metadata(&mut YAML.as_bytes()).unwrap();
+        let map = map_from_image(&metadata, &image()).unwrap();
+
+        assert_eq!((map.width(), map.height()), (4, 2));
+        // rows are flipped
+        assert_eq!(map.class_at(0, 0), PixelClass::Occupied);
+        assert_eq!(map.class_at(1, 0), PixelClass::Unknown);
+        assert_eq!(map.class_at(0, 1), PixelClass::Free);
+        assert_eq!(map.center(0, 0), point![-1.75, 1.25, 0.0]);
+        assert_eq!(map.center(3, 1), point![-0.25, 1.75, 0.0]);
+
+        let raw = MapMetadata {
+            mode: MapMode::Raw,
+            ..metadata
+        };
+        assert!(matches!(
+            map_from_image(&raw, &image()),
+            Err(MapServerError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn load_and_integrate() {
+        let dir = std::env::temp_dir().join(format!("esdf-vis-map-server-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("test.yaml"), YAML).unwrap();
+        image().save(dir.join("test.pgm")).unwrap();
+
+        let map = load_map(dir.join("test.yaml"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let map = map.unwrap();
+
+        let mut layer = Layer::<Tsdf, 8>::new(map.resolution());
+        let mut updated_blocks = BTreeSet::new();
+        TsdfIntegrator::new(TsdfIntegratorConfig::default()).integrate_map(
+            &mut layer,
+            &map,
+            &mut updated_blocks,
+        );
+
+        let voxel_at = |p: Point3<Real>| {
+            let (block_index, voxel_index) = layer.global_index_from_point(&p).block_voxel_index();
+            *layer
+                .block_by_index(&block_index)
+                .unwrap()
+                .read()
+                .voxel_from_index(&voxel_index)
+        };
+
+        // the obstacle is at the lower left corner of the map
+        let obstacle = voxel_at(point![-1.75, 1.25, 0.0]);
+        assert_eq!((obstacle.distance, obstacle.weight), (0.0, 1.0));
+
+        let free = voxel_at(point![-1.25, 1.75, 0.0]);
+        assert!((free.distance - (0.5 as Real).hypot(0.5)).abs() < 1e-5);
+
+        // unknown
+        assert_eq!(voxel_at(point![-0.25, 1.25, 0.0]).weight, 0.0);
+    }
+}
@@ -0,0 +1,25 @@
+pub mod map_server;
+pub mod ply;
+
+use std::path::Path;
+
+use crate::core::{map2d::Map2d, prelude::*};
+
+use self::map_server::MapServerError;
+
+/// Loads a `map_server` YAML file or an image with one cell of `resolution` per pixel
+///
+/// The resolution of YAML files is taken from the file.
+pub fn load_map<P: AsRef<Path>>(path: P, resolution: Real) -> Result<Map2d, MapServerError> {
+    let path = path.as_ref();
+    let is_yaml = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml"));
+
+    if is_yaml {
+        map_server::load_map(path)
+    } else {
+        let image = image::io::Reader::open(path)?.decode()?.to_rgb8();
+        Ok(Map2d::from_rgb(&image, resolution))
+    }
+}
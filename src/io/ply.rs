@@ -0,0 +1,385 @@
+//! Minimal PLY reader for point clouds
+//!
+//! Supports the `ascii`, `binary_little_endian` and `binary_big_endian` formats.
+//! Only the `x`, `y` and `z` properties of the `vertex` element are read.
+
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+};
+
+use crate::core::prelude::*;
+
+#[derive(Debug)]
+pub enum PlyError {
+    Io(std::io::Error),
+    Parse(String),
+}
+
+impl Display for PlyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlyError::Io(err) => write!(f, "io error: {err}"),
+            PlyError::Parse(msg) => write!(f, "invalid ply file: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for PlyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PlyError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PlyError {
+    fn from(err: std::io::Error) -> Self {
+        PlyError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ScalarType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl ScalarType {
+    fn parse(name: &str) -> Result<Self, PlyError> {
+        Ok(match name {
+            "char" | "int8" => ScalarType::I8,
+            "uchar" | "uint8" => ScalarType::U8,
+            "short" | "int16" => ScalarType::I16,
+            "ushort" | "uint16" => ScalarType::U16,
+            "int" | "int32" => ScalarType::I32,
+            "uint" | "uint32" => ScalarType::U32,
+            "float" | "float32" => ScalarType::F32,
+            "double" | "float64" => ScalarType::F64,
+            _ => return Err(PlyError::Parse(format!("unknown type '{name}'"))),
+        })
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            ScalarType::I8 | ScalarType::U8 => 1,
+            ScalarType::I16 | ScalarType::U16 => 2,
+            ScalarType::I32 | ScalarType::U32 | ScalarType::F32 => 4,
+            ScalarType::F64 => 8,
+        }
+    }
+
+    fn read<R: Read>(&self, reader: &mut R, format: Format) -> Result<f64, PlyError> {
+        let mut buf = [0u8; 8];
+        let buf = &mut buf[..self.size()];
+        reader.read_exact(buf)?;
+        if format == Format::BinaryBigEndian {
+            buf.reverse();
+        }
+
+        Ok(match self {
+            ScalarType::I8 => buf[0] as i8 as f64,
+            ScalarType::U8 => buf[0] as f64,
+            ScalarType::I16 => i16::from_le_bytes(buf.try_into().unwrap()) as f64,
+            ScalarType::U16 => u16::from_le_bytes(buf.try_into().unwrap()) as f64,
+            ScalarType::I32 => i32::from_le_bytes(buf.try_into().unwrap()) as f64,
+            ScalarType::U32 => u32::from_le_bytes(buf.try_into().unwrap()) as f64,
+            ScalarType::F32 => f32::from_le_bytes(buf.try_into().unwrap()) as f64,
+            ScalarType::F64 => f64::from_le_bytes(buf.try_into().unwrap()),
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Property {
+    Scalar(String, ScalarType),
+    List,
+}
+
+#[derive(Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+/// Loads the vertices of a PLY file
+pub fn load_points<P: AsRef<Path>>(path: P) -> Result<Vec<Point3<Real>>, PlyError> {
+    read_points(&mut BufReader::new(File::open(path)?))
+}
+
+/// Reads the vertices of a PLY file
+pub fn read_points<R: BufRead>(reader: &mut R) -> Result<Vec<Point3<Real>>, PlyError> {
+    let (format, elements) = read_header(reader)?;
+
+    for element in elements {
+        if element.name == "vertex" {
+            return read_vertices(reader, format, &element);
+        }
+
+        // skip elements in front of the vertices
+        for _ in 0..element.count {
+            match format {
+                Format::Ascii => {
+                    reader.read_line(&mut String::new())?;
+                }
+                _ => {
+                    for property in &element.properties {
+                        match property {
+                            Property::Scalar(_, ty) => {
+                                ty.read(reader, format)?;
+                            }
+                            Property::List => {
+                                return Err(PlyError::Parse(format!(
+                                    "cannot skip list properties of '{}' in binary files",
+                                    element.name
+                                )))
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Err(PlyError::Parse("no vertex element".to_string()))
+}
+
+fn read_header<R: BufRead>(reader: &mut R) -> Result<(Format, Vec<Element>), PlyError> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line.trim_end() != "ply" {
+        return Err(PlyError::Parse("missing 'ply' magic".to_string()));
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(PlyError::Parse("unexpected end of header".to_string()));
+        }
+
+        let tokens: Vec<_> = line.split_whitespace().collect();
+        match tokens.as_slice() {
+            ["format", "ascii", _] => format = Some(Format::Ascii),
+            ["format", "binary_little_endian", _] => format = Some(Format::BinaryLittleEndian),
+            ["format", "binary_big_endian", _] => format = Some(Format::BinaryBigEndian),
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| PlyError::Parse(format!("invalid element count '{count}'")))?,
+                properties: vec![],
+            }),
+            ["property", "list", ..] => elements
+                .last_mut()
+                .ok_or_else(|| PlyError::Parse("property without element".to_string()))?
+                .properties
+                .push(Property::List),
+            ["property", ty, name] => elements
+                .last_mut()
+                .ok_or_else(|| PlyError::Parse("property without element".to_string()))?
+                .properties
+                .push(Property::Scalar(name.to_string(), ScalarType::parse(ty)?)),
+            ["end_header"] => break,
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => {
+                return Err(PlyError::Parse(format!(
+                    "unexpected header line '{}'",
+                    line.trim_end()
+                )))
+            }
+        }
+    }
+
+    let format = format.ok_or_else(|| PlyError::Parse("missing format".to_string()))?;
+    Ok((format, elements))
+}
+
+fn read_vertices<R: BufRead>(
+    reader: &mut R,
+    format: Format,
+    element: &Element,
+) -> Result<Vec<Point3<Real>>, PlyError> {
+    let position = |axis: &str| {
+        element
+            .properties
+            .iter()
+            .position(|p| matches!(p, Property::Scalar(name, _) if name == axis))
+            .ok_or_else(|| PlyError::Parse(format!("vertex has no '{axis}' property")))
+    };
+    let xyz = [position("x")?, position("y")?, position("z")?];
+
+    if format != Format::Ascii
+        && element
+            .properties
+            .iter()
+            .any(|p| matches!(p, Property::List))
+    {
+        return Err(PlyError::Parse(
+            "list properties of vertices are not supported in binary files".to_string(),
+        ));
+    }
+
+    // the header count is untrusted, let the vector grow with the data actually read
+    let mut points = Vec::new();
+    let mut values = vec![0.0; element.properties.len()];
+    let mut line = String::new();
+
+    for _ in 0..element.count {
+        match format {
+            Format::Ascii => {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    return Err(PlyError::Parse(format!(
+                        "expected {} vertices, found {}",
+                        element.count,
+                        points.len()
+                    )));
+                }
+
+                // list properties are counted as a single value, they have to trail xyz
+                let mut tokens = line.split_whitespace();
+                for value in values.iter_mut() {
+                    let token = tokens.next().ok_or_else(|| {
+                        PlyError::Parse(format!("vertex {} has too few values", points.len()))
+                    })?;
+                    *value = token
+                        .parse()
+                        .map_err(|_| PlyError::Parse(format!("invalid value '{token}'")))?;
+                }
+            }
+            _ => {
+                for (value, property) in values.iter_mut().zip(&element.properties) {
+                    if let Property::Scalar(_, ty) = property {
+                        *value = ty.read(reader, format)?;
+                    }
+                }
+            }
+        }
+
+        points.push(Point3::new(
+            values[xyz[0]] as Real,
+            values[xyz[1]] as Real,
+            values[xyz[2]] as Real,
+        ));
+    }
+
+    Ok(points)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ascii() {
+        let ply = "ply\n\
+            format ascii 1.0\n\
+            comment test\n\
+            element vertex 2\n\
+            property float x\n\
+            property float y\n\
+            property float z\n\
+            property uchar intensity\n\
+            element face 0\n\
+            property list uchar int vertex_indices\n\
+            end_header\n\
+            1.0 2.0 3.0 255\n\
+            -1.5 0 0.25 0\n";
+
+        let points = read_points(&mut ply.as_bytes()).unwrap();
+        assert_eq!(
+            points,
+            vec![Point3::new(1.0, 2.0, 3.0), Point3::new(-1.5, 0.0, 0.25)]
+        );
+    }
+
+    #[test]
+    fn binary() {
+        let mut le = b"ply\nformat binary_little_endian 1.0\nelement vertex 2\n\
+            property uchar label\nproperty double x\nproperty double y\nproperty double z\n\
+            end_header\n"
+            .to_vec();
+        let mut be = b"ply\nformat binary_big_endian 1.0\nelement vertex 2\n\
+            property uchar label\nproperty double x\nproperty double y\nproperty double z\n\
+            end_header\n"
+            .to_vec();
+
+        for p in [[1.0f64, 2.0, 3.0], [-4.0, 0.5, 0.0]] {
+            le.push(7);
+            be.push(7);
+            for v in p {
+                le.extend_from_slice(&v.to_le_bytes());
+                be.extend_from_slice(&v.to_be_bytes());
+            }
+        }
+
+        for ply in [le, be] {
+            let points = read_points(&mut ply.as_slice()).unwrap();
+            assert_eq!(
+                points,
+                vec![Point3::new(1.0, 2.0, 3.0), Point3::new(-4.0, 0.5, 0.0)]
+            );
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(
+            read_points(&mut "off\n".as_bytes()),
+            Err(PlyError::Parse(_))
+        ));
+        assert!(matches!(
+            read_points(
+                &mut "ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\nend_header\n1\n"
+                    .as_bytes()
+            ),
+            Err(PlyError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let header = "ply\nformat ascii 1.0\nelement vertex 2\n\
+            property float x\nproperty float y\nproperty float z\nend_header\n";
+
+        // missing row
+        assert!(matches!(
+            read_points(&mut format!("{header}1 2 3\n").as_bytes()),
+            Err(PlyError::Parse(_))
+        ));
+        // short row
+        assert!(matches!(
+            read_points(&mut format!("{header}1 2 3\n4 5\n").as_bytes()),
+            Err(PlyError::Parse(_))
+        ));
+        // a huge count must not be preallocated
+        assert!(matches!(
+            read_points(
+                &mut "ply\nformat binary_little_endian 1.0\nelement vertex 4000000000000\n\
+                    property float x\nproperty float y\nproperty float z\nend_header\n"
+                    .as_bytes()
+            ),
+            Err(PlyError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,72 @@
+//! Incremental Euclidean signed distance fields (ESDF) from TSDF and occupancy maps
+//!
+//! Maps are integrated into a [`TsdfLayer`] (or an [`OccupancyLayer`]) and the ESDF is updated
+//! from the blocks that changed, on the CPU or the GPU. Most types are in the [`prelude`].
+//!
+//! ```
+//! use std::collections::BTreeSet;
+//!
+//! use esdf_vis::prelude::*;
+//!
+//! // a dark obstacle in a bright (free) map
+//! let map = image::RgbImage::from_fn(32, 32, |x, y| {
+//!     let obstacle = (12..20).contains(&x) && (12..20).contains(&y);
+//!     image::Rgb(if obstacle { [0; 3] } else { [255; 3] })
+//! });
+//!
+//! let mut tsdf_layer = TsdfLayer::<8>::new(0.1);
+//! let mut updated_blocks = BTreeSet::new();
+//! TsdfIntegrator::new(Default::default())
+//!     .integrate_image(&mut tsdf_layer, &map, &mut updated_blocks);
+//!
+//! let mut esdf_layer = EsdfLayer::<8>::new(0.1);
+//! let config = EsdfIntegratorConfig {
+//!     planar: true,
+//!     ..Default::default()
+//! };
+//! let mut esdf_integrator = EsdfIntegrator::new(config, Box::new(CpuBackend::default()));
+//! let stats =
+//!     esdf_integrator.update_blocks(&tsdf_layer, &mut esdf_layer, &updated_blocks, |_, _, _| {});
+//! esdf_integrator.sync(&mut esdf_layer);
+//!
+//! assert!(stats.converged);
+//! // 7 voxels to the left of the obstacle
+//! let index = esdf_layer.global_index_from_point(&Point3::new(0.55, 1.55, 0.0));
+//! let distance = esdf_layer.distance_at_index(&index).unwrap();
+//! assert!((distance - 0.7).abs() < 1e-4);
+//! ```
+
+pub mod core;
+pub mod integrators;
+pub mod io;
+pub mod renderer;
+pub mod scenario;
+mod wgpu_utils;
+
+pub use wgpu_utils::{request_device, DeviceError, DeviceOptions, GpuDevice, GpuError};
+
+pub type TsdfLayer<const VPS: usize = 8> = core::layer::Layer<core::voxel::Tsdf, VPS>;
+pub type EsdfLayer<const VPS: usize = 8> = core::layer::Layer<core::voxel::Esdf, VPS>;
+pub type OccupancyLayer<const VPS: usize = 8> = core::layer::Layer<core::voxel::Occupancy, VPS>;
+
+pub mod prelude {
+    pub use crate::core::prelude::*;
+
+    pub use crate::core::{
+        index::{BlockIndex, GlobalIndex, VoxelIndex},
+        layer::Layer,
+        map2d::Map2d,
+        voxel::{DistanceVoxel, Esdf, Occupancy, Tsdf},
+    };
+
+    pub use crate::integrators::{
+        esdf::{EsdfBackend, EsdfEvent, EsdfIntegrator, EsdfIntegratorConfig, EsdfUpdateStats},
+        esdf_cpu::CpuBackend,
+        esdf_gpu::GpuBackend,
+        occupancy::{OccupancyIntegrator, OccupancyIntegratorConfig},
+        projective_tsdf::{ProjectiveTsdfIntegrator, ProjectiveTsdfIntegratorConfig},
+        tsdf::{TsdfIntegrator, TsdfIntegratorConfig},
+    };
+
+    pub use crate::{EsdfLayer, OccupancyLayer, TsdfLayer};
+}
@@ -1,181 +1,746 @@
-use std::collections::BTreeSet;
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    error::Error,
+    fmt::Display,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use integrators::tsdf::{TsdfIntegrator, TsdfIntegratorConfig};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
 
-use renderer::Renderer;
+use esdf_vis::{
+    core::color::rainbow_map,
+    io,
+    prelude::*,
+    renderer::Renderer,
+    scenario::{Scenario, ScenarioRunner, Step},
+};
 
-use crate::integrators::{esdf, esdf_gpu};
+/// Incremental ESDF generation from 2D maps
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Reports every pass, repeat to report every event of the updates
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Only reports errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Writes a flame graph of the run to this directory
+    #[arg(long, global = true)]
+    profile: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Builds the ESDF from one map or a sequence of maps
+    Integrate {
+        #[command(flatten)]
+        maps: MapArgs,
+        /// ESDF layer of the last update
+        #[arg(short, long, default_value = "esdf.layer")]
+        output: PathBuf,
+        /// TSDF layer of the last update
+        #[arg(long)]
+        tsdf_output: Option<PathBuf>,
+        /// Statistics of the updates as JSON
+        #[arg(long)]
+        stats_output: Option<PathBuf>,
+    },
+    /// Renders the updates as an animated GIF
+    Render {
+        #[command(flatten)]
+        maps: MapArgs,
+        #[arg(short, long, default_value = "esdf.gif")]
+        output: PathBuf,
+        /// Colors the voxels by their site instead of their distance
+        #[arg(long)]
+        sites: bool,
+        /// Only renders the result of each update
+        #[arg(long)]
+        results_only: bool,
+    },
+    /// Writes the distance field of the last update
+    Export {
+        #[command(flatten)]
+        maps: MapArgs,
+        /// Output file, the format is taken from the extension if not given
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long, value_enum)]
+        format: Option<ExportFormat>,
+    },
+    /// Times the updates of the map sequence
+    Bench {
+        #[command(flatten)]
+        maps: MapArgs,
+        /// Repetitions of the whole sequence
+        #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+        runs: u32,
+        /// Statistics of all updates as JSON
+        #[arg(long)]
+        stats_output: Option<PathBuf>,
+    },
+    /// Runs the steps of a TOML or JSON scenario file
+    Scenario {
+        scenario: PathBuf,
+        #[arg(long, value_enum, default_value_t = Backend::Gpu)]
+        backend: Backend,
+        /// Voxels per side of a block
+        #[arg(long, value_enum, default_value_t = Vps::Vps8)]
+        vps: Vps,
+        /// Renders a frame after each step as an animated GIF
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Also renders the phases of the ESDF updates
+        #[arg(long, requires = "output")]
+        events: bool,
+        /// Directory of the layers saved by snapshot steps
+        #[arg(long, default_value = ".")]
+        output_dir: PathBuf,
+        /// Statistics of the updates as JSON
+        #[arg(long)]
+        stats_output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Args)]
+struct MapArgs {
+    /// Map images or map_server YAML files, each is followed by an ESDF update
+    #[arg(required = true)]
+    maps: Vec<PathBuf>,
+    #[arg(long, value_enum, default_value_t = Backend::Gpu)]
+    backend: Backend,
+    /// Size of a pixel of map images, map_server YAML files give their own resolution.
+    /// The resolution of the first map is the edge length of a voxel
+    #[arg(long, default_value_t = 1.0)]
+    voxel_size: Real,
+    /// Voxels per side of a block
+    #[arg(long, value_enum, default_value_t = Vps::Vps8)]
+    vps: Vps,
+    /// Integrations of each map, outweighs the previous maps
+    #[arg(long, default_value_t = 4)]
+    observations: usize,
+    /// Also sweeps and propagates along z
+    #[arg(long)]
+    volumetric: bool,
+    /// Negative distances inside of obstacles
+    #[arg(long)]
+    signed: bool,
+    /// Distances are not propagated beyond this value
+    #[arg(long)]
+    max_distance: Option<Real>,
+}
 
-mod core;
-mod integrators;
-mod renderer;
-mod wgpu_utils;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Backend {
+    Cpu,
+    /// Falls back to the CPU if there is no suitable device
+    Gpu,
+}
 
-type TsdfLayer = core::layer::Layer<core::voxel::Tsdf, 8>;
-type EsdfLayer = core::layer::Layer<core::voxel::Esdf, 8>;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Vps {
+    #[value(name = "4")]
+    Vps4,
+    #[value(name = "8")]
+    Vps8,
+    #[value(name = "16")]
+    Vps16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ExportFormat {
+    /// Colored distances of the z=0 plane
+    Png,
+    /// `x,y,z,distance` of every voxel with a known distance
+    Csv,
+}
 
 fn main() {
-    if firestorm::enabled() {
-        firestorm::bench("./flames/", || futures::executor::block_on(run())).unwrap();
+    let cli = Cli::parse();
+
+    let result = match (&cli.profile, firestorm::enabled()) {
+        (Some(dir), true) => {
+            // bench takes a `Fn` and may run it more than once
+            let result = RefCell::new(Ok(()));
+            firestorm::bench(dir, || *result.borrow_mut() = run(&cli)).unwrap();
+            result.into_inner()
+        }
+        _ => run(&cli),
+    };
+
+    if let Err(err) = result {
+        eprintln!("error: {err}");
+        std::process::exit(1);
     }
 }
 
-async fn run() {
-    const RENDER: bool = false;
-    const USE_GPU: bool = true;
+fn run(cli: &Cli) -> Result<(), Box<dyn Error>> {
+    let maps = match &cli.command {
+        Command::Integrate { maps, .. }
+        | Command::Render { maps, .. }
+        | Command::Export { maps, .. }
+        | Command::Bench { maps, .. } => maps,
+        Command::Scenario { vps, .. } => {
+            return match vps {
+                Vps::Vps4 => run_scenario::<4>(cli),
+                Vps::Vps8 => run_scenario::<8>(cli),
+                Vps::Vps16 => run_scenario::<16>(cli),
+            }
+        }
+    };
 
-    let (device, mut queue) = wgpu_utils::create_adapter().await.unwrap();
+    match maps.vps {
+        Vps::Vps4 => run_vps::<4>(cli, maps),
+        Vps::Vps8 => run_vps::<8>(cli, maps),
+        Vps::Vps16 => run_vps::<16>(cli, maps),
+    }
+}
 
-    let map_img = image::io::Reader::open(format!("{}/maps/map3.png", env!("CARGO_MANIFEST_DIR")))
-        .unwrap()
-        .decode()
-        .unwrap()
-        .to_rgb8();
+fn run_scenario<const VPS: usize>(cli: &Cli) -> Result<(), Box<dyn Error>> {
+    let Command::Scenario {
+        scenario,
+        backend,
+        output,
+        events,
+        output_dir,
+        stats_output,
+        ..
+    } = &cli.command
+    else {
+        unreachable!("not a scenario command")
+    };
+    let verbosity = Verbosity::new(cli);
 
-    let mut tsdf_layer = TsdfLayer::new(1.0);
-    let mut tsdf_integrator = TsdfIntegrator::new(TsdfIntegratorConfig::default());
+    let scenario = Scenario::load(scenario)?;
+    let mut runner = ScenarioRunner::<VPS>::new(&scenario, create_backend(*backend, verbosity));
+    runner.set_output_dir(output_dir);
+    if output.is_some() {
+        runner.record(Renderer::new(false), *events);
+    }
 
-    let mut esdf_layer = EsdfLayer::new(1.0);
+    runner.run(&scenario, |index, step, runner| {
+        if verbosity >= Verbosity::Passes {
+            println!("step {}: {step}", index + 1);
+        }
 
-    let renderer = std::rc::Rc::new(std::cell::RefCell::new(Renderer::new(false)));
+        if let (Step::UpdateEsdf, Some(stats), true) =
+            (step, runner.stats().last(), verbosity >= Verbosity::Normal)
+        {
+            print_stats(format_args!("step {}", index + 1), stats);
+        }
+    })?;
 
-    // map to tsdf
-    let mut dirty_blocks = BTreeSet::new();
-    tsdf_integrator.integrate_image(&mut tsdf_layer, &map_img, &mut dirty_blocks);
+    if let (Some(path), Some(renderer)) = (output, runner.renderer_mut()) {
+        renderer.render_gif(&path.to_string_lossy());
+    }
+    if let Some(path) = stats_output {
+        write_json(path, runner.stats())?;
+    }
 
-    // generate esdf and render on callback
-    {
-        firestorm::profile_section!(esdf_full_update);
-        let renderer_cb = renderer.clone();
+    Ok(())
+}
 
-        if USE_GPU {
-            let mut esdf_integrator = esdf_gpu::EsdfIntegrator::new(
-                &device,
-                &mut queue,
-                esdf_gpu::EsdfIntegratorConfig::default(),
-            );
+fn run_vps<const VPS: usize>(cli: &Cli, args: &MapArgs) -> Result<(), Box<dyn Error>> {
+    let verbosity = Verbosity::new(cli);
+    let maps = load_maps(args)?;
+    // all maps share the resolution
+    let voxel_size = maps[0].1.resolution();
 
-            esdf_integrator.update_blocks(
-                &tsdf_layer,
-                &mut esdf_layer,
-                &dirty_blocks,
-                &device,
-                &mut queue,
-                move |op, tsdf_layer, esdf_layer, block_indices, duration| {
-                    if RENDER {
-                        renderer_cb.borrow_mut().render_tsdf_layer(
-                            tsdf_layer,
-                            esdf_layer,
-                            block_indices,
-                            op,
-                            Some(duration),
-                        );
+    match &cli.command {
+        Command::Integrate {
+            output,
+            tsdf_output,
+            stats_output,
+            ..
+        } => {
+            let mut mapper = Mapper::<VPS>::new(args, voxel_size, verbosity);
+            let stats = mapper.update_sequence(&maps, |_, _, _| {}, |_, _| {});
+
+            mapper.esdf_layer.save(output)?;
+            if let Some(path) = tsdf_output {
+                mapper.tsdf_layer.save(path)?;
+            }
+            if let Some(path) = stats_output {
+                write_json(path, &stats)?;
+            }
+        }
+        Command::Render {
+            output,
+            sites,
+            results_only,
+            ..
+        } => {
+            let renderer = RefCell::new(Renderer::new(*sites));
+
+            let mut mapper = Mapper::<VPS>::new(args, voxel_size, verbosity);
+            mapper.update_sequence(
+                &maps,
+                |event, tsdf_layer, esdf_layer| {
+                    if !results_only {
+                        renderer
+                            .borrow_mut()
+                            .render_event(tsdf_layer, esdf_layer, event);
                     }
                 },
-            );
-        } else {
-            let mut esdf_integrator =
-                esdf::EsdfIntegrator::new(esdf::EsdfIntegratorConfig::default());
-
-            esdf_integrator.update_blocks(
-                &tsdf_layer,
-                &mut esdf_layer,
-                &dirty_blocks,
-                move |op, tsdf_layer, esdf_layer, block_indices, duration| {
-                    if RENDER {
-                        renderer_cb.borrow_mut().render_tsdf_layer(
-                            tsdf_layer,
-                            esdf_layer,
-                            block_indices,
-                            op,
-                            Some(duration),
-                        );
-                    }
+                |tsdf_layer, esdf_layer| {
+                    renderer.borrow_mut().render_tsdf_layer(
+                        tsdf_layer,
+                        esdf_layer,
+                        &[],
+                        "",
+                        Some(Duration::from_secs(2)),
+                    );
                 },
-            )
+            );
+
+            renderer.into_inner().render_gif(&output.to_string_lossy());
         }
+        Command::Export { output, format, .. } => {
+            let format = match format {
+                Some(format) => *format,
+                None => format_from_extension(output)?,
+            };
+
+            let mut mapper = Mapper::<VPS>::new(args, voxel_size, verbosity);
+            mapper.update_sequence(&maps, |_, _, _| {}, |_, _| {});
+
+            match format {
+                ExportFormat::Png => distance_image(&mapper.esdf_layer).save(output)?,
+                ExportFormat::Csv => write_csv(output, &mapper.esdf_layer)?,
+            }
+        }
+        Command::Bench {
+            runs, stats_output, ..
+        } => {
+            let mut all_stats = vec![];
+            for run in 0..*runs {
+                // the stats are reported by the bench
+                let mut mapper = Mapper::<VPS>::new(args, voxel_size, Verbosity::Quiet);
+                all_stats.push(mapper.update_sequence(&maps, |_, _, _| {}, |_, _| {}));
+
+                if verbosity >= Verbosity::Normal {
+                    println!("run {}/{runs} done", run + 1);
+                }
+            }
+
+            print_bench(&args.maps, &all_stats);
+            if let Some(path) = stats_output {
+                write_json(path, &all_stats)?;
+            }
+        }
+        Command::Scenario { .. } => unreachable!("scenarios have no map arguments"),
     }
 
-    renderer.borrow_mut().render_tsdf_layer(
-        &tsdf_layer,
-        &esdf_layer,
-        &[],
-        "",
-        Some(std::time::Duration::from_secs(2)),
-    );
+    Ok(())
+}
 
-    let map_img = image::io::Reader::open(format!("{}/maps/map3b.png", env!("CARGO_MANIFEST_DIR")))
-        .unwrap()
-        .decode()
-        .unwrap()
-        .to_rgb8();
-
-    // map to tsdf
-    dirty_blocks.clear();
-    tsdf_integrator.integrate_image(&mut tsdf_layer, &map_img, &mut dirty_blocks);
-
-    // generate esdf and render on callback
-    {
-        firestorm::profile_section!(esdf_partial_update);
-        let renderer_cb = renderer.clone();
-
-        if USE_GPU {
-            let mut esdf_integrator = esdf_gpu::EsdfIntegrator::new(
-                &device,
-                &mut queue,
-                esdf_gpu::EsdfIntegratorConfig::default(),
-            );
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Passes,
+    Events,
+}
 
-            esdf_integrator.update_blocks(
-                &tsdf_layer,
-                &mut esdf_layer,
-                &dirty_blocks,
-                &device,
-                &mut queue,
-                move |op, tsdf_layer, esdf_layer, block_indices, duration| {
-                    if RENDER {
-                        renderer_cb.borrow_mut().render_tsdf_layer(
-                            tsdf_layer,
-                            esdf_layer,
-                            block_indices,
-                            op,
-                            Some(duration),
-                        );
-                    }
+impl Verbosity {
+    fn new(cli: &Cli) -> Self {
+        match (cli.quiet, cli.verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Passes,
+            (false, _) => Verbosity::Events,
+        }
+    }
+}
+
+/// The TSDF and ESDF layers with their integrators
+struct Mapper<const VPS: usize> {
+    tsdf_layer: TsdfLayer<VPS>,
+    esdf_layer: EsdfLayer<VPS>,
+    tsdf_integrator: TsdfIntegrator,
+    esdf_integrator: EsdfIntegrator<VPS>,
+    observations: usize,
+    verbosity: Verbosity,
+}
+
+impl<const VPS: usize> Mapper<VPS> {
+    fn new(args: &MapArgs, voxel_size: Real, verbosity: Verbosity) -> Self {
+        let config = EsdfIntegratorConfig {
+            planar: !args.volumetric,
+            signed: args.signed,
+            max_distance: args.max_distance.unwrap_or(Real::MAX),
+            ..Default::default()
+        };
+
+        Self {
+            tsdf_layer: TsdfLayer::new(voxel_size),
+            esdf_layer: EsdfLayer::new(voxel_size),
+            // low max. weight to quickly adapt to a changed map
+            tsdf_integrator: TsdfIntegrator::new(TsdfIntegratorConfig {
+                max_weight: 1.0,
+                ..Default::default()
+            }),
+            esdf_integrator: EsdfIntegrator::new(config, create_backend(args.backend, verbosity)),
+            observations: args.observations.max(1),
+            verbosity,
+        }
+    }
+
+    /// Integrates each map and updates the ESDF
+    ///
+    /// `on_event` gets the events of the updates, `on_update` the synced layers after each update.
+    fn update_sequence(
+        &mut self,
+        maps: &[(PathBuf, Map2d)],
+        mut on_event: impl FnMut(&EsdfEvent<VPS>, &TsdfLayer<VPS>, &EsdfLayer<VPS>),
+        mut on_update: impl FnMut(&TsdfLayer<VPS>, &EsdfLayer<VPS>),
+    ) -> Vec<EsdfUpdateStats> {
+        let verbosity = self.verbosity;
+        let mut all_stats = vec![];
+
+        for (path, map) in maps {
+            let mut updated_blocks = BTreeSet::new();
+            for _ in 0..self.observations {
+                self.tsdf_integrator
+                    .integrate_map(&mut self.tsdf_layer, map, &mut updated_blocks);
+            }
+
+            let stats = self.esdf_integrator.update_blocks(
+                &self.tsdf_layer,
+                &mut self.esdf_layer,
+                &updated_blocks,
+                |event, tsdf_layer, esdf_layer| {
+                    print_event(event, verbosity);
+                    on_event(event, tsdf_layer, esdf_layer);
                 },
             );
-        } else {
-            let mut esdf_integrator =
-                esdf::EsdfIntegrator::new(esdf::EsdfIntegratorConfig::default());
-
-            esdf_integrator.update_blocks(
-                &tsdf_layer,
-                &mut esdf_layer,
-                &dirty_blocks,
-                move |op, tsdf_layer, esdf_layer, block_indices, duration| {
-                    if RENDER {
-                        renderer_cb.borrow_mut().render_tsdf_layer(
-                            tsdf_layer,
-                            esdf_layer,
-                            block_indices,
-                            op,
-                            Some(duration),
-                        );
-                    }
-                },
-            )
+            self.esdf_integrator.sync(&mut self.esdf_layer);
+            on_update(&self.tsdf_layer, &self.esdf_layer);
+
+            if verbosity >= Verbosity::Normal {
+                print_stats(path.display(), &stats);
+            }
+
+            all_stats.push(stats);
+        }
+
+        all_stats
+    }
+}
+
+fn create_backend<const VPS: usize>(
+    backend: Backend,
+    verbosity: Verbosity,
+) -> Box<dyn EsdfBackend<VPS>> {
+    if backend == Backend::Cpu {
+        return Box::new(CpuBackend::default());
+    }
+
+    let gpu_backend = || -> Result<GpuBackend<VPS>, Box<dyn Error>> {
+        let gpu = futures::executor::block_on(esdf_vis::request_device(&Default::default()))?;
+        if verbosity >= Verbosity::Passes {
+            println!("GPU: {} ({:?})", gpu.info.name, gpu.info.backend);
+        }
+
+        Ok(GpuBackend::new(Arc::new(gpu.device), Arc::new(gpu.queue))?)
+    };
+
+    match gpu_backend() {
+        Ok(backend) => Box::new(backend),
+        Err(err) => {
+            eprintln!("GPU: {err}, using the CPU");
+            Box::new(CpuBackend::default())
         }
     }
+}
 
-    renderer.borrow_mut().render_tsdf_layer(
-        &tsdf_layer,
-        &esdf_layer,
-        &[],
-        "",
-        Some(std::time::Duration::from_secs(4)),
+fn print_stats(name: impl Display, stats: &EsdfUpdateStats) {
+    println!(
+        "{name}: {} blocks cleared, {} passes, {} voxels updated in {:?}{}",
+        stats.blocks_cleared,
+        stats.passes.len(),
+        stats.updated_voxels(),
+        stats.cpu_time,
+        stats
+            .gpu_time
+            .map(|time| format!(" (GPU {time:?})"))
+            .unwrap_or_default()
     );
 
-    renderer
-        .borrow_mut()
-        .render_gif(&format!("{}/tsdf.gif", env!("CARGO_MANIFEST_DIR")));
+    if let Some(reason) = &stats.cpu_fallback {
+        eprintln!("GPU: {reason}, updated on the CPU");
+    }
+}
+
+fn print_event<const VPS: usize>(event: &EsdfEvent<VPS>, verbosity: Verbosity) {
+    let info = event.info();
+    let print = match event {
+        EsdfEvent::PassComplete { .. } | EsdfEvent::Converged { .. } => {
+            verbosity >= Verbosity::Passes
+        }
+        _ => verbosity >= Verbosity::Events,
+    };
+
+    if print {
+        println!(
+            "{event}:\t{} blocks\t{} voxels\t{:?}{}",
+            info.blocks.len(),
+            info.updated_voxels,
+            info.wall_time,
+            info.gpu_time
+                .map(|time| format!(" (GPU {time:?})"))
+                .unwrap_or_default()
+        );
+    }
+}
+
+/// Loads all maps upfront to fail before any work is done
+///
+/// Fails if the maps differ in resolution, they are integrated into the same layers.
+fn load_maps(args: &MapArgs) -> Result<Vec<(PathBuf, Map2d)>, Box<dyn Error>> {
+    let maps = args
+        .maps
+        .iter()
+        .map(|path| {
+            let map = io::load_map(path, args.voxel_size)
+                .map_err(|err| format!("{}: {err}", path.display()))?;
+            Ok((path.clone(), map))
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+    if let Some((first_path, first)) = maps.first() {
+        for (path, map) in &maps[1..] {
+            if map.resolution() != first.resolution() {
+                return Err(format!(
+                    "{}: resolution {} differs from {} of {}",
+                    path.display(),
+                    map.resolution(),
+                    first.resolution(),
+                    first_path.display()
+                )
+                .into());
+            }
+        }
+    }
+
+    Ok(maps)
+}
+
+fn format_from_extension(path: &Path) -> Result<ExportFormat, Box<dyn Error>> {
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase());
+
+    match extension.as_deref() {
+        Some("png") => Ok(ExportFormat::Png),
+        Some("csv") => Ok(ExportFormat::Csv),
+        _ => Err(format!("unknown export format of {}, see --format", path.display()).into()),
+    }
+}
+
+/// Distances of the z=0 plane over the allocated blocks, unknown voxels are gray
+fn distance_image<const VPS: usize>(esdf_layer: &EsdfLayer<VPS>) -> image::RgbImage {
+    let (Some(x_min), Some(y_min)) = (
+        esdf_layer
+            .allocated_blocks_iter()
+            .map(|index| index.x)
+            .min(),
+        esdf_layer
+            .allocated_blocks_iter()
+            .map(|index| index.y)
+            .min(),
+    ) else {
+        return image::RgbImage::new(0, 0);
+    };
+    let x_max = esdf_layer
+        .allocated_blocks_iter()
+        .map(|index| index.x)
+        .max();
+    let y_max = esdf_layer
+        .allocated_blocks_iter()
+        .map(|index| index.y)
+        .max();
+
+    let width = (x_max.unwrap() - x_min + 1) as u32 * VPS as u32;
+    let height = (y_max.unwrap() - y_min + 1) as u32 * VPS as u32;
+    let global_index = |x: u32, y: u32| {
+        GlobalIndex::<VPS>(Point3::new(
+            x_min as i64 * VPS as i64 + x as i64,
+            y_min as i64 * VPS as i64 + y as i64,
+            0,
+        ))
+    };
+
+    let distances: Vec<_> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| esdf_layer.distance_at_index(&global_index(x, y)).ok())
+        .collect();
+    let d_min = distances
+        .iter()
+        .flatten()
+        .copied()
+        .fold(Real::MAX, Real::min);
+    let d_max = distances
+        .iter()
+        .flatten()
+        .copied()
+        .fold(Real::MIN, Real::max);
+    let d_range = (d_max - d_min).max(Real::EPSILON);
+
+    image::RgbImage::from_fn(width, height, |x, y| {
+        let color = match distances[(y * width + x) as usize] {
+            Some(distance) => {
+                let color = rainbow_map((distance - d_min) / d_range);
+                [
+                    (color.x * 255.0) as u8,
+                    (color.y * 255.0) as u8,
+                    (color.z * 255.0) as u8,
+                ]
+            }
+            None => [220, 220, 220],
+        };
+        image::Rgb(color)
+    })
+}
+
+fn write_csv<const VPS: usize>(path: &Path, esdf_layer: &EsdfLayer<VPS>) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "x,y,z,distance")?;
+
+    let mut block_indices: Vec<_> = esdf_layer.allocated_blocks_iter().copied().collect();
+    block_indices.sort();
+
+    for block_index in block_indices {
+        let block = esdf_layer.block_by_index(&block_index).unwrap().read();
+        for (i, voxel) in block.as_slice().iter().enumerate() {
+            if let Some(distance) = voxel.distance() {
+                let global_index = GlobalIndex::from_block_and_local_lin_index(&block_index, i);
+                let p = esdf_layer.voxel_center(&global_index);
+                writeln!(writer, "{},{},{},{distance}", p.x, p.y, p.z)?;
+            }
+        }
+    }
+
+    writer.flush()
+}
+
+fn write_json<T: serde::Serialize + ?Sized>(path: &Path, value: &T) -> Result<(), Box<dyn Error>> {
+    let writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(writer, value)?;
+
+    Ok(())
+}
+
+/// Min., mean and max. time of each update over all runs
+fn print_bench(maps: &[PathBuf], all_stats: &[Vec<EsdfUpdateStats>]) {
+    println!("map\tmin\tmean\tmax\tpasses");
+
+    for (i, map) in maps.iter().enumerate() {
+        let times: Vec<Duration> = all_stats.iter().map(|stats| stats[i].cpu_time).collect();
+        let (Some(min), Some(max)) = (times.iter().min(), times.iter().max()) else {
+            continue;
+        };
+        let mean = times.iter().sum::<Duration>() / times.len() as u32;
+
+        println!(
+            "{}\t{min:?}\t{mean:?}\t{max:?}\t{}",
+            map.display(),
+            all_stats[0][i].passes.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verify_cli() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn parse_args() {
+        let cli = Cli::parse_from([
+            "esdf-vis",
+            "-vv",
+            "export",
+            "a.png",
+            "b.yaml",
+            "--vps",
+            "4",
+            "--backend",
+            "cpu",
+            "--voxel-size",
+            "0.05",
+            "-o",
+            "esdf.csv",
+        ]);
+        assert_eq!(Verbosity::new(&cli), Verbosity::Events);
+
+        let Command::Export { maps, output, .. } = cli.command else {
+            panic!("{:?}", cli.command);
+        };
+        assert_eq!(maps.maps, [PathBuf::from("a.png"), PathBuf::from("b.yaml")]);
+        assert_eq!(maps.vps, Vps::Vps4);
+        assert_eq!(maps.backend, Backend::Cpu);
+        assert_eq!(maps.voxel_size, 0.05);
+        assert_eq!(format_from_extension(&output).unwrap(), ExportFormat::Csv);
+
+        assert!(Cli::try_parse_from(["esdf-vis", "integrate", "a.png", "--vps", "5"]).is_err());
+        assert!(Cli::try_parse_from(["esdf-vis", "render"]).is_err());
+        assert!(Cli::try_parse_from(["esdf-vis", "scenario", "a.toml", "--events"]).is_err());
+        assert!(Cli::try_parse_from(["esdf-vis", "bench", "a.png", "--runs", "0"]).is_err());
+    }
+
+    #[test]
+    fn map_resolution() {
+        let dir = std::env::temp_dir().join(format!("esdf-vis-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("map.yaml"),
+            "image: map.pgm\nresolution: 0.05\norigin: [0.0, 0.0, 0.0]\nnegate: 0\n\
+             occupied_thresh: 0.65\nfree_thresh: 0.196\n",
+        )
+        .unwrap();
+        image::GrayImage::new(4, 4)
+            .save(dir.join("map.pgm"))
+            .unwrap();
+        image::RgbImage::new(4, 4)
+            .save(dir.join("map.png"))
+            .unwrap();
+
+        let load = |maps: &[&str], voxel_size: &str| {
+            let mut args = vec!["esdf-vis", "integrate", "--voxel-size", voxel_size];
+            args.extend(maps);
+            let Command::Integrate { maps, .. } = Cli::parse_from(args).command else {
+                unreachable!()
+            };
+            load_maps(&maps).map(|maps| maps[0].1.resolution())
+        };
+        let yaml = dir.join("map.yaml");
+        let png = dir.join("map.png");
+        let (yaml, png) = (yaml.to_str().unwrap(), png.to_str().unwrap());
+
+        // the voxel size only applies to images
+        let resolutions = [
+            load(&[yaml], "1.0").ok(),
+            load(&[yaml, png], "0.05").ok(),
+            load(&[png], "0.1").ok(),
+        ];
+        let mismatch = load(&[yaml, png], "1.0");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(resolutions, [Some(0.05), Some(0.05), Some(0.1)]);
+        assert!(mismatch.is_err());
+    }
 }
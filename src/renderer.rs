@@ -1,18 +1,22 @@
 use ab_glyph::{FontArc, PxScale};
 use image::{buffer::ConvertBuffer, Delay, RgbImage};
 use imageproc::drawing::draw_text_mut;
-use nalgebra::{point, Vector3};
+use nalgebra::point;
 
-use crate::core::{
-    color::rainbow_map,
-    index::{BlockIndex, GlobalIndex, VoxelIndex},
-    layer::Layer,
-    voxel::{Esdf, EsdfFlags, Tsdf},
+use crate::{
+    core::{
+        color::rainbow_map,
+        index::{BlockIndex, GlobalIndex, VoxelIndex},
+        layer::Layer,
+        voxel::{Esdf, EsdfFlags, Tsdf},
+    },
+    integrators::esdf::EsdfEvent,
 };
 
 static COLOR_OF_INTEREST: [u8; 3] = [255, 0, 255];
 static COLOR_GRID: [u8; 3] = [0, 0, 0];
 static COLOR_TSDF: [u8; 3] = [150, 150, 150];
+static COLOR_UNKNOWN: [u8; 3] = [220, 220, 220];
 
 pub struct Renderer {
     frames: Vec<(RgbImage, std::time::Duration)>,
@@ -32,6 +36,29 @@ impl Renderer {
         }
     }
 
+    /// Renders a frame for the phases of the ESDF update worth showing
+    pub fn render_event<const VPS: usize>(
+        &mut self,
+        tsdf_layer: &Layer<Tsdf, VPS>,
+        esdf_layer: &Layer<Esdf, VPS>,
+        event: &EsdfEvent<VPS>,
+    ) {
+        let delay = match event {
+            EsdfEvent::MapUpdated(_) => 500,
+            EsdfEvent::SiteClear(_) | EsdfEvent::Sweep { .. } | EsdfEvent::Propagate { .. } => 50,
+            EsdfEvent::PassComplete { .. } => 1000,
+            EsdfEvent::Transfer(_) | EsdfEvent::Converged { .. } => return,
+        };
+
+        self.render_tsdf_layer(
+            tsdf_layer,
+            esdf_layer,
+            event.info().blocks,
+            &event.to_string(),
+            Some(std::time::Duration::from_millis(delay)),
+        );
+    }
+
     pub fn render_tsdf_layer<const VPS: usize>(
         &mut self,
         tsdf_layer: &Layer<Tsdf, VPS>,
@@ -40,29 +67,41 @@ impl Renderer {
         op: &str,
         duration: Option<std::time::Duration>,
     ) {
-        let mut blocks_in_x = 0;
-        let mut blocks_in_y = 0;
+        // block indices may be negative, the image starts at the smallest one
+        let blocks = || {
+            tsdf_layer
+                .allocated_blocks_iter()
+                .chain(esdf_layer.allocated_blocks_iter())
+        };
+        let x_min = blocks().map(|index| index.x).min().unwrap_or_default();
+        let y_min = blocks().map(|index| index.y).min().unwrap_or_default();
+        let blocks_in_x = blocks().map(|index| index.x).max().unwrap_or_default() - x_min + 1;
+        let blocks_in_y = blocks().map(|index| index.y).max().unwrap_or_default() - y_min + 1;
 
-        for block in tsdf_layer.allocated_blocks_iter() {
-            blocks_in_x = blocks_in_x.max(block.coords.x);
-            blocks_in_y = blocks_in_y.max(block.coords.y);
-        }
+        // pixel of a voxel, each block is preceded by a grid line
+        let pixel = |index: &GlobalIndex<VPS>| {
+            let block_index = index.block_index();
+            (
+                (index.x - (x_min as i64 * VPS as i64) + (block_index.x - x_min) as i64 + 1) as u32,
+                (index.y - (y_min as i64 * VPS as i64) + (block_index.y - y_min) as i64 + 1) as u32,
+            )
+        };
 
         // make space for all blocks + block boundaries
         let bottom_padding = 24;
-        let img_width = (blocks_in_x + 1) * VPS as i32 + blocks_in_x + 2;
-        let img_height = (blocks_in_y + 1) * VPS as i32 + blocks_in_y + 2 + bottom_padding;
+        let img_width = blocks_in_x * VPS as i32 + blocks_in_x + 1;
+        let img_height = blocks_in_y * VPS as i32 + blocks_in_y + 1 + bottom_padding;
 
         let mut img = image::RgbImage::new(img_width as u32, img_height as u32);
         img.fill(255);
 
         // render block boundaries (block grid)
-        for bx in 0..=blocks_in_x + 1 {
+        for bx in 0..=blocks_in_x {
             for y in 0..img.height() - bottom_padding as u32 {
                 img.get_pixel_mut((bx * VPS as i32 + bx) as u32, y).0 = COLOR_GRID;
             }
         }
-        for by in 0..=blocks_in_y + 1 {
+        for by in 0..=blocks_in_y {
             for x in 0..img.width() {
                 img.get_pixel_mut(x, (by * VPS as i32 + by) as u32).0 = COLOR_GRID;
             }
@@ -88,24 +127,27 @@ impl Renderer {
 
                                 let color = if self.sites {
                                     rainbow_map(
-                                        Vector3::from_column_slice(&voxel.site_block_index)
+                                        voxel
+                                            .site_block_index::<VPS>()
+                                            .coords
                                             .cast::<f32>()
                                             .norm_squared()
                                             / 16.0,
                                     )
                                 } else {
-                                    rainbow_map(((voxel.distance - d_min) / d_range) as f32)
+                                    rainbow_map((voxel.distance - d_min) / d_range)
                                 };
 
-                                img.get_pixel_mut(
-                                    (index.x + block_index.x as i64) as u32 + 1,
-                                    (index.y + block_index.y as i64) as u32 + 1,
-                                )
-                                .0 = [
-                                    (color.x * 255.0) as u8,
-                                    (color.y * 255.0) as u8,
-                                    (color.z * 255.0) as u8,
-                                ];
+                                let (x, y) = pixel(&index);
+                                img.get_pixel_mut(x, y).0 = if voxel.is_known() {
+                                    [
+                                        (color.x * 255.0) as u8,
+                                        (color.y * 255.0) as u8,
+                                        (color.z * 255.0) as u8,
+                                    ]
+                                } else {
+                                    COLOR_UNKNOWN
+                                };
                             }
                         }
                     }
@@ -126,11 +168,8 @@ impl Renderer {
                             let index =
                                 GlobalIndex::from_block_and_voxel_index(block_index, &voxel_index);
 
-                            img.get_pixel_mut(
-                                (index.x + block_index.x as i64 + 1) as u32,
-                                (index.y + block_index.y as i64 + 1) as u32,
-                            )
-                            .0 = COLOR_TSDF;
+                            let (x, y) = pixel(&index);
+                            img.get_pixel_mut(x, y).0 = COLOR_TSDF;
                         }
                     }
                 }
@@ -139,31 +178,15 @@ impl Renderer {
 
         // render frame around block of interest
         for block_of_interest in blocks_of_interest {
-            for vx in 0..=VPS + 1 {
-                img.get_pixel_mut(
-                    (vx + block_of_interest.x as usize * VPS + block_of_interest.x as usize) as u32,
-                    (block_of_interest.y as usize * VPS + block_of_interest.y as usize) as u32,
-                )
-                .0 = COLOR_OF_INTEREST;
-                img.get_pixel_mut(
-                    (vx + block_of_interest.x as usize * VPS + block_of_interest.x as usize) as u32,
-                    (block_of_interest.y as usize * VPS + block_of_interest.y as usize + VPS + 1)
-                        as u32,
-                )
-                .0 = COLOR_OF_INTEREST;
-            }
-            for vy in 0..=VPS + 1 {
-                img.get_pixel_mut(
-                    (block_of_interest.x as usize * VPS + block_of_interest.x as usize) as u32,
-                    (vy + block_of_interest.y as usize * VPS + block_of_interest.y as usize) as u32,
-                )
-                .0 = COLOR_OF_INTEREST;
-                img.get_pixel_mut(
-                    ((block_of_interest.x as usize + 1) * VPS + block_of_interest.x as usize + 1)
-                        as u32,
-                    (vy + block_of_interest.y as usize * VPS + block_of_interest.y as usize) as u32,
-                )
-                .0 = COLOR_OF_INTEREST;
+            let x = (block_of_interest.x - x_min) as u32 * (VPS as u32 + 1);
+            let y = (block_of_interest.y - y_min) as u32 * (VPS as u32 + 1);
+            let size = VPS as u32 + 1;
+
+            for v in 0..=size {
+                img.get_pixel_mut(x + v, y).0 = COLOR_OF_INTEREST;
+                img.get_pixel_mut(x + v, y + size).0 = COLOR_OF_INTEREST;
+                img.get_pixel_mut(x, y + v).0 = COLOR_OF_INTEREST;
+                img.get_pixel_mut(x + size, y + v).0 = COLOR_OF_INTEREST;
             }
         }
 
@@ -211,9 +234,44 @@ impl Renderer {
             .set_repeat(image::codecs::gif::Repeat::Infinite)
             .unwrap();
         encoder
-            .encode_frames(self.frames.iter().enumerate().map(|(i, (f, d))| {
+            .encode_frames(self.frames.iter().map(|(f, d)| {
                 image::Frame::from_parts(f.convert(), 0, 0, Delay::from_saturating_duration(*d))
             }))
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn negative_block_indices() {
+        let mut tsdf_layer = Layer::<Tsdf, 4>::new(1.0);
+        let mut esdf_layer = Layer::<Esdf, 4>::new(1.0);
+
+        let corner = BlockIndex::new(-2, -1, 0);
+        for index in [corner, BlockIndex::new(1, 0, 0)] {
+            tsdf_layer.allocate_block_by_index(&index);
+            esdf_layer.allocate_block_by_index(&index);
+        }
+
+        // a surface voxel in the first column of the lower left block
+        tsdf_layer
+            .block_by_index(&corner)
+            .unwrap()
+            .write()
+            .voxel_from_index_mut(&VoxelIndex(point![0, 1, 0]))
+            .weight = 1.0;
+
+        let mut renderer = Renderer::new(false);
+        renderer.render_tsdf_layer(&tsdf_layer, &esdf_layer, &[corner], "negative", None);
+
+        let (img, _) = &renderer.frames[0];
+        assert_eq!(img.width(), 4 * 4 + 5);
+        assert_eq!(img.height(), 2 * 4 + 3 + 24);
+        assert_eq!(img.get_pixel(1, 2).0, COLOR_TSDF);
+        assert_eq!(img.get_pixel(0, 0).0, COLOR_OF_INTEREST);
+        assert_eq!(img.get_pixel(5, 5).0, COLOR_OF_INTEREST);
+    }
+}
@@ -0,0 +1,606 @@
+//! Declarative sequences of map updates, e.g., to reproduce bugs
+//!
+//! A scenario is a TOML or JSON file with the layer and integrator settings followed by the
+//! steps, which a [`ScenarioRunner`] executes in order:
+//!
+//! ```toml
+//! voxel_size = 1.0
+//!
+//! [tsdf]
+//! max_weight = 1.0
+//!
+//! [esdf]
+//! planar = true
+//!
+//! [[steps]]
+//! step = "load_map"
+//! path = "map.png"
+//!
+//! [[steps]]
+//! step = "integrate"
+//!
+//! [[steps]]
+//! step = "update_esdf"
+//!
+//! [[steps]]
+//! step = "paint_rect"
+//! min = [10, 10]
+//! max = [20, 12]
+//!
+//! [[steps]]
+//! step = "integrate"
+//! observations = 4
+//!
+//! [[steps]]
+//! step = "update_esdf"
+//!
+//! [[steps]]
+//! step = "snapshot"
+//! esdf = "esdf.layer"
+//! ```
+//!
+//! Map edits only change the current map, the next `integrate` step brings them into the TSDF.
+//! Rectangles are in cells of the map, i.e., pixels of images.
+
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    core::{
+        index::BlockIndex,
+        map2d::{Map2d, PixelClass},
+        prelude::*,
+        serialization::LayerIoError,
+    },
+    integrators::{
+        esdf::{EsdfBackend, EsdfIntegrator, EsdfIntegratorConfig, EsdfUpdateStats},
+        tsdf::{TsdfIntegrator, TsdfIntegratorConfig},
+    },
+    io::{self, map_server::MapServerError},
+    renderer::Renderer,
+    EsdfLayer, TsdfLayer,
+};
+
+#[derive(Debug)]
+pub enum ScenarioError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Toml(toml::de::Error),
+    /// Scenario files are `.toml` or `.json`
+    UnknownFormat(PathBuf),
+    Map(MapServerError),
+    Layer(LayerIoError),
+    /// Map edits and integration need a map loaded or created before
+    NoMap,
+    /// The resolution of a loaded map differs from the voxel size of the scenario
+    Resolution {
+        voxel_size: Real,
+        resolution: Real,
+    },
+    /// Error of the step with this index
+    Step(usize, Box<ScenarioError>),
+}
+
+impl Display for ScenarioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScenarioError::Io(err) => write!(f, "io error: {err}"),
+            ScenarioError::Json(err) => write!(f, "invalid scenario json: {err}"),
+            ScenarioError::Toml(err) => write!(f, "invalid scenario toml: {err}"),
+            ScenarioError::UnknownFormat(path) => {
+                write!(f, "unknown scenario format of {}", path.display())
+            }
+            ScenarioError::Map(err) => write!(f, "map: {err}"),
+            ScenarioError::Layer(err) => write!(f, "layer: {err}"),
+            ScenarioError::NoMap => write!(f, "no map, see load_map and new_map"),
+            ScenarioError::Resolution {
+                voxel_size,
+                resolution,
+            } => write!(
+                f,
+                "the map resolution {resolution} differs from the voxel size {voxel_size}"
+            ),
+            ScenarioError::Step(index, err) => write!(f, "step {}: {err}", index + 1),
+        }
+    }
+}
+
+impl std::error::Error for ScenarioError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScenarioError::Io(err) => Some(err),
+            ScenarioError::Json(err) => Some(err),
+            ScenarioError::Toml(err) => Some(err),
+            ScenarioError::Map(err) => Some(err),
+            ScenarioError::Layer(err) => Some(err),
+            ScenarioError::Step(_, err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ScenarioError {
+    fn from(err: std::io::Error) -> Self {
+        ScenarioError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ScenarioError {
+    fn from(err: serde_json::Error) -> Self {
+        ScenarioError::Json(err)
+    }
+}
+
+impl From<toml::de::Error> for ScenarioError {
+    fn from(err: toml::de::Error) -> Self {
+        ScenarioError::Toml(err)
+    }
+}
+
+impl From<MapServerError> for ScenarioError {
+    fn from(err: MapServerError) -> Self {
+        ScenarioError::Map(err)
+    }
+}
+
+impl From<LayerIoError> for ScenarioError {
+    fn from(err: LayerIoError) -> Self {
+        ScenarioError::Layer(err)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    /// Edge length of a voxel, also the size of a cell of images and new maps.
+    /// `map_server` YAML files need to have the same resolution
+    #[serde(default = "default_voxel_size")]
+    pub voxel_size: Real,
+    #[serde(default)]
+    pub tsdf: TsdfIntegratorConfig,
+    #[serde(default)]
+    pub esdf: EsdfIntegratorConfig,
+    pub steps: Vec<Step>,
+    /// Map paths are relative to this directory, the one of the scenario file
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+fn default_voxel_size() -> Real {
+    1.0
+}
+
+fn default_observations() -> usize {
+    1
+}
+
+/// A step of a [`Scenario`], tagged by `step` in the files
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Step {
+    /// Replaces the current map by a `map_server` YAML file or an image, see [`io::load_map`]
+    LoadMap { path: PathBuf },
+    /// Replaces the current map by a free map
+    NewMap { width: u32, height: u32 },
+    /// Adds an obstacle from `min` (inclusive) to `max` (exclusive) to the current map
+    PaintRect { min: [u32; 2], max: [u32; 2] },
+    /// Frees the cells from `min` (inclusive) to `max` (exclusive) of the current map
+    EraseRect { min: [u32; 2], max: [u32; 2] },
+    /// Integrates the current map into the TSDF, repeated observations outweigh older maps
+    Integrate {
+        #[serde(default = "default_observations")]
+        observations: usize,
+    },
+    /// Updates the ESDF from the blocks integrated since the last update
+    UpdateEsdf,
+    /// Saves the layers, paths are relative to the output directory of the runner
+    Snapshot {
+        label: Option<String>,
+        esdf: Option<PathBuf>,
+        tsdf: Option<PathBuf>,
+    },
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::LoadMap { path } => write!(
+                f,
+                "load {}",
+                path.file_name().unwrap_or_default().to_string_lossy()
+            ),
+            Step::NewMap { width, height } => write!(f, "new map {width}x{height}"),
+            Step::PaintRect { min, max } => {
+                write!(f, "paint {min:?}..{max:?}")
+            }
+            Step::EraseRect { min, max } => {
+                write!(f, "erase {min:?}..{max:?}")
+            }
+            Step::Integrate { observations } => write!(f, "integrate x{observations}"),
+            Step::UpdateEsdf => write!(f, "update ESDF"),
+            Step::Snapshot { label, .. } => {
+                write!(f, "{}", label.as_deref().unwrap_or("snapshot"))
+            }
+        }
+    }
+}
+
+impl Scenario {
+    /// Loads a `.toml` or `.json` scenario file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ScenarioError> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        let parse = match extension.as_deref() {
+            Some("toml") => Self::from_toml,
+            Some("json") => Self::from_json,
+            _ => return Err(ScenarioError::UnknownFormat(path.to_path_buf())),
+        };
+
+        let mut scenario = parse(&std::fs::read_to_string(path)?)?;
+        scenario.base_dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+
+        Ok(scenario)
+    }
+
+    pub fn from_toml(source: &str) -> Result<Self, ScenarioError> {
+        Ok(toml::from_str(source)?)
+    }
+
+    pub fn from_json(source: &str) -> Result<Self, ScenarioError> {
+        Ok(serde_json::from_str(source)?)
+    }
+}
+
+/// Executes the steps of a scenario through the TSDF and ESDF integrators
+pub struct ScenarioRunner<const VPS: usize> {
+    tsdf_layer: TsdfLayer<VPS>,
+    esdf_layer: EsdfLayer<VPS>,
+    tsdf_integrator: TsdfIntegrator,
+    esdf_integrator: EsdfIntegrator<VPS>,
+    voxel_size: Real,
+    base_dir: PathBuf,
+    output_dir: PathBuf,
+    /// Current map
+    map: Option<Map2d>,
+    /// Blocks integrated since the last ESDF update
+    updated_blocks: BTreeSet<BlockIndex<VPS>>,
+    stats: Vec<EsdfUpdateStats>,
+    renderer: Option<Renderer>,
+    render_events: bool,
+}
+
+impl<const VPS: usize> ScenarioRunner<VPS> {
+    pub fn new(scenario: &Scenario, backend: Box<dyn EsdfBackend<VPS>>) -> Self {
+        Self {
+            tsdf_layer: TsdfLayer::new(scenario.voxel_size),
+            esdf_layer: EsdfLayer::new(scenario.voxel_size),
+            tsdf_integrator: TsdfIntegrator::new(scenario.tsdf.clone()),
+            esdf_integrator: EsdfIntegrator::new(scenario.esdf.clone(), backend),
+            voxel_size: scenario.voxel_size,
+            base_dir: scenario.base_dir.clone(),
+            output_dir: PathBuf::from("."),
+            map: None,
+            updated_blocks: BTreeSet::new(),
+            stats: vec![],
+            renderer: None,
+            render_events: false,
+        }
+    }
+
+    /// Renders a frame after each step and, if `events`, for the phases of the ESDF updates
+    pub fn record(&mut self, renderer: Renderer, events: bool) {
+        self.renderer = Some(renderer);
+        self.render_events = events;
+    }
+
+    /// Directory of the layers saved by snapshots, the working directory by default
+    pub fn set_output_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.output_dir = dir.as_ref().to_path_buf();
+    }
+
+    pub fn tsdf_layer(&self) -> &TsdfLayer<VPS> {
+        &self.tsdf_layer
+    }
+
+    pub fn esdf_layer(&self) -> &EsdfLayer<VPS> {
+        &self.esdf_layer
+    }
+
+    /// Statistics of the ESDF updates so far
+    pub fn stats(&self) -> &[EsdfUpdateStats] {
+        &self.stats
+    }
+
+    /// The renderer passed to [`Self::record`]
+    pub fn renderer_mut(&mut self) -> Option<&mut Renderer> {
+        self.renderer.as_mut()
+    }
+
+    /// Executes all steps of `scenario`, stops at the first failing step
+    ///
+    /// `on_step` gets the index of each step and the runner after the step.
+    pub fn run(
+        &mut self,
+        scenario: &Scenario,
+        mut on_step: impl FnMut(usize, &Step, &Self),
+    ) -> Result<(), ScenarioError> {
+        for (index, step) in scenario.steps.iter().enumerate() {
+            self.step(step)
+                .map_err(|err| ScenarioError::Step(index, Box::new(err)))?;
+            on_step(index, step, self);
+        }
+
+        Ok(())
+    }
+
+    pub fn step(&mut self, step: &Step) -> Result<(), ScenarioError> {
+        firestorm::profile_method!("step");
+
+        match step {
+            Step::LoadMap { path } => {
+                let map = io::load_map(self.base_dir.join(path), self.voxel_size)?;
+                if map.resolution() != self.voxel_size {
+                    return Err(ScenarioError::Resolution {
+                        voxel_size: self.voxel_size,
+                        resolution: map.resolution(),
+                    });
+                }
+
+                self.map = Some(map);
+            }
+            Step::NewMap { width, height } => {
+                self.map = Some(Map2d::new(
+                    *width,
+                    *height,
+                    vec![PixelClass::Free; (width * height) as usize],
+                    self.voxel_size,
+                    Point3::origin(),
+                ));
+            }
+            Step::PaintRect { min, max } => {
+                self.map_mut()?.fill_rect(*min, *max, PixelClass::Occupied);
+            }
+            Step::EraseRect { min, max } => {
+                self.map_mut()?.fill_rect(*min, *max, PixelClass::Free);
+            }
+            Step::Integrate { observations } => {
+                let map = self.map.as_ref().ok_or(ScenarioError::NoMap)?;
+                for _ in 0..*observations {
+                    self.tsdf_integrator.integrate_map(
+                        &mut self.tsdf_layer,
+                        map,
+                        &mut self.updated_blocks,
+                    );
+                }
+            }
+            Step::UpdateEsdf => {
+                let updated_blocks = std::mem::take(&mut self.updated_blocks);
+                let mut renderer = self.renderer.as_mut().filter(|_| self.render_events);
+
+                let stats = self.esdf_integrator.update_blocks(
+                    &self.tsdf_layer,
+                    &mut self.esdf_layer,
+                    &updated_blocks,
+                    |event, tsdf_layer, esdf_layer| {
+                        if let Some(renderer) = &mut renderer {
+                            renderer.render_event(tsdf_layer, esdf_layer, event);
+                        }
+                    },
+                );
+                self.esdf_integrator.sync(&mut self.esdf_layer);
+                self.stats.push(stats);
+            }
+            Step::Snapshot { esdf, tsdf, .. } => {
+                if let Some(path) = esdf {
+                    self.esdf_layer.save(self.output_dir.join(path))?;
+                }
+                if let Some(path) = tsdf {
+                    self.tsdf_layer.save(self.output_dir.join(path))?;
+                }
+            }
+        }
+
+        if let Some(renderer) = &mut self.renderer {
+            renderer.render_tsdf_layer(
+                &self.tsdf_layer,
+                &self.esdf_layer,
+                &[],
+                &step.to_string(),
+                None,
+            );
+        }
+
+        Ok(())
+    }
+
+    fn map_mut(&mut self) -> Result<&mut Map2d, ScenarioError> {
+        self.map.as_mut().ok_or(ScenarioError::NoMap)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use nalgebra::point;
+
+    use super::*;
+    use crate::{core::index::GlobalIndex, integrators::esdf_cpu::CpuBackend};
+
+    const TOML: &str = r#"
+[tsdf]
+max_weight = 1.0
+
+[esdf]
+planar = true
+
+[[steps]]
+step = "new_map"
+width = 32
+height = 32
+
+[[steps]]
+step = "paint_rect"
+min = [12, 12]
+max = [20, 20]
+
+[[steps]]
+step = "integrate"
+
+[[steps]]
+step = "update_esdf"
+
+[[steps]]
+step = "erase_rect"
+min = [0, 0]
+max = [32, 32]
+
+[[steps]]
+step = "paint_rect"
+min = [24, 12]
+max = [28, 20]
+
+[[steps]]
+step = "integrate"
+observations = 4
+
+[[steps]]
+step = "update_esdf"
+
+[[steps]]
+step = "snapshot"
+label = "moved"
+"#;
+
+    #[test]
+    fn parse() {
+        let scenario = Scenario::from_toml(TOML).unwrap();
+        assert_eq!(scenario.voxel_size, 1.0);
+        assert_eq!(scenario.tsdf.max_weight, 1.0);
+        assert!(scenario.esdf.planar);
+        assert_eq!(scenario.steps.len(), 9);
+        assert_eq!(scenario.steps[2], Step::Integrate { observations: 1 });
+
+        let json = r#"{
+            "voxel_size": 0.5,
+            "steps": [
+                { "step": "load_map", "path": "map.png" },
+                { "step": "update_esdf" },
+                { "step": "snapshot", "esdf": "esdf.layer" }
+            ]
+        }"#;
+        let scenario = Scenario::from_json(json).unwrap();
+        assert_eq!(scenario.voxel_size, 0.5);
+        assert_eq!(
+            scenario.steps,
+            [
+                Step::LoadMap {
+                    path: "map.png".into()
+                },
+                Step::UpdateEsdf,
+                Step::Snapshot {
+                    label: None,
+                    esdf: Some("esdf.layer".into()),
+                    tsdf: None
+                }
+            ]
+        );
+
+        assert!(Scenario::from_json(r#"{ "steps": [{ "step": "fly" }] }"#).is_err());
+        assert!(Scenario::from_json(r#"{ "steps": [{ "step": "new_map" }] }"#).is_err());
+        assert!(Scenario::from_json(r#"{ "steps": [], "planar": true }"#).is_err());
+    }
+
+    #[test]
+    fn run() {
+        let scenario = Scenario::from_toml(TOML).unwrap();
+        let mut runner = ScenarioRunner::<8>::new(&scenario, Box::new(CpuBackend::default()));
+        runner.record(Renderer::new(false), true);
+
+        let distance_at = |runner: &ScenarioRunner<8>, x, y| {
+            runner
+                .esdf_layer()
+                .distance_at_index(&GlobalIndex(point![x, y, 0]))
+                .unwrap()
+        };
+
+        for (index, step) in scenario.steps.iter().enumerate() {
+            runner.step(step).unwrap();
+
+            if index == 3 {
+                assert_eq!(distance_at(&runner, 5, 15), 7.0);
+            }
+        }
+
+        // the obstacle moved to the right
+        assert_eq!(distance_at(&runner, 5, 15), 19.0);
+        assert_eq!(runner.stats().len(), 2);
+        assert!(runner.stats().iter().all(|stats| stats.converged));
+    }
+
+    #[test]
+    fn errors() {
+        let scenario = Scenario::from_json(r#"{ "steps": [{ "step": "integrate" }] }"#).unwrap();
+        let mut runner = ScenarioRunner::<8>::new(&scenario, Box::new(CpuBackend::default()));
+
+        let err = runner.run(&scenario, |_, _, _| {}).unwrap_err();
+        assert!(
+            matches!(&err, ScenarioError::Step(0, err) if matches!(**err, ScenarioError::NoMap))
+        );
+        assert_eq!(err.to_string(), "step 1: no map, see load_map and new_map");
+
+        assert!(matches!(
+            Scenario::load("scenario.yaml"),
+            Err(ScenarioError::UnknownFormat(_))
+        ));
+        assert!(matches!(
+            Scenario::load("missing.toml"),
+            Err(ScenarioError::Io(_))
+        ));
+
+        // a map_server map finer than the voxels
+        let dir = std::env::temp_dir().join(format!("esdf-vis-scenario-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("map.yaml"),
+            "image: map.pgm\nresolution: 0.05\norigin: [0.0, 0.0, 0.0]\nnegate: 0\n\
+             occupied_thresh: 0.65\nfree_thresh: 0.196\n",
+        )
+        .unwrap();
+        image::GrayImage::new(4, 4)
+            .save(dir.join("map.pgm"))
+            .unwrap();
+
+        let load = |voxel_size| {
+            let scenario = Scenario {
+                voxel_size,
+                base_dir: dir.clone(),
+                ..Scenario::from_json(
+                    r#"{ "steps": [{ "step": "load_map", "path": "map.yaml" }] }"#,
+                )
+                .unwrap()
+            };
+            ScenarioRunner::<8>::new(&scenario, Box::new(CpuBackend::default()))
+                .run(&scenario, |_, _, _| {})
+        };
+        let results = [load(1.0), load(0.05)];
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let Err(ScenarioError::Step(0, err)) = &results[0] else {
+            panic!("{:?}", results[0]);
+        };
+        assert!(matches!(
+            **err,
+            ScenarioError::Resolution {
+                voxel_size: 1.0,
+                resolution: 0.05
+            }
+        ));
+        assert!(results[1].is_ok());
+    }
+}
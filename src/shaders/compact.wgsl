@@ -0,0 +1,88 @@
+// Collects the blocks updated by the propagation into the work list of the next iteration
+//
+// `const VPS: u32` is prepended when the pipeline is created
+
+// flags
+const Updated: u32          = 1u << 3;
+
+struct BlockInfo {
+    flags: u32,
+    updated_voxels: u32,
+    // changed since the last readback
+    modified: u32,
+    _pad: u32,
+};
+
+struct Params {
+    flags: u32,
+    max_distance: f32,
+    voxel_size: f32,
+    block_count: u32,
+    // workgroups per row of a dispatch, see WorkList
+    dispatch_width: u32,
+    _pad0: u32,
+    _pad1: u32,
+    _pad2: u32,
+};
+
+// slots of the blocks to process, followed by the indirect dispatch arguments
+// (rows of at most `params.dispatch_width` workgroups)
+struct WorkList {
+    count: atomic<u32>,
+    dispatch_x: atomic<u32>,
+    dispatch_y: atomic<u32>,
+    dispatch_z: u32,
+    slots: array<u32>,
+};
+
+// accumulated over a submission, cleared by the host
+struct Stats {
+    updated_voxels: atomic<u32>,
+};
+
+// bindings
+@group(0)
+@binding(2)
+var<storage, read_write> block_info: array<BlockInfo>;
+
+@group(0)
+@binding(4)
+var<uniform> params: Params;
+
+@group(0)
+@binding(6)
+var<storage, read_write> next_work_list: WorkList;
+
+@group(0)
+@binding(7)
+var<storage, read_write> stats: Stats;
+
+// main
+@compute
+@workgroup_size(64)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+) {
+    let slot = global_id.x + global_id.y * params.dispatch_width * 64u;
+    if (slot >= params.block_count) {
+        return;
+    }
+
+    let flags = block_info[slot].flags;
+    atomicAdd(&stats.updated_voxels, block_info[slot].updated_voxels);
+
+    // ready for the next iteration
+    block_info[slot].flags = 0u;
+    block_info[slot].updated_voxels = 0u;
+
+    if ((flags & Updated) > 0u) {
+        block_info[slot].modified = 1u;
+
+        let i = atomicAdd(&next_work_list.count, 1u);
+        next_work_list.slots[i] = slot;
+
+        // grow the dispatch to cover the new entry
+        atomicMax(&next_work_list.dispatch_x, min(i + 1u, params.dispatch_width));
+        atomicMax(&next_work_list.dispatch_y, i / params.dispatch_width + 1u);
+    }
+}
@@ -2,8 +2,8 @@
 // VPS=8  | still fast, fewer blocks
 // VPS=16 | slowest, does not work with shared memory (requires 80k, available 64k?)
 //
-const VPS: u32 = 8u;
-const VoxelSize: f32 = 1.0;
+// `const VPS: u32` and `const DIR_BLOCK_INDEX_OFFSET: u32` (1: x, 3: y, 5: z)
+// are prepended when the pipeline is created
 
 // flags
 const Observed: u32         = 1u << 0;
@@ -16,13 +16,15 @@ const SpilledYPlus: u32     = 1u << 6;
 const SpilledYMinus: u32    = 1u << 7;
 const SpilledZPlus: u32     = 1u << 8;
 const SpilledZMinus: u32    = 1u << 9;
+const Inside: u32           = 1u << 10;
+const Free: u32             = 1u << 11;
 
 const Invalid: u32          = 0xFFFFFFFF;
 
 const Stride: u32           = 7; // [self, x+, x-, y+, y-, z+, z-]
 
 struct EsdfVoxel {
-    site_block_index: vec3<i32>,
+    site_index: vec3<i32>,
     distance: f32,
     flags: u32,
 };
@@ -34,10 +36,31 @@ struct Block {
 struct BlockInfo {
     flags: atomic<u32>,
     updated_voxels: atomic<u32>,
+    // changed since the last readback
+    modified: atomic<u32>,
+    _pad: u32,
 };
 
-struct Settings {
-    dir_block_index_offset: u32,
+struct Params {
+    flags: u32,
+    max_distance: f32,
+    voxel_size: f32,
+    block_count: u32,
+    // workgroups per row of a dispatch, see WorkList
+    dispatch_width: u32,
+    _pad0: u32,
+    _pad1: u32,
+    _pad2: u32,
+};
+
+// slots of the blocks to process, followed by the indirect dispatch arguments
+// (rows of at most `params.dispatch_width` workgroups)
+struct WorkList {
+    count: u32,
+    dispatch_x: u32,
+    dispatch_y: u32,
+    dispatch_z: u32,
+    slots: array<u32>,
 };
 
 // bindings
@@ -45,16 +68,26 @@ struct Settings {
 @binding(0) 
 var<storage, read_write> block_voxels: array<Block>;
 
+// [self, x+, x-, y+, y-, z+, z-] per slot
 @group(0) 
 @binding(1) 
-var<storage, read_write> workgroup_block_indices: array<u32>;
+var<storage, read> neighbors: array<u32>;
 
 @group(0) 
 @binding(2) 
 var<storage, read_write> block_info: array<BlockInfo>;
 
-// push constants
-var<push_constant> settings: Settings;
+@group(0) 
+@binding(3) 
+var<storage, read> block_indices: array<vec4<i32>>;
+
+@group(0) 
+@binding(4) 
+var<uniform> params: Params;
+
+@group(0) 
+@binding(5) 
+var<storage, read> work_list: WorkList;
 
 // helpers
 fn voxel_index_to_lin(index: vec3<u32>) -> u32 {
@@ -69,25 +102,50 @@ fn is_observed(flags: u32) -> bool {
     return (flags & Observed) > 0;
 }
 
-fn update_voxel(block_index: u32, voxel_index: u32, parent_block_index: u32, parent_voxel_index: u32) -> bool {  
-    let voxel = &(block_voxels[block_index].voxels[voxel_index]);
-    let parent_voxel = &(block_voxels[parent_block_index].voxels[parent_voxel_index]);
+fn is_inside(flags: u32) -> bool {
+    return (flags & Inside) > 0;
+}
+
+// sites are seeds for both sides, the sides themselves are kept apart
+fn is_same_side(flags: u32, parent_flags: u32) -> bool {
+    return is_observed(parent_flags) || (is_inside(flags) == is_inside(parent_flags));
+}
+
+fn site_distance(global_index: vec3<i32>, site_index: vec3<i32>) -> f32 {
+    return length(vec3<f32>(global_index - site_index)) * params.voxel_size;
+}
+
+fn update_voxel(block_index: u32, voxel_index: vec3<u32>, parent_block_index: u32, parent_voxel_index: vec3<u32>) -> bool {  
+    let voxel = &(block_voxels[block_index].voxels[voxel_index_to_lin(voxel_index)]);
+    let parent_voxel = &(block_voxels[parent_block_index].voxels[voxel_index_to_lin(parent_voxel_index)]);
 
     let is_parent_fixed = is_fixed((*parent_voxel).flags);
     let is_voxel_observed = is_observed((*voxel).flags);
     let is_voxel_fixed = is_fixed((*voxel).flags);
 
-    if (is_parent_fixed && !is_voxel_observed) {
+    if (is_parent_fixed && !is_voxel_observed && is_same_side((*voxel).flags, (*parent_voxel).flags)) {
+        let global_index = block_indices[block_index].xyz * i32(VPS) + vec3<i32>(voxel_index);
+        var distance = site_distance(global_index, (*parent_voxel).site_index);
+
+        // stop propagating
+        if (distance > params.max_distance) {
+            return false;
+        }
+
+        if (is_inside((*voxel).flags)) {
+            distance = -distance;
+        }
+
         if (!is_voxel_fixed) {
-            (*voxel).distance = (*parent_voxel).distance + VoxelSize;
+            (*voxel).distance = distance;
             (*voxel).flags |= Fixed | HasSiteIndex;
-            (*voxel).site_block_index = (*parent_voxel).site_block_index;
+            (*voxel).site_index = (*parent_voxel).site_index;
 
             return true;
 
-        } else if ((*voxel).distance > (*parent_voxel).distance + VoxelSize) {
-            (*voxel).distance = (*parent_voxel).distance + VoxelSize;
-            (*voxel).site_block_index = (*parent_voxel).site_block_index;
+        } else if (abs((*voxel).distance) > abs(distance)) {
+            (*voxel).distance = distance;
+            (*voxel).site_index = (*parent_voxel).site_index;
 
             return true;
         }
@@ -103,9 +161,15 @@ fn main(
     @builtin(local_invocation_id) local_id: vec3<u32>,
     @builtin(workgroup_id) workgroup_id: vec3<u32>,
 ) {
-    let parent_block_index = workgroup_block_indices[workgroup_id.x * Stride];
-    let prop_p_block_index = workgroup_block_indices[workgroup_id.x * Stride + settings.dir_block_index_offset];
-    let prop_m_block_index = workgroup_block_indices[workgroup_id.x * Stride + settings.dir_block_index_offset +1];
+    let work_index = workgroup_id.x + workgroup_id.y * params.dispatch_width;
+    if (work_index >= work_list.count) {
+        return;
+    }
+
+    let row = work_list.slots[work_index] * Stride;
+    let parent_block_index = neighbors[row];
+    let prop_p_block_index = neighbors[row + DIR_BLOCK_INDEX_OFFSET];
+    let prop_m_block_index = neighbors[row + DIR_BLOCK_INDEX_OFFSET + 1];
 
     // voxel indexing
     // x
@@ -113,28 +177,27 @@ fn main(
     var index_m = vec3(VPS-1, local_id.x, local_id.y);
 
     // y?
-    if (settings.dir_block_index_offset == 3) {
+    if (DIR_BLOCK_INDEX_OFFSET == 3) {
         index_p = vec3(local_id.x, 0, local_id.y);
         index_m = vec3(local_id.x, VPS-1, local_id.y);
         
     // z?
-    } else if (settings.dir_block_index_offset == 5) {
+    } else if (DIR_BLOCK_INDEX_OFFSET == 5) {
         index_p = vec3(local_id.x, local_id.y, 0);
         index_m = vec3(local_id.x, local_id.y, VPS-1);
     }
 
-    var i_p = voxel_index_to_lin(index_p);
-    var i_m = voxel_index_to_lin(index_m);
-
     if (prop_p_block_index != Invalid) {
-        if (update_voxel(prop_p_block_index, i_p, parent_block_index, i_m)) {
+        if (update_voxel(prop_p_block_index, index_p, parent_block_index, index_m)) {
+            atomicAdd(&block_info[prop_p_block_index].updated_voxels, 1u);
             atomicOr(&block_info[prop_p_block_index].flags, Updated);
         }
     }
 
     
     if (prop_m_block_index != Invalid) {
-        if (update_voxel(prop_m_block_index, i_m, parent_block_index, i_p)) {
+        if (update_voxel(prop_m_block_index, index_m, parent_block_index, index_p)) {
+            atomicAdd(&block_info[prop_m_block_index].updated_voxels, 1u);
             atomicOr(&block_info[prop_m_block_index].flags, Updated);
         }
     }
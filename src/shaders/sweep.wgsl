@@ -1,9 +1,8 @@
 // VPS=4  | the fastest, many blocks
 // VPS=8  | still fast, fewer blocks
-// VPS=16 | slowest, does not work with shared memory (requires 80k, available 64k?)
+// VPS=16 | slowest, a block (128 KiB) exceeds the workgroup memory of most devices
 //
-const VPS: u32 = 8u;
-const VoxelSize: f32 = 1.0;
+// `const VPS: u32` is prepended when the pipeline is created
 
 // flags
 const Observed: u32         = 1u << 0;
@@ -16,12 +15,16 @@ const SpilledYPlus: u32     = 1u << 6;
 const SpilledYMinus: u32    = 1u << 7;
 const SpilledZPlus: u32     = 1u << 8;
 const SpilledZMinus: u32    = 1u << 9;
+const Inside: u32           = 1u << 10;
+const Free: u32             = 1u << 11;
+
+// param flags
+const ProcessZ: u32         = 1u << 0;
 
 struct EsdfVoxel {
-    site_block_index: vec3<i32>,
+    site_index: vec3<i32>,
     distance: f32,
     flags: u32,
-    
 };
 
 struct Block {
@@ -31,6 +34,31 @@ struct Block {
 struct BlockInfo {
     flags: atomic<u32>,
     updated_voxels: atomic<u32>,
+    // changed since the last readback
+    modified: atomic<u32>,
+    _pad: u32,
+};
+
+struct Params {
+    flags: u32,
+    max_distance: f32,
+    voxel_size: f32,
+    block_count: u32,
+    // workgroups per row of a dispatch, see WorkList
+    dispatch_width: u32,
+    _pad0: u32,
+    _pad1: u32,
+    _pad2: u32,
+};
+
+// slots of the blocks to process, followed by the indirect dispatch arguments
+// (rows of at most `params.dispatch_width` workgroups)
+struct WorkList {
+    count: u32,
+    dispatch_x: u32,
+    dispatch_y: u32,
+    dispatch_z: u32,
+    slots: array<u32>,
 };
 
 // vars
@@ -39,9 +67,21 @@ struct BlockInfo {
 var<storage, read_write> block_voxels: array<Block>;
 
 @group(0) 
-@binding(1) 
+@binding(2) 
 var<storage, read_write> block_info: array<BlockInfo>;
 
+@group(0) 
+@binding(3) 
+var<storage, read> block_indices: array<vec4<i32>>;
+
+@group(0) 
+@binding(4) 
+var<uniform> params: Params;
+
+@group(0) 
+@binding(5) 
+var<storage, read> work_list: WorkList;
+
 var<workgroup> voxel_data_wg: array<EsdfVoxel, (VPS*VPS*VPS)>;
 
 // helpers
@@ -57,21 +97,45 @@ fn is_observed(flags: u32) -> bool {
     return (flags & Observed) > 0;
 }
 
-fn update_voxel(voxel_index: u32, parent_voxel_index: u32) -> bool {  
-    let voxel = &(voxel_data_wg[voxel_index]);
-    let parent_voxel = &(voxel_data_wg[parent_voxel_index]);
+fn is_inside(flags: u32) -> bool {
+    return (flags & Inside) > 0;
+}
+
+// sites are seeds for both sides, the sides themselves are kept apart
+fn is_same_side(flags: u32, parent_flags: u32) -> bool {
+    return is_observed(parent_flags) || (is_inside(flags) == is_inside(parent_flags));
+}
+
+fn site_distance(global_index: vec3<i32>, site_index: vec3<i32>) -> f32 {
+    return length(vec3<f32>(global_index - site_index)) * params.voxel_size;
+}
+
+fn update_voxel(block_origin: vec3<i32>, index: vec3<u32>, parent_index: vec3<u32>) -> bool {  
+    let voxel = &(voxel_data_wg[index_to_lin(index)]);
+    let parent_voxel = &(voxel_data_wg[index_to_lin(parent_index)]);
+
+    if (is_fixed((*parent_voxel).flags) && !is_observed((*voxel).flags) && is_same_side((*voxel).flags, (*parent_voxel).flags)) {
+        var distance = site_distance(block_origin + vec3<i32>(index), (*parent_voxel).site_index);
+
+        // stop propagating
+        if (distance > params.max_distance) {
+            return false;
+        }
+
+        if (is_inside((*voxel).flags)) {
+            distance = -distance;
+        }
 
-    if (is_fixed((*parent_voxel).flags) && !is_observed((*voxel).flags)) {
         if (!is_fixed((*voxel).flags)) {
-            (*voxel).distance = (*parent_voxel).distance + VoxelSize;
+            (*voxel).distance = distance;
             (*voxel).flags |= Fixed | HasSiteIndex;
-            (*voxel).site_block_index = (*parent_voxel).site_block_index;
+            (*voxel).site_index = (*parent_voxel).site_index;
 
             return true;
 
-        } else if ((*voxel).distance > (*parent_voxel).distance + VoxelSize) {
-            (*voxel).distance = (*parent_voxel).distance + VoxelSize;
-            (*voxel).site_block_index = (*parent_voxel).site_block_index;
+        } else if (abs((*voxel).distance) > abs(distance)) {
+            (*voxel).distance = distance;
+            (*voxel).site_index = (*parent_voxel).site_index;
 
             return true;
         }
@@ -87,7 +151,13 @@ fn main(
     @builtin(local_invocation_id) local_id: vec3<u32>,
     @builtin(workgroup_id) workgroup_id: vec3<u32>,
 ) {
-    let block_id = workgroup_id.x;
+    let work_index = workgroup_id.x + workgroup_id.y * params.dispatch_width;
+    if (work_index >= work_list.count) {
+        return;
+    }
+
+    let block_id = work_list.slots[work_index];
+    let block_origin = block_indices[block_id].xyz * i32(VPS);
 
     // note: from tests this improves
     // performance by a factor of 1.5
@@ -103,10 +173,10 @@ fn main(
 
     // x+
     for (var w: u32 = 1; w < VPS; w++) {
-        let i = index_to_lin(vec3(w, local_id.x, local_id.y));
-        let i_p = index_to_lin(vec3(w-1, local_id.x, local_id.y));
+        let i = vec3(w, local_id.x, local_id.y);
+        let i_p = vec3(w-1, local_id.x, local_id.y);
 
-        if (update_voxel(i, i_p)) {
+        if (update_voxel(block_origin, i, i_p)) {
             atomicAdd(&block_info[block_id].updated_voxels, 1u);
             atomicOr(&block_info[block_id].flags, SpilledXPLus);
         }
@@ -116,10 +186,10 @@ fn main(
 
     // x-
     for (var w: u32 = VPS - 1; w > 0; w--) {
-        let i = index_to_lin(vec3(w-1, local_id.x, local_id.y));
-        let i_p = index_to_lin(vec3(w, local_id.x, local_id.y));
+        let i = vec3(w-1, local_id.x, local_id.y);
+        let i_p = vec3(w, local_id.x, local_id.y);
 
-        if (update_voxel(i, i_p)) {
+        if (update_voxel(block_origin, i, i_p)) {
             atomicAdd(&block_info[block_id].updated_voxels, 1u);
             atomicOr(&block_info[block_id].flags, SpilledXMinus);
         }
@@ -129,10 +199,10 @@ fn main(
 
     // y+
     for (var w: u32 = 1; w < VPS; w++) {
-        let i = index_to_lin(vec3(local_id.x, w, local_id.y));
-        let i_p = index_to_lin(vec3(local_id.x, w-1, local_id.y));
+        let i = vec3(local_id.x, w, local_id.y);
+        let i_p = vec3(local_id.x, w-1, local_id.y);
 
-        if (update_voxel(i, i_p)) {
+        if (update_voxel(block_origin, i, i_p)) {
             atomicAdd(&block_info[block_id].updated_voxels, 1u);
             atomicOr(&block_info[block_id].flags, SpilledYPlus);
         }
@@ -142,16 +212,44 @@ fn main(
 
     // y-
     for (var w: u32 = VPS - 1; w > 0; w--) {
-        let i = index_to_lin(vec3(local_id.x, w-1, local_id.y));
-        let i_p = index_to_lin(vec3(local_id.x, w, local_id.y));
+        let i = vec3(local_id.x, w-1, local_id.y);
+        let i_p = vec3(local_id.x, w, local_id.y);
 
-        if (update_voxel(i, i_p)) {
+        if (update_voxel(block_origin, i, i_p)) {
             atomicAdd(&block_info[block_id].updated_voxels, 1u);
             atomicOr(&block_info[block_id].flags, SpilledYMinus);
         }
     }
 
     workgroupBarrier();
+
+    if ((params.flags & ProcessZ) > 0) {
+        // z+
+        for (var w: u32 = 1; w < VPS; w++) {
+            let i = vec3(local_id.x, local_id.y, w);
+            let i_p = vec3(local_id.x, local_id.y, w-1);
+
+            if (update_voxel(block_origin, i, i_p)) {
+                atomicAdd(&block_info[block_id].updated_voxels, 1u);
+                atomicOr(&block_info[block_id].flags, SpilledZPlus);
+            }
+        }
+
+        workgroupBarrier();
+
+        // z-
+        for (var w: u32 = VPS - 1; w > 0; w--) {
+            let i = vec3(local_id.x, local_id.y, w-1);
+            let i_p = vec3(local_id.x, local_id.y, w);
+
+            if (update_voxel(block_origin, i, i_p)) {
+                atomicAdd(&block_info[block_id].updated_voxels, 1u);
+                atomicOr(&block_info[block_id].flags, SpilledZMinus);
+            }
+        }
+
+        workgroupBarrier();
+    }
     
     // writeback (shared to global memory)
     for (var w: u32 = 0; w < VPS; w++) {
@@ -159,5 +257,7 @@ fn main(
 
         block_voxels[block_id].voxels[i] = voxel_data_wg[i];
     }
+
+    atomicStore(&block_info[block_id].modified, 1u);
 }
 
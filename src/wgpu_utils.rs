@@ -1,664 +1,1081 @@
-use wgpu::{include_wgsl, util::DeviceExt, Device, PushConstantRange, Queue, RequestDeviceError};
+use bitflags::bitflags;
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    fmt::Display,
+    ops::ControlFlow,
+    time::{Duration, Instant},
+};
+
+use wgpu::{Device, Queue, RequestDeviceError};
 
 use crate::core::{
-    block::Block,
+    index::{BlockIndex, BlockMap},
+    layer::Layer,
     voxel::{Esdf, EsdfFlags},
 };
 
-pub async fn create_adapter() -> Result<(Device, Queue), RequestDeviceError> {
+/// How [`request_device`] selects the adapter
+#[derive(Debug, Clone)]
+pub struct DeviceOptions {
+    pub backends: wgpu::Backends,
+    pub power_preference: wgpu::PowerPreference,
+    /// Only consider the fallback adapter (software rendering, e.g. lavapipe)
+    pub force_fallback_adapter: bool,
+    /// Fail if the adapter cannot measure GPU timings instead of running without them
+    pub require_timestamps: bool,
+}
+
+impl Default for DeviceOptions {
+    fn default() -> Self {
+        Self {
+            backends: wgpu::Backends::all(),
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter: false,
+            require_timestamps: false,
+        }
+    }
+}
+
+/// Device and queue selected by [`request_device`]
+pub struct GpuDevice {
+    pub device: Device,
+    pub queue: Queue,
+    pub info: wgpu::AdapterInfo,
+    /// GPU timings are measured with timestamp queries
+    pub timestamps: bool,
+}
+
+/// No device suitable for the ESDF update, use the CPU backend instead
+#[derive(Debug)]
+pub enum DeviceError {
+    /// Neither a hardware nor a fallback adapter is available
+    NoAdapter,
+    /// The adapter cannot run the compute shaders
+    MissingCapabilities(wgpu::DownlevelFlags),
+    /// The adapter lacks features required by the [`DeviceOptions`]
+    MissingFeatures(wgpu::Features),
+    RequestDevice(RequestDeviceError),
+}
+
+impl Display for DeviceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceError::NoAdapter => write!(f, "no GPU adapter available"),
+            DeviceError::MissingCapabilities(flags) => {
+                write!(f, "the adapter does not support {flags:?}")
+            }
+            DeviceError::MissingFeatures(features) => {
+                write!(f, "the adapter does not support {features:?}")
+            }
+            DeviceError::RequestDevice(err) => write!(f, "cannot create the device: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+impl From<RequestDeviceError> for DeviceError {
+    fn from(value: RequestDeviceError) -> Self {
+        DeviceError::RequestDevice(value)
+    }
+}
+
+/// Selects a device for the ESDF update
+///
+/// Falls back to the software adapter if there is no hardware adapter.
+/// Timestamps are only enabled if the adapter supports them.
+pub async fn request_device(options: &DeviceOptions) -> Result<GpuDevice, DeviceError> {
     let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-        backends: wgpu::Backends::all(),
+        backends: options.backends,
         ..Default::default()
     });
 
-    let adapter = instance
-        .request_adapter(&wgpu::RequestAdapterOptions {
-            power_preference: wgpu::PowerPreference::HighPerformance,
-            force_fallback_adapter: false,
+    let request_adapter = |force_fallback_adapter| {
+        instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: options.power_preference,
+            force_fallback_adapter,
             compatible_surface: None,
         })
-        .await
-        .expect("cannot get adapter");
+    };
+
+    let mut adapter = request_adapter(options.force_fallback_adapter).await;
+    if adapter.is_none() && !options.force_fallback_adapter {
+        adapter = request_adapter(true).await;
+    }
+    let adapter = adapter.ok_or(DeviceError::NoAdapter)?;
+
+    // the work lists are dispatched indirectly
+    let required_flags =
+        wgpu::DownlevelFlags::COMPUTE_SHADERS | wgpu::DownlevelFlags::INDIRECT_EXECUTION;
+    let missing_flags = required_flags - adapter.get_downlevel_capabilities().flags;
+    if !missing_flags.is_empty() {
+        return Err(DeviceError::MissingCapabilities(missing_flags));
+    }
+
+    let timestamps = adapter.features().contains(wgpu::Features::TIMESTAMP_QUERY);
+    if options.require_timestamps && !timestamps {
+        return Err(DeviceError::MissingFeatures(
+            wgpu::Features::TIMESTAMP_QUERY,
+        ));
+    }
+
+    let mut required_features = wgpu::Features::empty();
+    required_features.set(wgpu::Features::TIMESTAMP_QUERY, timestamps);
 
-    adapter
+    // the block pool is sized from these limits, take what the adapter offers
+    let (device, queue) = adapter
         .request_device(
             &wgpu::DeviceDescriptor {
                 label: None,
-                required_features: wgpu::Features::TIMESTAMP_QUERY | wgpu::Features::PUSH_CONSTANTS,
-                required_limits: wgpu::Limits {
-                    max_storage_buffer_binding_size: (2048 << 20) - 1, // 2GB,
-                    max_buffer_size: (2048 << 20) - 1,                 // 2GB
-                    max_push_constant_size: 128,
-                    ..Default::default()
-                },
+                required_features,
+                required_limits: adapter.limits(),
             },
             None,
         )
-        .await
+        .await?;
+
+    Ok(GpuDevice {
+        device,
+        queue,
+        info: adapter.get_info(),
+        timestamps,
+    })
 }
 
-#[derive(Debug, Clone, Copy, Default, bytemuck::Pod, bytemuck::Zeroable)]
-#[repr(C, align(16))]
-pub struct TestData {
-    pos: [i32; 4],
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GpuError {
+    /// The sweep keeps a whole block in workgroup memory
+    WorkgroupStorageExceeded {
+        vps: usize,
+        required: u32,
+        available: u32,
+    },
+    /// One invocation per voxel of a block face
+    WorkgroupSizeExceeded {
+        vps: usize,
+        required: u32,
+        available: u32,
+    },
+    /// The blocks of a layer do not fit the block pool at once
+    TooManyBlocks { required: usize, available: usize },
 }
 
-pub struct GpuSweep {
-    shader_module: wgpu::ShaderModule,
-    bind_group_layout: wgpu::BindGroupLayout,
-    pipeline_layout: wgpu::PipelineLayout,
-    compute_pipeline: wgpu::ComputePipeline,
-    voxel_storage_buffer: wgpu::Buffer,
-    block_info_storage_buffer: wgpu::Buffer,
-    timestamp_query_buffer: wgpu::Buffer,
-    bind_group: wgpu::BindGroup,
-    voxel_readback_buffer: wgpu::Buffer,
-    block_info_readback_buffer: wgpu::Buffer,
-    timestamp_readback_buffer: wgpu::Buffer,
-    timestamp_query_set: wgpu::QuerySet,
-}
-
-impl GpuSweep {
-    pub fn new(device: &Device, queue: &mut Queue) -> Self {
-        let shader_module = device.create_shader_module(include_wgsl!("shaders/sweep.wgsl"));
-
-        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: None,
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    count: None,
-                    ty: wgpu::BindingType::Buffer {
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                        ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    },
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    count: None,
-                    ty: wgpu::BindingType::Buffer {
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                        ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    },
-                },
-            ],
-        });
-
-        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-            label: None,
-            bind_group_layouts: &[&bind_group_layout],
-            push_constant_ranges: &[],
-        });
-
-        let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: None,
-            layout: Some(&pipeline_layout),
-            module: &shader_module,
-            entry_point: "main",
-        });
-
-        let voxel_storage_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: 1024 * 1024 * 256,
-            mapped_at_creation: false,
-            usage: wgpu::BufferUsages::STORAGE
-                | wgpu::BufferUsages::COPY_DST // allow as destination buffer for copy_buffer
-                | wgpu::BufferUsages::COPY_SRC, // allow as source buffer for copy_buffer
-        });
-
-        let block_info_storage_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: 1024 * 1024 * 128,
-            mapped_at_creation: false,
-            usage: wgpu::BufferUsages::STORAGE
-                | wgpu::BufferUsages::COPY_DST // allow as destination buffer for copy_buffer
-                | wgpu::BufferUsages::COPY_SRC, // allow as source buffer for copy_buffer
-        });
-
-        let timestamp_query_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: 8 * 2,
-            usage: wgpu::BufferUsages::COPY_DST // allow as destination buffer for copy_buffer
-                | wgpu::BufferUsages::COPY_SRC // allow as source buffer for copy_buffer
-                | wgpu::BufferUsages::QUERY_RESOLVE, // allow for query use
-            mapped_at_creation: false,
-        });
-
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: voxel_storage_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: block_info_storage_buffer.as_entire_binding(),
-                },
-            ],
-        });
-
-        let voxel_readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: 1024 * 1024 * 256, //voxel_storage_buffer.size(),
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+impl Display for GpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GpuError::WorkgroupStorageExceeded {
+                vps,
+                required,
+                available,
+            } => write!(
+                f,
+                "VPS {vps} requires {required} bytes of workgroup memory, the device has {available}"
+            ),
+            GpuError::WorkgroupSizeExceeded {
+                vps,
+                required,
+                available,
+            } => write!(
+                f,
+                "VPS {vps} requires {required} invocations per workgroup, the device supports {available}"
+            ),
+            GpuError::TooManyBlocks {
+                required,
+                available,
+            } => write!(
+                f,
+                "{required} blocks do not fit the device, at most {available} blocks can be resident"
+            ),
+        }
+    }
+}
 
-        let block_info_readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: 1024 * 1024 * 256, //block_info_storage_buffer.size(),
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+impl std::error::Error for GpuError {}
 
-        let timestamp_readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: timestamp_query_buffer.size(),
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
+/// Checks that the shaders specialised for `vps` fit the limits of the device
+pub fn check_limits(limits: &wgpu::Limits, vps: usize) -> Result<(), GpuError> {
+    let required = (vps * vps * vps * std::mem::size_of::<Esdf>()) as u32;
+    if required > limits.max_compute_workgroup_storage_size {
+        return Err(GpuError::WorkgroupStorageExceeded {
+            vps,
+            required,
+            available: limits.max_compute_workgroup_storage_size,
         });
+    }
 
-        let timestamp_query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
-            label: None,
-            ty: wgpu::QueryType::Timestamp,
-            count: 2,
+    let required = (vps * vps) as u32;
+    let available = limits
+        .max_compute_invocations_per_workgroup
+        .min(limits.max_compute_workgroup_size_x * limits.max_compute_workgroup_size_y);
+    if required > available || vps as u32 > limits.max_compute_workgroup_size_x {
+        return Err(GpuError::WorkgroupSizeExceeded {
+            vps,
+            required,
+            available,
         });
-
-        Self {
-            shader_module,
-            bind_group_layout,
-            pipeline_layout,
-            compute_pipeline,
-            voxel_storage_buffer,
-            block_info_storage_buffer,
-            timestamp_query_buffer,
-            bind_group,
-            voxel_readback_buffer,
-            block_info_readback_buffer,
-            timestamp_readback_buffer,
-            timestamp_query_set,
-        }
     }
 
-    pub fn submit<const VPS: usize>(
-        &mut self,
-        device: &Device,
-        queue: &mut Queue,
-        blocks: &[&Block<Esdf, VPS>],
-    ) {
-        firestorm::profile_method!("submit");
-        firestorm::profile_section!(prepare);
-
-        // prepare data
-        let mut voxels = Vec::with_capacity(blocks.len() * VPS * VPS * VPS);
-        for block in blocks {
-            let lock = block.read();
-            for voxel in lock.as_slice() {
-                voxels.push(*voxel);
-            }
-        }
-        let voxel_data: &[u8] = bytemuck::cast_slice(&voxels);
+    Ok(())
+}
 
-        let block_info = vec![BlockInfo::default(); blocks.len()];
-        let block_info_data: &[u8] = bytemuck::cast_slice(&block_info);
+/// Number of blocks of `vps` the largest storage buffer of the device can hold
+pub fn max_resident_blocks(limits: &wgpu::Limits, vps: usize) -> usize {
+    let block_bytes = (vps * vps * vps * std::mem::size_of::<Esdf>()) as u64;
+    let buffer_size = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
 
-        queue.write_buffer(&self.voxel_storage_buffer, 0, voxel_data);
-        queue.write_buffer(&self.block_info_storage_buffer, 0, block_info_data);
+    (buffer_size / block_bytes) as usize
+}
 
-        // record command
-        let mut encoder =
-            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+/// Dispatch of `workgroups` as rows of at most `width` workgroups
+///
+/// Keeps large dispatches within the per-dimension limit of the device,
+/// the shaders skip workgroups past the end of the last row.
+pub fn dispatch_size(workgroups: u32, width: u32) -> [u32; 3] {
+    [workgroups.min(width), workgroups.div_ceil(width), 1]
+}
 
-        // initial time
-        encoder.write_timestamp(&self.timestamp_query_set, 0);
+/// WGSL source of a shader specialised for `vps` and the additional `u32` `constants`
+fn shader_source(source: &str, vps: usize, constants: &[(&str, u32)]) -> String {
+    let mut header = format!("const VPS: u32 = {vps}u;\n");
+    for (name, value) in constants {
+        header += &format!("const {name}: u32 = {value}u;\n");
+    }
 
-        {
-            let mut comp_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-                label: None,
-                timestamp_writes: None,
-            });
-            comp_pass.set_bind_group(0, &self.bind_group, &[]);
-            comp_pass.set_pipeline(&self.compute_pipeline);
-            comp_pass.dispatch_workgroups(blocks.len() as u32, 1, 1);
-        }
+    header + source
+}
 
-        encoder.copy_buffer_to_buffer(
-            &self.voxel_storage_buffer,
-            0,
-            &self.voxel_readback_buffer,
-            0,
-            voxel_data.len() as u64,
-        );
+fn create_shader_module(
+    device: &Device,
+    label: &str,
+    source: &str,
+    vps: usize,
+    constants: &[(&str, u32)],
+) -> wgpu::ShaderModule {
+    device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some(label),
+        source: wgpu::ShaderSource::Wgsl(Cow::Owned(shader_source(source, vps, constants))),
+    })
+}
 
-        encoder.copy_buffer_to_buffer(
-            &self.block_info_storage_buffer,
-            0,
-            &self.block_info_readback_buffer,
-            0,
-            block_info_data.len() as u64,
-        );
+/// Marks a missing neighbor in the neighbor table of [`BlockSlots`]
+pub const INVALID_SLOT: u32 = u32::MAX;
 
-        // end time
-        encoder.write_timestamp(&self.timestamp_query_set, 1);
-        encoder.resolve_query_set(
-            &self.timestamp_query_set,
-            0..2,
-            &self.timestamp_query_buffer,
-            0,
-        );
-        encoder.copy_buffer_to_buffer(
-            &self.timestamp_query_buffer,
-            0,
-            &self.timestamp_readback_buffer,
-            0,
-            self.timestamp_readback_buffer.size(),
-        );
+/// Sweep and propagate iterations recorded per submission, the host checks for convergence in between
+const ITERATIONS_PER_SUBMIT: usize = 8;
 
-        drop(prepare);
+/// Workgroup size of `compact.wgsl`
+const COMPACT_WORKGROUP_SIZE: u32 = 64;
 
-        // submit
-        firestorm::profile_section!(submit_poll);
-        queue.submit(Some(encoder.finish()));
+/// Header of an empty work list, the count followed by the dispatch arguments
+const WORK_LIST_HEADER: [u32; 4] = [0, 0, 0, 1];
 
-        // readback
-        self.timestamp_readback_buffer
-            .slice(..)
-            .map_async(wgpu::MapMode::Read, |_| {});
-        self.block_info_readback_buffer
-            .slice(..block_info_data.len() as u64)
-            .map_async(wgpu::MapMode::Read, |_| {});
+/// Offset of the dispatch arguments in a work list
+const WORK_LIST_DISPATCH_OFFSET: u64 = 4;
 
-        self.voxel_readback_buffer
-            .slice(..voxel_data.len() as u64)
-            .map_async(wgpu::MapMode::Read, |_| {});
+/// Assigns the blocks resident on the GPU to slots of the block pool
+///
+/// Slots are handed out in order and never freed. Each slot has a row of neighbor slots
+/// in the order of [`BlockIndex::neighbors6_include_self`].
+#[derive(Debug, Default)]
+pub struct BlockSlots<const VPS: usize> {
+    slots: BlockMap<VPS, u32>,
+    blocks: Vec<BlockIndex<VPS>>,
+    neighbors: Vec<[u32; 7]>,
+}
 
-        device.poll(wgpu::Maintain::Wait);
+impl<const VPS: usize> BlockSlots<VPS> {
+    /// Returns the slot of the block and whether it was newly assigned
+    pub fn insert(&mut self, index: BlockIndex<VPS>) -> (u32, bool) {
+        if let Some(slot) = self.get(&index) {
+            return (slot, false);
+        }
 
-        drop(submit_poll);
+        let slot = self.blocks.len() as u32;
+        let mut row = [INVALID_SLOT; 7];
+        row[0] = slot;
 
-        {
-            firestorm::profile_section!(readback);
+        for (i, neighbor) in index.neighbors6_include_self().enumerate().skip(1) {
+            if let Some(neighbor_slot) = self.get(&neighbor.index) {
+                row[i] = neighbor_slot;
+                // x+ <-> x-, y+ <-> y-, z+ <-> z-
+                let opposite = if i % 2 == 1 { i + 1 } else { i - 1 };
+                self.neighbors[neighbor_slot as usize][opposite] = slot;
+            }
+        }
 
-            let bytes: &[u8] = &self
-                .voxel_readback_buffer
-                .slice(..voxel_data.len() as u64)
-                .get_mapped_range();
-            let data: &[Esdf] = bytemuck::cast_slice(bytes);
+        self.slots.insert(index, slot);
+        self.blocks.push(index);
+        self.neighbors.push(row);
 
-            let voxel_blocks: Vec<_> = data.chunks(VPS * VPS * VPS).collect();
+        (slot, true)
+    }
 
-            let bytes: &[u8] = &self.timestamp_readback_buffer.slice(..).get_mapped_range();
-            let counts: &[u64; 2] = bytemuck::from_bytes(bytes);
+    pub fn get(&self, index: &BlockIndex<VPS>) -> Option<u32> {
+        self.slots.get(index).copied()
+    }
 
-            println!(
-                "GPU sweep:\t{} blocks\t{:?}",
-                blocks.len(),
-                TimestampGpu::new(counts, queue).duration()
-            );
+    pub fn block(&self, slot: u32) -> BlockIndex<VPS> {
+        self.blocks[slot as usize]
+    }
 
-            let bytes: &[u8] = &self
-                .block_info_readback_buffer
-                .slice(..block_info_data.len() as u64)
-                .get_mapped_range();
-            let block_info: &[BlockInfo] = bytemuck::cast_slice(bytes);
+    pub fn neighbors(&self) -> &[[u32; 7]] {
+        &self.neighbors
+    }
 
-            // writeback
-            for (block, voxel_data) in blocks.iter().zip(voxel_blocks) {
-                block.write().as_mut_slice().copy_from_slice(voxel_data);
-            }
-        }
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
 
-        self.voxel_readback_buffer.unmap();
-        self.block_info_readback_buffer.unmap();
-        self.timestamp_readback_buffer.unmap();
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
     }
 }
 
-pub struct GpuPropagate {
-    shader_module: wgpu::ShaderModule,
+/// Compute pipelines of the ESDF update, specialised for one VPS
+pub struct GpuPipelines {
+    vps: usize,
+    /// Workgroups per row of a dispatch
+    dispatch_width: u32,
     bind_group_layout: wgpu::BindGroupLayout,
-    pipeline_layout: wgpu::PipelineLayout,
-    compute_pipeline: wgpu::ComputePipeline,
-    voxel_storage_buffer: wgpu::Buffer,
-    block_info_storage_buffer: wgpu::Buffer,
-    timestamp_query_buffer: wgpu::Buffer,
-    workgroup_block_indices_storage_buffer: wgpu::Buffer,
-    bind_group: wgpu::BindGroup,
-    voxel_readback_buffer: wgpu::Buffer,
-    block_info_readback_buffer: wgpu::Buffer,
-    timestamp_readback_buffer: wgpu::Buffer,
-    timestamp_query_set: wgpu::QuerySet,
-}
-
-impl GpuPropagate {
-    pub fn new(device: &Device, queue: &mut Queue) -> Self {
-        let shader_module = device.create_shader_module(include_wgsl!("shaders/propagate.wgsl"));
+    sweep: wgpu::ComputePipeline,
+    /// One per direction, x, y and z
+    propagate: [wgpu::ComputePipeline; 3],
+    compact: wgpu::ComputePipeline,
+}
 
+impl GpuPipelines {
+    pub fn new(device: &Device, vps: usize) -> Result<Self, GpuError> {
+        check_limits(&device.limits(), vps)?;
+
+        let buffer_entry = |binding, ty| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            count: None,
+            ty: wgpu::BindingType::Buffer {
+                has_dynamic_offset: false,
+                min_binding_size: None,
+                ty,
+            },
+        };
+        let storage = |read_only| wgpu::BufferBindingType::Storage { read_only };
+
+        // shared by all shaders, see the bindings in the shaders
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: None,
             entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    count: None,
-                    ty: wgpu::BindingType::Buffer {
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                        ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    },
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    count: None,
-                    ty: wgpu::BindingType::Buffer {
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                        ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    },
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    count: None,
-                    ty: wgpu::BindingType::Buffer {
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                        ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    },
-                },
+                buffer_entry(0, storage(false)),                   // voxels
+                buffer_entry(1, storage(true)),                    // neighbors
+                buffer_entry(2, storage(false)),                   // block info
+                buffer_entry(3, storage(true)),                    // block indices
+                buffer_entry(4, wgpu::BufferBindingType::Uniform), // params
+                buffer_entry(5, storage(true)),                    // work list
+                buffer_entry(6, storage(false)),                   // next work list
+                buffer_entry(7, storage(false)),                   // stats
             ],
         });
 
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: None,
             bind_group_layouts: &[&bind_group_layout],
-            push_constant_ranges: &[PushConstantRange {
-                stages: wgpu::ShaderStages::COMPUTE,
-                range: 0..4,
-            }],
+            push_constant_ranges: &[],
         });
 
-        let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: None,
-            layout: Some(&pipeline_layout),
-            module: &shader_module,
-            entry_point: "main",
-        });
+        let pipeline = |label: &str, source: &str, constants: &[(&str, u32)]| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(&pipeline_layout),
+                module: &create_shader_module(device, label, source, vps, constants),
+                entry_point: "main",
+            })
+        };
 
-        let voxel_storage_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: 1024 * 1024 * 256,
-            mapped_at_creation: false,
-            usage: wgpu::BufferUsages::STORAGE
-                | wgpu::BufferUsages::COPY_DST // allow as destination buffer for copy_buffer
-                | wgpu::BufferUsages::COPY_SRC, // allow as source buffer for copy_buffer
+        // the direction is a constant rather than a push constant, which not every device supports
+        let propagate = PropagateDirection::ALL.map(|dir| {
+            pipeline(
+                "propagate.wgsl",
+                include_str!("shaders/propagate.wgsl"),
+                &dir.constants(),
+            )
         });
 
-        let block_info_storage_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: 1024 * 1024 * 128,
-            mapped_at_creation: false,
-            usage: wgpu::BufferUsages::STORAGE
-                | wgpu::BufferUsages::COPY_DST // allow as destination buffer for copy_buffer
-                | wgpu::BufferUsages::COPY_SRC, // allow as source buffer for copy_buffer
-        });
+        Ok(Self {
+            vps,
+            dispatch_width: device.limits().max_compute_workgroups_per_dimension,
+            sweep: pipeline("sweep.wgsl", include_str!("shaders/sweep.wgsl"), &[]),
+            propagate,
+            compact: pipeline("compact.wgsl", include_str!("shaders/compact.wgsl"), &[]),
+            bind_group_layout,
+        })
+    }
+}
+
+/// ESDF blocks kept on the GPU across passes and updates
+///
+/// The layer on the host is only updated by [`GpuBlockPool::sync`].
+pub struct GpuBlockPool<const VPS: usize> {
+    slots: BlockSlots<VPS>,
+    capacity: usize,
+    /// Limit of the capacity, at most what the device supports
+    max_capacity: usize,
+    voxel_buffer: wgpu::Buffer,
+    block_info_buffer: wgpu::Buffer,
+    block_index_buffer: wgpu::Buffer,
+    neighbor_buffer: wgpu::Buffer,
+    params_buffer: wgpu::Buffer,
+    work_list_buffers: [wgpu::Buffer; 2],
+    /// Counters of the current submission, see `compact.wgsl`
+    stats_buffer: wgpu::Buffer,
+    /// Blocks left after each iteration of the current submission
+    counts_buffer: wgpu::Buffer,
+    /// `bind_groups[i]` processes `work_list_buffers[i]` and fills the other one
+    bind_groups: [wgpu::BindGroup; 2],
+    /// Only if the device supports timestamp queries
+    timestamps: Option<Timestamps>,
+    readback: Readback,
+}
+
+impl<const VPS: usize> GpuBlockPool<VPS> {
+    const BLOCK_BYTES: u64 = (VPS * VPS * VPS * std::mem::size_of::<Esdf>()) as u64;
 
-        let workgroup_block_indices_storage_buffer =
+    /// Creates a pool for `capacity` blocks that grows up to `max_capacity` blocks,
+    /// both are limited to as many blocks as the device supports
+    pub fn new(
+        device: &Device,
+        pipelines: &GpuPipelines,
+        capacity: usize,
+        max_capacity: usize,
+    ) -> Self {
+        assert_eq!(VPS, pipelines.vps, "pipelines were created for another VPS");
+
+        let max_capacity = max_capacity.min(max_resident_blocks(&device.limits(), VPS));
+        let capacity = capacity.clamp(1, max_capacity.max(1));
+        let storage_buffer = |size: u64| {
             device.create_buffer(&wgpu::BufferDescriptor {
                 label: None,
-                size: 1024 * 1024 * 128,
+                size,
                 mapped_at_creation: false,
                 usage: wgpu::BufferUsages::STORAGE
-            | wgpu::BufferUsages::COPY_DST // allow as destination buffer for copy_buffer
-            | wgpu::BufferUsages::COPY_SRC, // allow as source buffer for copy_buffer
-            });
+                    | wgpu::BufferUsages::COPY_DST
+                    | wgpu::BufferUsages::COPY_SRC,
+            })
+        };
 
-        let timestamp_query_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        let voxel_buffer = storage_buffer(capacity as u64 * Self::BLOCK_BYTES);
+        let block_info_buffer =
+            storage_buffer((capacity * std::mem::size_of::<BlockInfo>()) as u64);
+        let block_index_buffer =
+            storage_buffer((capacity * std::mem::size_of::<GpuBlockIndex>()) as u64);
+        let neighbor_buffer = storage_buffer((capacity * std::mem::size_of::<[u32; 7]>()) as u64);
+        let stats_buffer = storage_buffer(std::mem::size_of::<u32>() as u64);
+        let counts_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: None,
-            size: 8 * 2,
-            usage: wgpu::BufferUsages::COPY_DST // allow as destination buffer for copy_buffer
-                | wgpu::BufferUsages::COPY_SRC // allow as source buffer for copy_buffer
-                | wgpu::BufferUsages::QUERY_RESOLVE, // allow for query use
+            size: (ITERATIONS_PER_SUBMIT * std::mem::size_of::<u32>()) as u64,
             mapped_at_creation: false,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
         });
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: None,
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: voxel_storage_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: workgroup_block_indices_storage_buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: block_info_storage_buffer.as_entire_binding(),
-                },
-            ],
-        });
-
-        let voxel_readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: 1024 * 1024 * 256,
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            size: std::mem::size_of::<EsdfParams>() as u64,
             mapped_at_creation: false,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let block_info_readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: 1024 * 1024 * 256,
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
+        // the work lists double as indirect dispatch arguments
+        let work_list_buffers = [(); 2].map(|_| {
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size: (std::mem::size_of_val(&WORK_LIST_HEADER) + capacity * 4) as u64,
+                mapped_at_creation: true,
+                usage: wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::INDIRECT
+                    | wgpu::BufferUsages::COPY_DST
+                    | wgpu::BufferUsages::COPY_SRC,
+            });
+            buffer.slice(..16).get_mapped_range_mut()[..16]
+                .copy_from_slice(bytemuck::cast_slice(&WORK_LIST_HEADER));
+            buffer.unmap();
+            buffer
         });
 
-        let timestamp_readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: timestamp_query_buffer.size(),
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        let bind_group = |current: usize| {
+            let buffers = [
+                &voxel_buffer,
+                &neighbor_buffer,
+                &block_info_buffer,
+                &block_index_buffer,
+                &params_buffer,
+                &work_list_buffers[current],
+                &work_list_buffers[1 - current],
+                &stats_buffer,
+            ];
+            let entries: Vec<_> = buffers
+                .iter()
+                .enumerate()
+                .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                    binding: binding as u32,
+                    resource: buffer.as_entire_binding(),
+                })
+                .collect();
+
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &pipelines.bind_group_layout,
+                entries: &entries,
+            })
+        };
+        let bind_groups = [bind_group(0), bind_group(1)];
 
-        let timestamp_query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
-            label: None,
-            ty: wgpu::QueryType::Timestamp,
-            count: 2,
-        });
+        let timestamps = device
+            .features()
+            .contains(wgpu::Features::TIMESTAMP_QUERY)
+            .then(|| Timestamps::new(device));
 
         Self {
-            shader_module,
-            bind_group_layout,
-            pipeline_layout,
-            compute_pipeline,
-            voxel_storage_buffer,
-            workgroup_block_indices_storage_buffer,
-            block_info_storage_buffer,
-            timestamp_query_buffer,
-            bind_group,
-            voxel_readback_buffer,
-            block_info_readback_buffer,
-            timestamp_readback_buffer,
-            timestamp_query_set,
+            slots: BlockSlots::default(),
+            capacity,
+            max_capacity,
+            voxel_buffer,
+            block_info_buffer,
+            block_index_buffer,
+            neighbor_buffer,
+            params_buffer,
+            work_list_buffers,
+            stats_buffer,
+            counts_buffer,
+            bind_groups,
+            timestamps,
+            readback: Readback::new(device, 1024),
+        }
+    }
+
+    pub fn max_capacity(&self) -> usize {
+        self.max_capacity
+    }
+
+    /// Uploads the blocks of `layer` that are not resident yet and the `changed_blocks`
+    ///
+    /// The pool grows if it is full, which uploads all blocks again.
+    /// Changes on the GPU that were not synced to `layer` are lost in that case.
+    /// Fails if the blocks do not fit the device, the pool is left unchanged then.
+    pub fn upload(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        pipelines: &GpuPipelines,
+        layer: &Layer<Esdf, VPS>,
+        changed_blocks: &BTreeSet<BlockIndex<VPS>>,
+    ) -> Result<(), GpuError> {
+        firestorm::profile_method!("upload");
+
+        let missing = layer
+            .allocated_blocks_iter()
+            .filter(|index| self.slots.get(index).is_none())
+            .count();
+        let required = self.slots.len() + missing;
+        if required > self.max_capacity {
+            return Err(GpuError::TooManyBlocks {
+                required,
+                available: self.max_capacity,
+            });
         }
+        if required > self.capacity {
+            *self = Self::new(
+                device,
+                pipelines,
+                required.next_power_of_two(),
+                self.max_capacity,
+            );
+        }
+
+        let mut new_blocks: Vec<_> = layer
+            .allocated_blocks_iter()
+            .filter(|index| self.slots.get(index).is_none())
+            .copied()
+            .collect();
+        new_blocks.sort();
+        self.insert(queue, layer, &new_blocks, changed_blocks);
+
+        Ok(())
     }
 
-    pub fn submit<const VPS: usize>(
+    /// Replaces the resident blocks by the `blocks` of `layer`, growing the pool if needed
+    ///
+    /// Used to update a layer in slices that fit the pool, see [`GpuBlockPool::iterate`].
+    pub fn load(
         &mut self,
         device: &Device,
-        queue: &mut Queue,
-        workgroup_block_indices: &[u32],
-        blocks: &[&Block<Esdf, VPS>],
-    ) -> Vec<BlockInfo> {
-        firestorm::profile_method!("submit");
-        firestorm::profile_section!(prepare);
-
-        // prepare data
-        let mut voxels = Vec::with_capacity(blocks.len() * VPS * VPS * VPS);
-
-        {
-            firestorm::profile_section!(prep_voxels);
-            for block in blocks {
-                let lock = block.read();
-                for voxel in lock.as_slice() {
-                    voxels.push(*voxel);
-                }
+        queue: &Queue,
+        pipelines: &GpuPipelines,
+        layer: &Layer<Esdf, VPS>,
+        blocks: &BTreeSet<BlockIndex<VPS>>,
+    ) {
+        firestorm::profile_method!("load");
+
+        assert!(
+            blocks.len() <= self.max_capacity,
+            "the blocks do not fit the pool"
+        );
+        if blocks.len() > self.capacity {
+            *self = Self::new(
+                device,
+                pipelines,
+                blocks.len().next_power_of_two(),
+                self.max_capacity,
+            );
+        } else {
+            self.clear();
+        }
+
+        let blocks: Vec<_> = blocks
+            .iter()
+            .filter(|index| layer.contains(index))
+            .copied()
+            .collect();
+        self.insert(queue, layer, &blocks, &BTreeSet::new());
+    }
+
+    /// Forgets the resident blocks, e.g., after the layer was updated elsewhere
+    pub fn clear(&mut self) {
+        self.slots = BlockSlots::default();
+    }
+
+    /// Assigns slots to the `new_blocks` and writes them and the resident `changed_blocks`
+    fn insert(
+        &mut self,
+        queue: &Queue,
+        layer: &Layer<Esdf, VPS>,
+        new_blocks: &[BlockIndex<VPS>],
+        changed_blocks: &BTreeSet<BlockIndex<VPS>>,
+    ) {
+        let first_new_slot = self.slots.len();
+        for index in new_blocks {
+            self.slots.insert(*index);
+        }
+
+        for index in new_blocks.iter().chain(changed_blocks) {
+            let (Some(slot), Some(block)) = (self.slots.get(index), layer.block_by_index(index))
+            else {
+                continue;
+            };
+
+            queue.write_buffer(
+                &self.voxel_buffer,
+                slot as u64 * Self::BLOCK_BYTES,
+                bytemuck::cast_slice(block.read().as_slice()),
+            );
+        }
+
+        if !new_blocks.is_empty() {
+            let block_indices: Vec<_> = new_blocks.iter().map(GpuBlockIndex::from).collect();
+            queue.write_buffer(
+                &self.block_index_buffer,
+                (first_new_slot * std::mem::size_of::<GpuBlockIndex>()) as u64,
+                bytemuck::cast_slice(&block_indices),
+            );
+
+            // new blocks also change the rows of their neighbors
+            queue.write_buffer(
+                &self.neighbor_buffer,
+                0,
+                bytemuck::cast_slice(self.slots.neighbors()),
+            );
+        }
+    }
+
+    /// Sweeps and propagates starting at the `dirty_blocks` until no block is updated anymore
+    ///
+    /// `report` is called after each submission and may stop the run early.
+    /// Returns the number of iterations.
+    pub fn run(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        pipelines: &GpuPipelines,
+        dirty_blocks: &BTreeSet<BlockIndex<VPS>>,
+        params: &EsdfParams,
+        mut report: impl FnMut(&GpuPassReport<VPS>) -> ControlFlow<()>,
+    ) -> usize {
+        firestorm::profile_method!("run");
+
+        if !self.write_work_list(queue, pipelines, dirty_blocks, params) {
+            return 0;
+        }
+
+        let mut current = 0;
+        let mut iterations = 0;
+        loop {
+            let submission = self.submit(
+                device,
+                queue,
+                pipelines,
+                params.flags,
+                ITERATIONS_PER_SUBMIT,
+                &mut current,
+            );
+            iterations += submission.iterations;
+
+            let converged = submission.pending_blocks.is_empty();
+            let flow = report(&GpuPassReport {
+                iterations,
+                ..submission
+            });
+
+            if converged || flow.is_break() {
+                break;
             }
         }
 
-        let voxel_data: &[u8] = bytemuck::cast_slice(&voxels);
+        iterations
+    }
+
+    /// Sweeps and propagates the resident `blocks` once
+    ///
+    /// The pending blocks of the report are the blocks updated by the propagation.
+    pub fn iterate(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        pipelines: &GpuPipelines,
+        blocks: &BTreeSet<BlockIndex<VPS>>,
+        params: &EsdfParams,
+    ) -> GpuPassReport<VPS> {
+        firestorm::profile_method!("iterate");
+
+        if !self.write_work_list(queue, pipelines, blocks, params) {
+            return GpuPassReport::default();
+        }
+
+        self.submit(device, queue, pipelines, params.flags, 1, &mut 0)
+    }
 
-        let block_info = vec![BlockInfo::default(); blocks.len()];
-        let block_info_data: &[u8] = bytemuck::cast_slice(&block_info);
+    /// Writes the `params` and the resident `blocks` as the first work list
+    ///
+    /// Returns false if none of the blocks is resident.
+    fn write_work_list(
+        &mut self,
+        queue: &Queue,
+        pipelines: &GpuPipelines,
+        blocks: &BTreeSet<BlockIndex<VPS>>,
+        params: &EsdfParams,
+    ) -> bool {
+        let slots: Vec<u32> = blocks
+            .iter()
+            .filter_map(|index| self.slots.get(index))
+            .collect();
+        if slots.is_empty() {
+            return false;
+        }
 
-        let workgroup_block_indices_data: &[u8] = bytemuck::cast_slice(workgroup_block_indices);
+        let params = EsdfParams {
+            block_count: self.slots.len() as u32,
+            dispatch_width: pipelines.dispatch_width,
+            ..*params
+        };
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[params]));
 
-        queue.write_buffer(&self.voxel_storage_buffer, 0, voxel_data);
-        queue.write_buffer(&self.block_info_storage_buffer, 0, block_info_data);
+        let count = slots.len() as u32;
+        let [x, y, z] = dispatch_size(count, pipelines.dispatch_width);
+        let header = [count, x, y, z];
+        queue.write_buffer(&self.work_list_buffers[0], 0, bytemuck::cast_slice(&header));
         queue.write_buffer(
-            &self.workgroup_block_indices_storage_buffer,
-            0,
-            workgroup_block_indices_data,
+            &self.work_list_buffers[0],
+            std::mem::size_of_val(&header) as u64,
+            bytemuck::cast_slice(&slots),
         );
 
-        // record command
+        true
+    }
+
+    /// Records `iterations` iterations starting at the `current` work list and waits for them
+    ///
+    /// `current` is advanced to the work list of the blocks left to process. The report holds
+    /// the iterations that ran, those after the work list ran empty dispatch nothing.
+    fn submit(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        pipelines: &GpuPipelines,
+        flags: EsdfParamFlags,
+        iterations: usize,
+        current: &mut usize,
+    ) -> GpuPassReport<VPS> {
+        let start = Instant::now();
         let mut encoder =
             device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
 
-        // initial time
-        encoder.write_timestamp(&self.timestamp_query_set, 0);
+        encoder.clear_buffer(&self.stats_buffer, 0, None);
+        if let Some(timestamps) = &self.timestamps {
+            encoder.write_timestamp(&timestamps.query_set, 0);
+        }
+        debug_assert!(iterations <= ITERATIONS_PER_SUBMIT);
+        for i in 0..iterations {
+            self.record_iteration(&mut encoder, pipelines, *current, flags);
+            *current = 1 - *current;
+            encoder.copy_buffer_to_buffer(
+                &self.work_list_buffers[*current],
+                0,
+                &self.counts_buffer,
+                (i * 4) as u64,
+                4,
+            );
+        }
 
-        {
-            let mut comp_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
-                label: None,
-                timestamp_writes: None,
-            });
+        let counts_size = (iterations * 4) as u64;
+        let mut copies = vec![
+            (&self.counts_buffer, 0, counts_size),
+            (&self.stats_buffer, 0, 4),
+        ];
+        if let Some(timestamps) = &self.timestamps {
+            timestamps.resolve(&mut encoder);
+            copies.push((&timestamps.buffer, 0, 16));
+        }
 
-            comp_pass.set_bind_group(0, &self.bind_group, &[]);
-            comp_pass.set_pipeline(&self.compute_pipeline);
-            let settings = PropagateSettings {
-                dir: PropagateSettings::X,
-            };
-            comp_pass.set_push_constants(0, bytemuck::cast_slice(&[settings]));
-            comp_pass.dispatch_workgroups(workgroup_block_indices.len() as u32 / 7, 1, 1);
+        let status = self.readback.read(device, queue, encoder, &copies);
+        let (counts, status) = status.split_at(counts_size as usize);
+        let counts: Vec<u32> = bytemuck::pod_collect_to_vec(counts);
+        let remaining = counts[iterations - 1];
+        let updated_voxels: u32 = bytemuck::pod_read_unaligned(&status[..4]);
+        let gpu_time = self.timestamps.as_ref().map(|_| {
+            let counts: [u64; 2] = bytemuck::pod_read_unaligned(&status[4..]);
+            TimestampGpu::new(&counts, queue).duration()
+        });
 
-            let settings = PropagateSettings {
-                dir: PropagateSettings::Y,
-            };
-            comp_pass.set_push_constants(0, bytemuck::cast_slice(&[settings]));
-            comp_pass.dispatch_workgroups(workgroup_block_indices.len() as u32 / 7, 1, 1);
+        GpuPassReport {
+            // the first iteration always has blocks to process
+            iterations: 1 + counts[..iterations - 1]
+                .iter()
+                .take_while(|count| **count > 0)
+                .count(),
+            pending_blocks: self.read_work_list(device, queue, *current, remaining),
+            updated_voxels: updated_voxels as usize,
+            wall_time: start.elapsed(),
+            gpu_time,
         }
+    }
 
-        encoder.copy_buffer_to_buffer(
-            &self.voxel_storage_buffer,
-            0,
-            &self.voxel_readback_buffer,
-            0,
-            voxel_data.len() as u64,
-        );
+    /// Blocks of the first `count` entries of a work list
+    fn read_work_list(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        current: usize,
+        count: u32,
+    ) -> Vec<BlockIndex<VPS>> {
+        if count == 0 {
+            return vec![];
+        }
 
-        encoder.copy_buffer_to_buffer(
-            &self.block_info_storage_buffer,
-            0,
-            &self.block_info_readback_buffer,
-            0,
-            block_info_data.len() as u64,
+        let encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let bytes = self.readback.read(
+            device,
+            queue,
+            encoder,
+            &[(
+                &self.work_list_buffers[current],
+                std::mem::size_of_val(&WORK_LIST_HEADER) as u64,
+                count as u64 * 4,
+            )],
         );
+        let slots: Vec<u32> = bytemuck::pod_collect_to_vec(&bytes);
 
-        // end time
-        encoder.write_timestamp(&self.timestamp_query_set, 1);
-        encoder.resolve_query_set(
-            &self.timestamp_query_set,
-            0..2,
-            &self.timestamp_query_buffer,
-            0,
-        );
-        encoder.copy_buffer_to_buffer(
-            &self.timestamp_query_buffer,
-            0,
-            &self.timestamp_readback_buffer,
-            0,
-            self.timestamp_readback_buffer.size(),
+        slots.iter().map(|slot| self.slots.block(*slot)).collect()
+    }
+
+    /// Sweep and propagate the blocks of the current work list, then collect the updated blocks
+    /// into the next one
+    fn record_iteration(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pipelines: &GpuPipelines,
+        current: usize,
+        flags: EsdfParamFlags,
+    ) {
+        let work_list = &self.work_list_buffers[current];
+
+        // the count and the dispatch rows, z stays 1
+        encoder.clear_buffer(&self.work_list_buffers[1 - current], 0, Some(12));
+
+        let directions = if flags.contains(EsdfParamFlags::ProcessZ) {
+            &PropagateDirection::ALL[..]
+        } else {
+            &PropagateDirection::ALL[..2]
+        };
+
+        let mut comp_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: None,
+            timestamp_writes: None,
+        });
+        comp_pass.set_bind_group(0, &self.bind_groups[current], &[]);
+
+        comp_pass.set_pipeline(&pipelines.sweep);
+        comp_pass.dispatch_workgroups_indirect(work_list, WORK_LIST_DISPATCH_OFFSET);
+
+        for dir in directions {
+            comp_pass.set_pipeline(&pipelines.propagate[*dir as usize]);
+            comp_pass.dispatch_workgroups_indirect(work_list, WORK_LIST_DISPATCH_OFFSET);
+        }
+
+        comp_pass.set_pipeline(&pipelines.compact);
+        let [x, y, z] = dispatch_size(
+            (self.slots.len() as u32).div_ceil(COMPACT_WORKGROUP_SIZE),
+            pipelines.dispatch_width,
         );
+        comp_pass.dispatch_workgroups(x, y, z);
+    }
 
-        drop(prepare);
+    /// Writes the blocks changed on the GPU since the last sync back to `layer`
+    ///
+    /// Returns the number of blocks written.
+    pub fn sync(&mut self, device: &Device, queue: &Queue, layer: &Layer<Esdf, VPS>) -> usize {
+        firestorm::profile_method!("sync");
 
-        // submit
-        firestorm::profile_section!(submit_poll);
-        queue.submit(Some(encoder.finish()));
+        if self.slots.is_empty() {
+            return 0;
+        }
 
-        // readback
-        self.timestamp_readback_buffer
-            .slice(..)
-            .map_async(wgpu::MapMode::Read, |_| {});
+        let block_info_size = (self.slots.len() * std::mem::size_of::<BlockInfo>()) as u64;
+        let encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let bytes = self.readback.read(
+            device,
+            queue,
+            encoder,
+            &[(&self.block_info_buffer, 0, block_info_size)],
+        );
+        let block_info: Vec<BlockInfo> = bytemuck::pod_collect_to_vec(&bytes);
 
-        self.block_info_readback_buffer
-            .slice(..block_info_data.len() as u64)
-            .map_async(wgpu::MapMode::Read, |_| {});
+        let modified: Vec<u32> = (0..self.slots.len() as u32)
+            .filter(|slot| block_info[*slot as usize].modified != 0)
+            .collect();
+        if modified.is_empty() {
+            return 0;
+        }
 
-        self.voxel_readback_buffer
-            .slice(..voxel_data.len() as u64)
-            .map_async(wgpu::MapMode::Read, |_| {});
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.clear_buffer(&self.block_info_buffer, 0, None);
+        let copies: Vec<_> = modified
+            .iter()
+            .map(|slot| {
+                (
+                    &self.voxel_buffer,
+                    *slot as u64 * Self::BLOCK_BYTES,
+                    Self::BLOCK_BYTES,
+                )
+            })
+            .collect();
+        let bytes = self.readback.read(device, queue, encoder, &copies);
+        let voxels: Vec<Esdf> = bytemuck::pod_collect_to_vec(&bytes);
+
+        for (slot, voxels) in modified.iter().zip(voxels.chunks(VPS * VPS * VPS)) {
+            if let Some(block) = layer.block_by_index(&self.slots.block(*slot)) {
+                block.write().as_mut_slice().copy_from_slice(voxels);
+            }
+        }
 
-        device.poll(wgpu::Maintain::Wait);
-        drop(submit_poll);
+        modified.len()
+    }
+}
 
-        firestorm::profile_section!(readback);
+/// Progress of [`GpuBlockPool::run`] after a submission
+#[derive(Debug, Default, Clone)]
+pub struct GpuPassReport<const VPS: usize> {
+    /// Iterations run so far, iterations recorded after convergence are not counted
+    pub iterations: usize,
+    /// Blocks updated by the last iteration, processed by the next submission
+    pub pending_blocks: Vec<BlockIndex<VPS>>,
+    /// Voxels updated by the sweeps of the submission
+    pub updated_voxels: usize,
+    pub wall_time: Duration,
+    /// Only measured on devices supporting timestamps
+    pub gpu_time: Option<Duration>,
+}
 
-        let block_info = {
-            let bytes: &[u8] = &self
-                .voxel_readback_buffer
-                .slice(..voxel_data.len() as u64)
-                .get_mapped_range();
-            let data: &[Esdf] = bytemuck::cast_slice(bytes);
+/// Mappable buffer to read GPU buffers back to the host, grows as needed
+struct Readback {
+    buffer: wgpu::Buffer,
+}
 
-            let voxel_blocks: Vec<_> = data.chunks(VPS * VPS * VPS).collect();
+impl Readback {
+    fn new(device: &Device, size: u64) -> Self {
+        Self {
+            buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+        }
+    }
 
-            let bytes: &[u8] = &self.timestamp_readback_buffer.slice(..).get_mapped_range();
-            let counts: &[u64; 2] = bytemuck::from_bytes(bytes);
+    /// Submits `encoder` followed by the `(buffer, offset, size)` copies and returns their
+    /// concatenated contents
+    fn read(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        mut encoder: wgpu::CommandEncoder,
+        copies: &[(&wgpu::Buffer, u64, u64)],
+    ) -> Vec<u8> {
+        let size: u64 = copies.iter().map(|(_, _, size)| size).sum();
+        if size > self.buffer.size() {
+            let max_size = device.limits().max_buffer_size;
+            *self = Self::new(device, size.next_power_of_two().min(max_size).max(size));
+        }
 
-            println!(
-                "GPU propgate:\t{} blocks\t{:?}",
-                blocks.len(),
-                TimestampGpu::new(counts, queue).duration()
-            );
+        let mut offset = 0;
+        for (buffer, src_offset, size) in copies {
+            encoder.copy_buffer_to_buffer(buffer, *src_offset, &self.buffer, offset, *size);
+            offset += size;
+        }
+        queue.submit(Some(encoder.finish()));
 
-            let bytes: &[u8] = &self
-                .block_info_readback_buffer
-                .slice(..block_info_data.len() as u64)
-                .get_mapped_range();
-            let block_info: &[BlockInfo] = bytemuck::cast_slice(bytes);
+        let slice = self.buffer.slice(..size);
+        slice.map_async(wgpu::MapMode::Read, |_| {});
+        device.poll(wgpu::Maintain::Wait);
 
-            // writeback
-            for (block, voxel_data) in blocks.iter().zip(voxel_blocks) {
-                block.write().as_mut_slice().copy_from_slice(voxel_data);
-            }
+        let bytes = slice.get_mapped_range().to_vec();
+        self.buffer.unmap();
 
-            block_info.to_owned()
-        };
+        bytes
+    }
+}
 
-        self.voxel_readback_buffer.unmap();
-        self.block_info_readback_buffer.unmap();
-        self.timestamp_readback_buffer.unmap();
+/// Timestamps written around a submission
+struct Timestamps {
+    query_set: wgpu::QuerySet,
+    buffer: wgpu::Buffer,
+}
 
-        drop(readback);
+impl Timestamps {
+    fn new(device: &Device) -> Self {
+        Self {
+            query_set: device.create_query_set(&wgpu::QuerySetDescriptor {
+                label: None,
+                ty: wgpu::QueryType::Timestamp,
+                count: 2,
+            }),
+            buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size: 8 * 2,
+                usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::QUERY_RESOLVE,
+                mapped_at_creation: false,
+            }),
+        }
+    }
 
-        block_info
+    /// Writes the second timestamp and resolves both into `buffer`
+    fn resolve(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.write_timestamp(&self.query_set, 1);
+        encoder.resolve_query_set(&self.query_set, 0..2, &self.buffer, 0);
     }
 }
 
 #[derive(Debug, Clone, Copy)]
 struct TimestampGpu {
-    ns: [f32; 2],
     delta_ns: f32,
 }
 
@@ -667,31 +1084,169 @@ impl TimestampGpu {
         let period = queue.get_timestamp_period();
 
         Self {
-            ns: [counts[0] as f32 * period, counts[1] as f32 * period],
             delta_ns: (counts[1] - counts[0]) as f32 * period,
         }
     }
 
-    pub fn duration(&self) -> std::time::Duration {
-        std::time::Duration::from_nanos(self.delta_ns as u64)
+    pub fn duration(&self) -> Duration {
+        Duration::from_nanos(self.delta_ns as u64)
     }
 }
 
 #[derive(Debug, Default, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
-#[repr(C, align(8))]
+#[repr(C, align(16))]
 pub struct BlockInfo {
     pub flags: EsdfFlags,
     pub updated_voxels: u32,
+    /// Changed since the last sync
+    pub modified: u32,
+    pub _pad: u32,
+}
+
+/// Block index as seen by the shaders (`vec4<i32>`)
+#[derive(Debug, Default, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C, align(16))]
+struct GpuBlockIndex([i32; 4]);
+
+impl<const VPS: usize> From<&BlockIndex<VPS>> for GpuBlockIndex {
+    fn from(value: &BlockIndex<VPS>) -> Self {
+        Self([value.x, value.y, value.z, 0])
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
-#[repr(C, align(4))]
-struct PropagateSettings {
-    dir: u32,
+#[repr(C, align(16))]
+pub struct EsdfParams {
+    pub flags: EsdfParamFlags,
+    pub max_distance: f32,
+    pub voxel_size: f32,
+    /// Number of resident blocks, set by the block pool
+    pub block_count: u32,
+    /// Workgroups per row of a dispatch, set by the block pool
+    pub dispatch_width: u32,
+    pub _pad: [u32; 3],
 }
 
-impl PropagateSettings {
-    const X: u32 = 0 + 1;
-    const Y: u32 = 2 + 1;
-    const Z: u32 = 4 + 1;
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, bytemuck::Pod, bytemuck::Zeroable)]
+    #[repr(C)]
+    pub struct EsdfParamFlags: u32 {
+        const ProcessZ = 1<<0;
+    }
+}
+
+/// Direction of a propagate pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PropagateDirection {
+    X = 0,
+    Y = 1,
+    Z = 2,
+}
+
+impl PropagateDirection {
+    const ALL: [Self; 3] = [Self::X, Self::Y, Self::Z];
+
+    /// Constants prepended to `propagate.wgsl`
+    fn constants(self) -> [(&'static str, u32); 1] {
+        // offset of the plus neighbor in a row of the neighbor table, the minus neighbor follows
+        [("DIR_BLOCK_INDEX_OFFSET", 2 * self as u32 + 1)]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn validate(source: &str, vps: usize, constants: &[(&str, u32)]) {
+        let module = naga::front::wgsl::parse_str(&shader_source(source, vps, constants)).unwrap();
+
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::all(),
+        )
+        .validate(&module)
+        .unwrap();
+    }
+
+    #[test]
+    fn validate_shaders() {
+        for vps in [4, 8, 16] {
+            validate(include_str!("shaders/sweep.wgsl"), vps, &[]);
+            validate(include_str!("shaders/compact.wgsl"), vps, &[]);
+            for dir in PropagateDirection::ALL {
+                validate(
+                    include_str!("shaders/propagate.wgsl"),
+                    vps,
+                    &dir.constants(),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn block_slots() {
+        let mut slots = BlockSlots::<8>::default();
+
+        assert_eq!(slots.insert(BlockIndex::new(0, 0, 0)), (0, true));
+        assert_eq!(slots.insert(BlockIndex::new(1, 0, 0)), (1, true));
+        assert_eq!(slots.insert(BlockIndex::new(0, -1, 0)), (2, true));
+        assert_eq!(slots.insert(BlockIndex::new(1, 0, 0)), (1, false));
+
+        assert_eq!(slots.len(), 3);
+        assert_eq!(slots.get(&BlockIndex::new(0, -1, 0)), Some(2));
+        assert_eq!(slots.get(&BlockIndex::new(0, 0, 1)), None);
+        assert_eq!(slots.block(1), BlockIndex::new(1, 0, 0));
+
+        // [self, x+, x-, y+, y-, z+, z-], linked both ways
+        let i = INVALID_SLOT;
+        assert_eq!(
+            slots.neighbors(),
+            &[
+                [0, 1, i, i, 2, i, i],
+                [1, i, 0, i, i, i, i],
+                [2, i, i, 0, i, i, i]
+            ]
+        );
+    }
+
+    #[test]
+    fn resident_blocks() {
+        let limits = wgpu::Limits::default();
+        assert_eq!(
+            max_resident_blocks(&limits, 8),
+            limits.max_storage_buffer_binding_size as usize / (8 * 8 * 8 * 32)
+        );
+
+        let limits = wgpu::Limits {
+            max_buffer_size: 4 * 4 * 4 * 32 * 10,
+            ..Default::default()
+        };
+        assert_eq!(max_resident_blocks(&limits, 4), 10);
+        assert_eq!(max_resident_blocks(&limits, 8), 1);
+    }
+
+    #[test]
+    fn limits() {
+        let limits = wgpu::Limits::default();
+
+        assert_eq!(check_limits(&limits, 4), Ok(()));
+        assert_eq!(check_limits(&limits, 8), Ok(()));
+        assert_eq!(
+            check_limits(&limits, 16),
+            Err(GpuError::WorkgroupStorageExceeded {
+                vps: 16,
+                required: 16 * 16 * 16 * 32,
+                available: limits.max_compute_workgroup_storage_size
+            })
+        );
+
+        let limits = wgpu::Limits {
+            max_compute_workgroup_storage_size: 1 << 20,
+            ..Default::default()
+        };
+        assert!(matches!(
+            check_limits(&limits, 32),
+            Err(GpuError::WorkgroupSizeExceeded { vps: 32, .. })
+        ));
+    }
 }